@@ -0,0 +1,315 @@
+use crate::{
+    meta::Error,
+    net::{ipset, nft},
+};
+use std::{
+    fmt::{Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub const USAGE: &str = "\
+Usage: torcher <command> [options] [paths...]
+
+Commands:
+  aggregate   Merge every input file into optimized sets and write them to the output directory
+              as ipset<n>-v4 and ipset<n>-v6
+  render      Merge the given files (or the input directory) and print the result to stdout
+  check       Parse the given files (or the input directory) and report what was found
+  diff        Compare two set files and print the entries unique to each side
+
+Options:
+  -i, --input <path>        Input file or directory (default: ./in)
+  -o, --output <dir>        Output directory (default: ./out)
+  -x, --exclude <path>      Networks carved out of every output (default: ./exclude if present)
+  -f, --format <format>     Output format: plain, range, ipset, nft (default: plain)
+  -c, --chunk-size <n>      Maximum entries per output file (default: per format)
+      --swap                Load ipset output through a temporary set and swap it in atomically
+      --table <name>        Table nft sets are declared in (default: torcher)
+      --services <file>     Services database (default: /etc/services)
+      --protocols <file>    Protocols database (default: /etc/protocols)
+  -v, --verbose             Increase verbosity, may be repeated
+  -q, --quiet               Only print errors
+  -h, --help                Print this help
+  -V, --version             Print the version";
+
+/// Default exclude path, only read when it exists.
+pub const EXCLUDE: &str = "exclude";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    Aggregate,
+    Render,
+    Check,
+    Diff,
+    Help,
+    Version,
+}
+
+impl Command {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Command::Aggregate => "aggregate",
+            Command::Render => "render",
+            Command::Check => "check",
+            Command::Diff => "diff",
+            Command::Help => "help",
+            Command::Version => "version",
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Command {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aggregate" | "agg" => Ok(Command::Aggregate),
+            "render" => Ok(Command::Render),
+            "check" => Ok(Command::Check),
+            "diff" => Ok(Command::Diff),
+            "help" => Ok(Command::Help),
+            "version" => Ok(Command::Version),
+            _ => Err(Error::new(format!("unknown command '{}'", s), 2)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Format {
+    #[default]
+    Plain,
+    Range,
+    Ipset,
+    Nft,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Range => "range",
+            Format::Ipset => "ipset",
+            Format::Nft => "nft",
+        }
+    }
+    /// Largest number of entries written to a single output file.
+    ///
+    /// nft sets have no element limit, their scripts are split into statements instead.
+    pub fn chunk_size(&self) -> usize {
+        match self {
+            Format::Plain | Format::Range => 65535,
+            Format::Ipset => ipset::MAXELEM,
+            Format::Nft => usize::MAX,
+        }
+    }
+    /// File name extension of output files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Plain => "",
+            Format::Range => ".range",
+            Format::Ipset => ".ipset",
+            Format::Nft => ".nft",
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "plain" | "cidr" | "txt" => Ok(Format::Plain),
+            "range" | "iprange" => Ok(Format::Range),
+            "ipset" | "ipset-restore" => Ok(Format::Ipset),
+            "nft" | "nftables" => Ok(Format::Nft),
+            _ => Err(Error::new(format!("unknown output format '{}'", s), 2)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub exclude: Option<PathBuf>,
+    pub format: Format,
+    pub chunk_size: Option<usize>,
+    pub swap: bool,
+    pub table: String,
+    pub services: Option<PathBuf>,
+    pub protocols: Option<PathBuf>,
+    pub verbosity: u8,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            command: Command::Help,
+            input: PathBuf::from("in"),
+            output: PathBuf::from("out"),
+            exclude: None,
+            format: Format::default(),
+            chunk_size: None,
+            swap: false,
+            table: nft::TABLE.to_string(),
+            services: None,
+            protocols: None,
+            verbosity: 1,
+            paths: Vec::new(),
+        }
+    }
+}
+
+impl Args {
+    /// Parses the process arguments, without the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut parsed = Self::default();
+        let mut command = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if arg.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| -> Result<String, Error> {
+                match inline.clone().or_else(|| args.next()) {
+                    Some(v) if !v.is_empty() => Ok(v),
+                    _ => Err(Error::new(format!("option '{}' requires a value", name), 2)),
+                }
+            };
+            match flag.as_str() {
+                "-i" | "--input" => parsed.input = PathBuf::from(value(&flag)?),
+                "-o" | "--output" => parsed.output = PathBuf::from(value(&flag)?),
+                "-x" | "--exclude" => parsed.exclude = Some(PathBuf::from(value(&flag)?)),
+                "-f" | "--format" => parsed.format = Format::from_str(&value(&flag)?)?,
+                "-c" | "--chunk-size" => {
+                    let v = value(&flag)?;
+                    match v.parse::<usize>() {
+                        Ok(n) if n > 0 => parsed.chunk_size = Some(n),
+                        _ => return Err(Error::new(format!("invalid chunk size '{}'", v), 2)),
+                    }
+                }
+                "--swap" => parsed.swap = true,
+                "--table" => parsed.table = value(&flag)?,
+                "--services" => parsed.services = Some(PathBuf::from(value(&flag)?)),
+                "--protocols" => parsed.protocols = Some(PathBuf::from(value(&flag)?)),
+                "-q" | "--quiet" => parsed.verbosity = 0,
+                "-v" | "--verbose" => parsed.verbosity = parsed.verbosity.saturating_add(1),
+                "-h" | "--help" => command = Some(Command::Help),
+                "-V" | "--version" => command = Some(Command::Version),
+                _ if flag.starts_with("-vv") && flag[1..].chars().all(|c| c == 'v') => {
+                    parsed.verbosity = parsed.verbosity.saturating_add((flag.len() - 1) as u8);
+                }
+                _ if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(Error::new(format!("unknown option '{}'", flag), 2));
+                }
+                _ if command.is_none() => command = Some(Command::from_str(&arg)?),
+                _ => parsed.paths.push(PathBuf::from(arg)),
+            }
+        }
+        parsed.command = command.unwrap_or(Command::Help);
+        if parsed.command == Command::Diff && parsed.paths.len() != 2 {
+            return Err(Error::new("diff expects exactly two paths".to_string(), 2));
+        }
+        Ok(parsed)
+    }
+
+    /// Chunk size given on the command line, or the default of the output format.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(self.format.chunk_size())
+    }
+
+    /// Files named on the command line, or the contents of the input path.
+    pub fn input_files(&self) -> Result<Vec<PathBuf>, Error> {
+        if self.paths.is_empty() {
+            return files(&self.input);
+        }
+        let mut all = Vec::new();
+        for path in &self.paths {
+            all.append(&mut files(path)?);
+        }
+        Ok(all)
+    }
+
+    /// Files of the exclude path given on the command line, or of `./exclude` when it exists.
+    pub fn exclude_files(&self) -> Result<Vec<PathBuf>, Error> {
+        match &self.exclude {
+            Some(path) => files(path),
+            None if Path::new(EXCLUDE).exists() => files(Path::new(EXCLUDE)),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Lists the regular files at `path`: the path itself, or the files of a directory in name order.
+pub fn files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let entries = path
+        .read_dir()
+        .map_err(|e| Error::new(format!("cannot read {}: {}", path.display(), e), 1))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|p| p.ok().map(|p| p.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn options_and_command() {
+        let args = parse(&["render", "-f", "nft", "--table=fw", "-vv", "a.txt", "b.txt"]).unwrap();
+        assert_eq!(args.command, Command::Render);
+        assert_eq!(args.format, Format::Nft);
+        assert_eq!(args.table, "fw");
+        assert_eq!(args.verbosity, 3);
+        assert_eq!(args.paths, [PathBuf::from("a.txt"), PathBuf::from("b.txt")]);
+        assert_eq!(args.chunk_size(), usize::MAX);
+        assert_eq!(parse(&["render", "-c", "100"]).unwrap().chunk_size(), 100);
+    }
+
+    #[test]
+    fn defaults_to_help() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.command, Command::Help);
+        assert_eq!((args.input, args.output), ("in".into(), "out".into()));
+        assert_eq!(parse(&["-q", "-V"]).unwrap().command, Command::Version);
+    }
+
+    #[test]
+    fn invalid_arguments_are_errors() {
+        let message = |args: &[&str]| parse(args).unwrap_err().message().clone();
+        assert_eq!(message(&["render", "-f"]), "option '-f' requires a value");
+        assert_eq!(message(&["render", "-c", "0"]), "invalid chunk size '0'");
+        assert_eq!(message(&["render", "--bogus"]), "unknown option '--bogus'");
+        assert_eq!(
+            message(&["render", "-f", "xml"]),
+            "unknown output format 'xml'"
+        );
+        assert_eq!(message(&["diff", "a"]), "diff expects exactly two paths");
+    }
+}
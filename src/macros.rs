@@ -2637,7 +2637,10 @@ macro_rules! impl_set {
     ($T:ident, $U:ident) => {
         impl<$U: Debug + Clone + PartialEq + Eq> $T {
             pub fn new() -> Self {
-                Self { data: std::ptr::null_mut(), len: 0 }
+                Self {
+                    data: std::ptr::null_mut(),
+                    len: 0,
+                }
             }
             pub fn size_of(&self) -> usize {
                 std::mem::size_of::<$U>() * self.len
@@ -2646,7 +2649,10 @@ macro_rules! impl_set {
                 self.len
             }
             pub fn clone(&self) -> Self {
-                Self { data: self.data, len: self.len }
+                Self {
+                    data: self.data,
+                    len: self.len,
+                }
             }
             pub fn is_empty(&self) -> bool {
                 self.len == 0
@@ -2658,7 +2664,9 @@ macro_rules! impl_set {
                     unsafe {
                         let layout = std::alloc::Layout::array::<$U>(len).unwrap();
                         let ptr = std::alloc::alloc(layout) as *mut $U;
-                        if ptr.is_null() { std::process::abort(); }
+                        if ptr.is_null() {
+                            std::process::abort();
+                        }
                         ptr
                     }
                 }
@@ -2706,7 +2714,11 @@ macro_rules! impl_set {
                         }
                         std::ptr::write(new_data.add(index), item);
                         if index < self.len - 1 {
-                            std::ptr::copy_nonoverlapping(self.data.add(index + 1), new_data.add(index + 1), self.len - index - 1);
+                            std::ptr::copy_nonoverlapping(
+                                self.data.add(index + 1),
+                                new_data.add(index + 1),
+                                self.len - index - 1,
+                            );
                         }
                     }
                     self.free();
@@ -2753,12 +2765,18 @@ macro_rules! impl_set {
                 }
             }
             pub fn with_capacity(capacity: usize) -> Self {
-                Self { data: Self::alloc(capacity), len: 0 }
+                Self {
+                    data: Self::alloc(capacity),
+                    len: 0,
+                }
             }
         }
         impl<$U: Clone + PartialEq + Eq> Clone for $T {
             fn clone(&self) -> Self {
-                Self { data: self.data, len: self.len }
+                Self {
+                    data: self.data,
+                    len: self.len,
+                }
             }
         }
         impl<$U: Clone + PartialEq + Eq> Drop for $T {
@@ -2766,7 +2784,7 @@ macro_rules! impl_set {
                 self.free();
             }
         }
-            
+
         impl<$U: Clone + PartialEq + Eq> AsRef<[$U]> for $T {
             fn as_ref(&self) -> &[$U] {
                 unsafe { std::slice::from_raw_parts(self.data as *const $U, self.len) }
@@ -2870,10 +2888,17 @@ macro_rules! impl_octet_quad {
             pub fn to_string(&self) -> String {
                 format!("{}.{}.{}.{}", self.0, self.1, self.2, self.3)
             }
-            pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
+            pub fn from_str(s: &str) -> Result<Self, $crate::meta::Error> {
+                let invalid = |token: &str, expected: &str| {
+                    $crate::meta::Error::parse(token, expected).locate(s, token)
+                };
+                let parts: Vec<&str> = s.split('.').collect();
+                if parts.len() != 4 {
+                    return Err(invalid(s, "four dotted octets"));
+                }
                 let mut octets = [0u8; 4];
-                for (i, ss) in s.split('.').enumerate() {
-                    octets[i] = u8::from_str(ss)?;
+                for (octet, part) in octets.iter_mut().zip(parts) {
+                    *octet = u8::from_str(part).map_err(|_| invalid(part, "an octet 0-255"))?;
                 }
                 Ok(Self::from_octets(octets))
             }
@@ -2908,7 +2933,7 @@ macro_rules! impl_octet_quad {
             }
         }
         impl FromStr for $T {
-            type Err = ParseIntError;
+            type Err = $crate::meta::Error;
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 Self::from_str(s)
             }
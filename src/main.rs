@@ -1,67 +1,357 @@
+pub mod cli;
 pub mod macros;
 pub mod meta;
 pub mod net;
 pub mod util;
+use cli::{Args, Command, Format, USAGE};
+use meta::Error;
 pub use net::Port;
-use net::{PORT_LIST, PROTO_LIST, v4::NetSet};
-use std::{env::current_dir, fs::{read_to_string, write}, path::PathBuf};
+use net::{PORT_LIST, PROTO_LIST, sets::Sets};
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
 pub use util::Paths;
 fn main() {
-    println!("Torcher v{}", env!("CARGO_PKG_VERSION"));
-    println!("Copyright (c) 2025 Matthew Fay. All rights reserved.");
-    println!("License: MIT");
-    println!("\n");
-    let input_dir = current_dir().unwrap().join("in");
-    println!("Importing port information from file: /etc/services");
-    for port in PORT_LIST.iter() {
-        println!("{}", port.to_string());
-    }
-    println!("Imported {} port service entries", PORT_LIST.len());
-    println!("Importing protocol information from file: /etc/protocols");
-    for proto in PROTO_LIST.iter() {
-        println!("{}", proto.to_string());
-    }
-    println!("Imported {} protocol entries", PROTO_LIST.len());
-    let output_dir = current_dir().unwrap().join("out");
-    // Filter for files only and convert to path buffers in one step
-    let paths: Vec<PathBuf> = input_dir
-        .read_dir()
-        .unwrap()
-        .filter_map(|p| p.ok().map(|p| p.path()))
-        .filter(|p| p.is_file())
-        .collect();
-
-    // Process input files and optimize in a single pass
-    let mut ns = NetSet::new();
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("torcher: {}", e.message);
+            eprintln!("{}", USAGE);
+            exit(e.code as i32);
+        }
+    };
+    if let Err(e) = run(&args) {
+        eprintln!("torcher: {}", e.message);
+        exit(e.code as i32);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    match args.command {
+        Command::Help => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("torcher {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        _ => {}
+    }
+    if args.verbosity > 0 && args.command == Command::Aggregate {
+        eprintln!("Torcher v{}", env!("CARGO_PKG_VERSION"));
+        eprintln!("Copyright (c) 2025 Matthew Fay. All rights reserved.");
+        eprintln!("License: MIT");
+        eprintln!();
+    }
+    load_databases(args)?;
+    match args.command {
+        Command::Aggregate => aggregate(args),
+        Command::Render => render(args),
+        Command::Check => check(args),
+        Command::Diff => diff(args),
+        Command::Help | Command::Version => Ok(()),
+    }
+}
+
+/// Applies the services/protocols overrides and, when asked to, lists their contents on stderr.
+fn load_databases(args: &Args) -> Result<(), Error> {
+    for (path, set) in [
+        (
+            &args.services,
+            net::set_services_path as fn(PathBuf) -> bool,
+        ),
+        (&args.protocols, net::set_protocols_path),
+    ] {
+        if let Some(path) = path {
+            if !path.is_file() {
+                return Err(Error::new(format!("no such file: {}", path.display()), 1));
+            }
+            set(path.clone());
+        }
+    }
+    if args.verbosity > 2 {
+        eprintln!(
+            "Importing port information from file: {}",
+            net::services_path().display()
+        );
+        for port in PORT_LIST.iter() {
+            eprintln!("{}", port);
+        }
+        eprintln!("Imported {} port service entries", PORT_LIST.len());
+        eprintln!(
+            "Importing protocol information from file: {}",
+            net::protocols_path().display()
+        );
+        for proto in PROTO_LIST.iter() {
+            eprintln!("{}", proto);
+        }
+        eprintln!("Imported {} protocol entries", PROTO_LIST.len());
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Sets, Error> {
+    let content = read_to_string(path)
+        .map_err(|e| Error::new(format!("cannot read {}: {}", path.display(), e), 1))?;
+    Sets::from_str(&content).map_err(|e| e.in_file(path))
+}
+
+/// Whether pipeline progress goes out: always for `aggregate`, only when verbose for commands
+/// whose stdout is the payload.
+fn progress(args: &Args) -> bool {
+    args.verbosity > 1 || (args.verbosity > 0 && args.command == Command::Aggregate)
+}
+
+/// Reads every file in `paths`, skipping (and reporting) the ones that fail to parse.
+///
+/// With `strict` a file that fails to parse aborts the run instead: a skipped exclude file
+/// would leave networks that must never be blocked in the output.
+fn read_all(
+    args: &Args,
+    paths: &[PathBuf],
+    kind: &str,
+    strict: bool,
+) -> Result<Vec<(PathBuf, Sets)>, Error> {
+    let mut sets = Vec::new();
     for (i, path) in paths.iter().enumerate() {
-        println!("Adding input file number {} : {}", i + 1, path.display());
-        if let Ok(content) = read_to_string(path) {
-            if let Ok(mut file_set) = NetSet::from_str(&content) {
-                ns.append(&mut file_set);
-                ns.sort();
-                ns.dedup();
+        if args.verbosity > 1 {
+            eprintln!("Adding {} file number {} : {}", kind, i + 1, path.display());
+        }
+        match read(path) {
+            Ok(file_sets) => {
+                if progress(args) {
+                    eprintln!(
+                        "{}: {} IPv4, {} IPv6 entries",
+                        path.display(),
+                        file_sets.v4.len(),
+                        file_sets.v6.len()
+                    );
+                }
+                sets.push((path.clone(), file_sets))
+            }
+            Err(e) => {
+                eprint!("{}", e.render());
+                if strict {
+                    return Err(Error::new(
+                        format!("{} file {} failed to parse", kind, path.display()),
+                        2,
+                    ));
+                }
             }
         }
     }
-    ns.optimize();
-    // Split into chunks of max 65535 entries
-    let mut netsets = Vec::new();
-    if ns.len() > 65535 {
-        while ns.len() > 65535 {
-            let (chunk, remainder) = ns.split_at(65535);
-            netsets.push(chunk);
-            ns = remainder;
+    Ok(sets)
+}
+
+/// Reads and merges every input file into optimized per-family sets, minus the excluded
+/// networks.
+///
+/// Every overlap between an input file and an exclude file is reported, so feeds listing
+/// networks that must never be blocked can be tracked down.
+fn collect(args: &Args) -> Result<Sets, Error> {
+    let inputs = read_all(args, &args.input_files()?, "input", false)?;
+    let excludes = read_all(args, &args.exclude_files()?, "exclude", true)?;
+    let mut sets = Sets::new();
+    for (_, file_sets) in &inputs {
+        sets.append(file_sets);
+    }
+    let entries_in = (sets.v4.len(), sets.v6.len());
+    if !excludes.is_empty() {
+        let mut exclude = Sets::new();
+        for (exclude_path, exclude_sets) in &excludes {
+            for (path, file_sets) in &inputs {
+                let overlap = file_sets.intersection(exclude_sets);
+                let nets = overlap
+                    .v4
+                    .iter()
+                    .map(|net| net.to_string())
+                    .chain(overlap.v6.iter().map(|net| net.to_string()));
+                for net in nets {
+                    if args.verbosity > 0 {
+                        eprintln!(
+                            "torcher: {}: excluded {} (listed in {})",
+                            path.display(),
+                            net,
+                            exclude_path.display()
+                        );
+                    }
+                }
+            }
+            exclude.append(exclude_sets);
+        }
+        sets = sets.difference(&exclude);
+    }
+    let (mut v4, mut v6) = sets.optimize();
+    v4.entries_in = entries_in.0;
+    v6.entries_in = entries_in.1;
+    if progress(args) {
+        eprintln!("Aggregated IPv4: {}", v4);
+        eprintln!("Aggregated IPv6: {}", v6);
+    }
+    Ok(sets)
+}
+
+/// Splits both families into chunks and renders each in the selected format.
+///
+/// Returns `(name, entries, text)` per chunk, named `ipset<n>-v4` and `ipset<n>-v6`.
+fn outputs(args: &Args, sets: &Sets) -> Vec<(String, usize, String)> {
+    let mut out = Vec::new();
+    for (i, chunk) in sets.v4.chunks(args.chunk_size()).iter().enumerate() {
+        let name = format!("ipset{}-v4", i + 1);
+        let text = match args.format {
+            Format::Plain => chunk.to_string(),
+            Format::Range => lines(chunk.to_ranges()),
+            Format::Ipset => chunk.to_ipset(&name, args.chunk_size(), args.swap),
+            Format::Nft => chunk.to_nft(&args.table, &name),
+        };
+        out.push((name, chunk.len(), text));
+    }
+    for (i, chunk) in sets.v6.chunks(args.chunk_size()).iter().enumerate() {
+        let name = format!("ipset{}-v6", i + 1);
+        let text = match args.format {
+            Format::Plain => format!("{}\n", chunk),
+            Format::Range => lines(chunk.to_ranges()),
+            Format::Ipset => chunk.to_ipset(&name, args.chunk_size(), args.swap),
+            Format::Nft => chunk.to_nft(&args.table, &name),
+        };
+        out.push((name, chunk.len(), text));
+    }
+    out
+}
+
+fn lines<T: std::fmt::Display>(items: Vec<T>) -> String {
+    items.iter().map(|item| format!("{}\n", item)).collect()
+}
+
+fn aggregate(args: &Args) -> Result<(), Error> {
+    let sets = collect(args)?;
+    create_dir_all(&args.output)
+        .map_err(|e| Error::new(format!("cannot create {}: {}", args.output.display(), e), 1))?;
+    for (name, entries, text) in outputs(args, &sets) {
+        let output_path = args
+            .output
+            .join(format!("{}{}", name, args.format.extension()));
+        if let Err(e) = write(&output_path, text) {
+            eprintln!("Failed to write ip set to target file: {}: {}", name, e);
+        } else if args.verbosity > 0 {
+            eprintln!("Wrote {} entries to {}", entries, output_path.display());
+        }
+    }
+    Ok(())
+}
+
+fn render(args: &Args) -> Result<(), Error> {
+    let sets = collect(args)?;
+    for (_, _, text) in outputs(args, &sets) {
+        print!("{}", text);
+    }
+    Ok(())
+}
+
+fn check(args: &Args) -> Result<(), Error> {
+    let mut failed = 0;
+    for path in args.input_files()? {
+        match read(&path) {
+            Ok(sets) => println!(
+                "{}: {} IPv4 networks, {} IPv6 networks",
+                path.display(),
+                sets.v4.len(),
+                sets.v6.len()
+            ),
+            Err(e) => {
+                eprint!("{}", e.render());
+                failed += 1;
+            }
         }
     }
-    if !ns.is_empty() {
-        netsets.push(ns);
+    if failed > 0 {
+        return Err(Error::new(format!("{} file(s) failed to parse", failed), 2));
     }
+    Ok(())
+}
 
-    // Write output files
-    for (i, netset) in netsets.iter().enumerate() {
-        let output_path = output_dir.join(format!("ipset{}", i + 1));
-        if let Err(e) = write(output_path, netset.to_string()) {
-            println!("Failed to write ip set to target file: ipset{}: {}", i + 1, e);
+fn diff(args: &Args) -> Result<(), Error> {
+    let mut sides = Vec::new();
+    for path in &args.paths {
+        let mut sets = read(path)?;
+        sets.optimize();
+        sides.push(sets);
+    }
+    let (old, new) = (&sides[0], &sides[1]);
+    for net in old.v4.iter().filter(|net| !new.v4.contains(**net)) {
+        println!("- {}", net);
+    }
+    for net in old.v6.iter().filter(|net| !new.v6.contains(net)) {
+        println!("- {}", net);
+    }
+    for net in new.v4.iter().filter(|net| !old.v4.contains(**net)) {
+        println!("+ {}", net);
+    }
+    for net in new.v6.iter().filter(|net| !old.v6.contains(net)) {
+        println!("+ {}", net);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` into a fresh directory under the system temp dir.
+    fn dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torcher-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn args(input: PathBuf, exclude: PathBuf) -> Args {
+        Args {
+            command: Command::Render,
+            input,
+            exclude: Some(exclude),
+            verbosity: 0,
+            ..Args::default()
         }
     }
+
+    #[test]
+    fn exclude_carves_networks_out_of_inputs() {
+        let input = dir("carve-in", &[("a", "10.0.0.0/8\n2001:db8::/32\n")]);
+        let exclude = dir("carve-ex", &[("b", "10.1.0.0/16\n2001:db8:1::/48\n")]);
+        let sets = collect(&args(input, exclude)).unwrap();
+        assert_eq!(
+            lines(sets.v4.to_ranges()),
+            "10.0.0.0-10.0.255.255\n10.2.0.0-10.255.255.255\n"
+        );
+        assert_eq!(
+            lines(sets.v6.to_ranges()),
+            "2001:db8::-2001:db8:0:ffff:ffff:ffff:ffff:ffff\n\
+             2001:db8:2::-2001:db8:ffff:ffff:ffff:ffff:ffff:ffff\n"
+        );
+    }
+
+    #[test]
+    fn bad_exclude_file_aborts() {
+        let input = dir("bad-in", &[("a", "10.0.0.0/8\n")]);
+        let exclude = dir("bad-ex", &[("b", "10.1.0.0/16\n300.1.2.3\n")]);
+        let e = collect(&args(input, exclude)).unwrap_err();
+        assert_eq!(e.code, 2);
+        assert!(e.message.contains("exclude file"));
+    }
+
+    #[test]
+    fn bad_input_file_is_skipped() {
+        let input = dir("skip-in", &[("a", "10.0.0.0/8\n"), ("b", "300.1.2.3\n")]);
+        let exclude = dir("skip-ex", &[]);
+        let sets = collect(&args(input, exclude)).unwrap();
+        assert_eq!(sets.v4.len(), 1);
+    }
 }
@@ -1,7 +1,9 @@
 use std::cmp::Ordering;
 use std::{
     fmt::{Display, Formatter},
+    num::ParseIntError,
     ops::{Add, AddAssign, Index, IndexMut},
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
@@ -10,21 +12,128 @@ pub struct Info {
     pub data: Vec<String>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// An error with, for parse errors, where in the input it was found.
+#[derive(Debug, Default, Eq, PartialEq)]
 pub struct Error {
     pub message: String,
     pub code: u32,
+    /// File the input was read from.
+    pub file: Option<PathBuf>,
+    /// Line and column of `token`, counting from 1, 0 when not known.
+    pub line: usize,
+    pub column: usize,
+    /// The offending token.
+    pub token: String,
+    /// Text of the line the error is on.
+    pub snippet: String,
 }
 
 impl Error {
     pub fn new(message: String, code: u32) -> Self {
-        Self { message, code }
+        Self {
+            message,
+            code,
+            ..Self::default()
+        }
     }
-    pub fn clone(&self) -> Self {
+    /// A parse error for `token` where `expected` should have been.
+    pub fn parse(token: &str, expected: &str) -> Self {
+        let found = match token {
+            "" => "end of input".to_string(),
+            token => format!("'{}'", token),
+        };
         Self {
-            message: self.message.clone(),
-            code: self.code,
+            message: format!("expected {}, found {}", expected, found),
+            code: 2,
+            token: token.to_string(),
+            ..Self::default()
+        }
+    }
+    pub fn clone(&self) -> Self {
+        <Self as Clone>::clone(self)
+    }
+    pub fn is_located(&self) -> bool {
+        self.line > 0
+    }
+    /// Places the error at `token` within `source`. `token` is best a slice of `source`,
+    /// otherwise its first occurrence is used. Errors already placed are left alone.
+    pub fn locate(mut self, source: &str, token: &str) -> Self {
+        if self.is_located() {
+            return self;
+        }
+        let start = source.as_ptr() as usize;
+        let offset = match (token.as_ptr() as usize).checked_sub(start) {
+            Some(offset) if offset + token.len() <= source.len() => offset,
+            _ => match source.find(token) {
+                Some(offset) => offset,
+                None => return self,
+            },
+        };
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        self.line = before.matches('\n').count() + 1;
+        self.column = before[line_start..].chars().count() + 1;
+        self.snippet = source[line_start..]
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if self.token.is_empty() {
+            self.token = token.to_string();
         }
+        self
+    }
+    /// Places an error found in a fragment that starts at `line` and `column` of a larger
+    /// input, or at the start of the fragment when it has no position yet.
+    pub fn shift(mut self, line: usize, column: usize, snippet: &str) -> Self {
+        if self.line <= 1 {
+            self.column = column + self.column.max(1) - 1;
+        }
+        self.line = line + self.line.max(1) - 1;
+        if self.snippet.is_empty() || self.line == line {
+            self.snippet = snippet.to_string();
+        }
+        self
+    }
+    pub fn in_file(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+    /// Where the error is, `file:line:column`.
+    pub fn location(&self) -> String {
+        let file = self
+            .file
+            .as_ref()
+            .map(|f| format!("{}:", f.display()))
+            .unwrap_or_default();
+        match self.is_located() {
+            true => format!("{}{}:{}", file, self.line, self.column),
+            false => file.trim_end_matches(':').to_string(),
+        }
+    }
+    /// Formats the error the way compilers do, with the offending line and the token marked.
+    pub fn render(&self) -> String {
+        let mut out = format!("error: {}\n", self.message);
+        let location = self.location();
+        if location.is_empty() {
+            return out;
+        }
+        let gutter = " ".repeat(self.line.to_string().len());
+        out.push_str(&format!("{}--> {}\n", gutter, location));
+        if self.snippet.is_empty() {
+            return out;
+        }
+        let indent: String = self
+            .snippet
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let marker = "^".repeat(self.token.chars().count().max(1));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", self.line, self.snippet));
+        out.push_str(&format!("{} | {}{}\n", gutter, indent, marker));
+        out
     }
     pub fn cmp(&self, other: &Self) -> Ordering {
         self.code.cmp(&other.code)
@@ -33,10 +142,13 @@ impl Error {
         Some(self.cmp(other))
     }
     pub fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{}: {}", self.code, self.message)
+        write!(f, "{}", self.to_string())
     }
     pub fn to_string(&self) -> String {
-        format!("{}: {}", self.code, self.message)
+        match self.is_located() {
+            true => format!("{}: {}", self.location(), self.message),
+            false => format!("{}: {}", self.code, self.message),
+        }
     }
     pub fn from_str(s: &str) -> Result<Self, Self> {
         let mut message = String::new();
@@ -48,10 +160,10 @@ impl Error {
                 code = line.parse().unwrap();
             }
         }
-        Ok(Self { message, code })
+        Ok(Self::new(message, code))
     }
     pub fn display(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{}: {}", self.code, self.message)
+        write!(f, "{}", self.to_string())
     }
     pub fn message(&self) -> &String {
         &self.message
@@ -70,9 +182,21 @@ impl Clone for Error {
         Self {
             message: self.message.clone(),
             code: self.code,
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            token: self.token.clone(),
+            snippet: self.snippet.clone(),
         }
     }
 }
+
+/// Number errors carry no position, parsers place them with [`Error::locate`].
+impl From<ParseIntError> for Error {
+    fn from(e: ParseIntError) -> Self {
+        Self::new(e.to_string(), 2)
+    }
+}
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         None
@@ -89,7 +213,7 @@ impl std::error::Error for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{}: {}", self.code, self.message)
+        write!(f, "{}", self.to_string())
     }
 }
 
@@ -110,19 +234,13 @@ impl<T: Display + ToString + Default + Clone + FromStr + PartialEq + Ord + Parti
             output: T::default(),
             parse: |s: &str| match T::from_str(s) {
                 Ok(t) => Ok(t),
-                Err(_e) => Err(Error {
-                    message: "Format error".to_string(),
-                    code: 2,
-                }),
+                Err(_e) => Err(Error::new("Format error".to_string(), 2)),
             },
             fmt: |t: &T, f: &mut Formatter| write!(f, "{}", t),
             to_string: |t: &T| t.to_string(),
             from_str: |s: &str| match T::from_str(s) {
                 Ok(t) => Ok(t),
-                Err(_e) => Err(Error {
-                    message: "Format error".to_string(),
-                    code: 2,
-                }),
+                Err(_e) => Err(Error::new("Format error".to_string(), 2)),
             },
         }
     }
@@ -276,10 +394,7 @@ impl Info {
             }
         }
         if name.is_empty() || description.is_empty() {
-            return Err(Error {
-                message: "Invalid format".to_string(),
-                code: 2,
-            });
+            return Err(Error::new("Invalid format".to_string(), 2));
         }
         Ok(Self {
             data: vec![name, description, details.join("\n")],
@@ -315,10 +430,7 @@ impl FromStr for Info {
             }
         }
         if name.is_empty() || description.is_empty() {
-            return Err(Error {
-                message: "Invalid format".to_string(),
-                code: 2,
-            });
+            return Err(Error::new("Invalid format".to_string(), 2));
         }
         Ok(Self {
             data: vec![name, description, details.join("\n")],
@@ -1,19 +1,51 @@
+pub mod analysis;
+pub mod cidr;
+pub mod diff;
+pub mod ipset;
+pub mod iptables;
+pub mod nft;
+pub mod sets;
+pub mod simulate;
 pub mod v4;
 pub mod v6;
+use crate::meta;
 use std::{
     cmp::Ordering,
     fmt::{Debug, Display, Formatter, Result},
     fs::File,
     hash::{Hash, Hasher},
     io::Read,
-    num::ParseIntError,
     ops::{Index, IndexMut},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::LazyLock,
+    sync::{LazyLock, OnceLock},
     usize,
 };
 
+static PROTOCOLS_PATH: OnceLock<PathBuf> = OnceLock::new();
+static SERVICES_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides the file `PROTO_LIST` is imported from. Only takes effect before first use.
+pub fn set_protocols_path(path: PathBuf) -> bool {
+    PROTOCOLS_PATH.set(path).is_ok()
+}
+pub fn protocols_path() -> &'static Path {
+    PROTOCOLS_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new("/etc/protocols"))
+}
+/// Overrides the file `PORT_LIST` is imported from. Only takes effect before first use.
+pub fn set_services_path(path: PathBuf) -> bool {
+    SERVICES_PATH.set(path).is_ok()
+}
+pub fn services_path() -> &'static Path {
+    SERVICES_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new("/etc/services"))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Direction {
     None,
@@ -211,13 +243,15 @@ impl From<usize> for Direction {
     }
 }
 impl FromStr for Direction {
-    type Err = ParseIntError;
-    fn from_str(s: &str) -> std::result::Result<Direction, ParseIntError> {
-        match s.trim().to_ascii_lowercase().as_str() {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Direction, meta::Error> {
+        let token = s.trim();
+        match token.to_ascii_lowercase().as_str() {
+            "none" | "" => Ok(Direction::None),
             "in" | "i" | "input" => Ok(Direction::In),
             "out" | "o" | "output" => Ok(Direction::Out),
             "fwd" | "f" | "forward" => Ok(Direction::Fwd),
-            _ => Ok(Direction::None),
+            _ => Err(meta::Error::parse(token, "in, out or fwd").locate(s, token)),
         }
     }
 }
@@ -251,12 +285,12 @@ impl IndexMut<usize> for Direction {
 }
 impl Ord for Direction {
     fn cmp(&self, other: &Direction) -> Ordering {
-        self.cmp(other)
+        u8::from(*self).cmp(&u8::from(*other))
     }
 }
 impl PartialEq for Direction {
     fn eq(&self, other: &Direction) -> bool {
-        self == other
+        u8::from(*self) == u8::from(*other)
     }
 }
 impl PartialOrd for Direction {
@@ -476,14 +510,15 @@ impl PartialOrd for Family {
     }
 }
 impl FromStr for Family {
-    type Err = ParseIntError;
-    fn from_str(s: &str) -> std::result::Result<Family, ParseIntError> {
-        match s {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Family, meta::Error> {
+        let token = s.trim();
+        match token.to_ascii_lowercase().as_str() {
             "none" | "" => Ok(Family::None),
             "v4" | "4" | "ipv4" | "ip4" => Ok(Family::V4),
             "v6" | "6" | "ipv6" | "ip6" => Ok(Family::V6),
             "any" | "a" | "all" => Ok(Family::Any),
-            _ => Ok(Family::None),
+            _ => Err(meta::Error::parse(token, "v4, v6 or any").locate(s, token)),
         }
     }
 }
@@ -1019,10 +1054,7 @@ impl Ip {
                     };
                     format!("{}.{}.{}.{}{}", a[0], a[1], a[2], a[3], subnet)
                 } else if a.len() == 17 {
-                    let addr = (0..8)
-                        .map(|i| format!("{:x}", ((a[i * 2] as u16) << 8) | (a[i * 2 + 1] as u16)))
-                        .collect::<Vec<_>>()
-                        .join(":");
+                    let addr = v6::format_addr(u128::from_be_bytes(a[..16].try_into().unwrap()));
                     let subnet = if a[16] < 128 {
                         format!("/{}", a[16])
                     } else {
@@ -1042,10 +1074,7 @@ impl Ip {
                 format!("{}.{}.{}.{}{}", a[0], a[1], a[2], a[3], subnet)
             }
             Ip::V6(a) => {
-                let addr = (0..8)
-                    .map(|i| format!("{:x}", ((a[i * 2] as u16) << 8) | (a[i * 2 + 1] as u16)))
-                    .collect::<Vec<_>>()
-                    .join(":");
+                let addr = v6::format_addr(u128::from_be_bytes(a[..16].try_into().unwrap()));
                 if a[16] < 128 {
                     format!("{}/{}", addr, a[16])
                 } else {
@@ -1056,110 +1085,21 @@ impl Ip {
         }
     }
 
-    fn from_str(s: &str) -> std::result::Result<Ip, ParseIntError> {
+    /// Parses `any`, an IPv4 network or an RFC 4291 IPv6 network, an empty string is
+    /// [`Ip::None`]. Errors are placed within `s`.
+    fn from_str(s: &str) -> std::result::Result<Ip, meta::Error> {
         let s = s.trim();
         if s.is_empty() {
             return Ok(Ip::None);
         }
-
-        // Try IPv4
-        if s.contains('.') && !s.contains(':') {
-            let (addr_part, subnet_part) = s.split_once('/').unwrap_or((s, ""));
-            // Accept short forms: "1", "1.2", "1.2.3", "1.2.3.4"
-            let mut octets = [0u8; 4];
-            let parts: Vec<&str> = addr_part.split('.').collect();
-            for i in 0..parts.len().min(4) {
-                octets[i] = u8::from_str(parts[i])?;
-            }
-            // Fill trailing octets with 0 if missing
-            for i in parts.len()..4 {
-                octets[i] = 0;
-            }
-            let subnet = if !subnet_part.is_empty() {
-                u8::from_str(subnet_part)?.min(32)
-            } else {
-                32
-            };
-            let mut ip = [0u8; 5];
-            ip[..4].copy_from_slice(&octets);
-            ip[4] = subnet;
-            return Ok(Ip::V4(ip));
-        }
-
-        // Try IPv6
-        if s.contains(':') {
-            let (addr_part, subnet_part) = s.split_once('/').unwrap_or((s, ""));
-            // Expand zero compression and parse
-            let parts = addr_part.split("::").collect::<Vec<_>>();
-            let mut addr_bytes = [0u8; 16];
-            let mut segs: Vec<u16> = Vec::with_capacity(8);
-
-            if parts.len() == 1 {
-                // No zero compression
-                for part in addr_part.split(':') {
-                    if !part.is_empty() {
-                        segs.push(u16::from_str_radix(part, 16)?);
-                    }
-                }
-            } else if parts.len() == 2 {
-                // There is zero compression
-                let left: Vec<&str> = parts[0].split(':').filter(|p| !p.is_empty()).collect();
-                let right: Vec<&str> = parts[1].split(':').filter(|p| !p.is_empty()).collect();
-                for part in &left {
-                    segs.push(u16::from_str_radix(part, 16)?);
-                }
-                // Fill zeros
-                let zeros = 8 - (left.len() + right.len());
-                for _ in 0..zeros {
-                    segs.push(0);
-                }
-                for part in &right {
-                    segs.push(u16::from_str_radix(part, 16)?);
-                }
-            } else {
-                // Multiple "::" is not valid
-                return Ok(Ip::None);
-            }
-            if segs.len() != 8 {
-                // Try to parse IPv4-mapped IPv6, e.g. ::ffff:192.168.1.1
-                if let Some(_last) = segs.last().copied() {
-                    if segs.len() == 6 {
-                        // The last segment should be an IPv4 address in dotted form
-                        if let Some(ipv4_str) = addr_part.rsplitn(1, ':').next() {
-                            if ipv4_str.contains('.') {
-                                let ipv4 = Ip::from_str(ipv4_str)?;
-                                let octets = ipv4.to_bytes();
-                                segs.push(((octets[0] as u16) << 8) | (octets[1] as u16));
-                                segs.push(((octets[2] as u16) << 8) | (octets[3] as u16));
-                            }
-                        }
-                    }
-                }
-            }
-            if segs.len() != 8 {
-                return Ok(Ip::None);
-            }
-            for (i, seg) in segs.iter().enumerate() {
-                addr_bytes[i * 2] = (seg >> 8) as u8;
-                addr_bytes[i * 2 + 1] = (seg & 0xFF) as u8;
-            }
-            let subnet = if !subnet_part.is_empty() {
-                u8::from_str(subnet_part)?.min(128)
-            } else {
-                128
-            };
-            let mut ip = [0u8; 17];
-            ip[..16].copy_from_slice(&addr_bytes);
-            ip[16] = subnet;
-            return Ok(Ip::V6(ip));
-        }
-
-        // Ip::Any
         if s.eq_ignore_ascii_case("any") {
             return Ok(Ip::Any(vec![0u8; 5]));
         }
-
-        Ok(Ip::None)
+        if s.contains(':') {
+            let net = v6::Net::from_str(s)?;
+            return Ok(Ip::from((net.address(), net.subnet())));
+        }
+        Ok(Ip::V4(v4::Net::from_str(s)?.to_be_bytes()))
     }
 
     pub fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
@@ -1562,6 +1502,22 @@ impl Ip {
     pub fn contains(&self, other: &Ip) -> bool {
         self.network() <= other.network() && self.broadcast() >= other.broadcast()
     }
+    /// The network as a prefix on the address space of its family, IPv4 in the low 32 bits.
+    pub fn to_prefix(&self) -> Option<(Family, cidr::Prefix)> {
+        match self {
+            Ip::V4(a) => Some((
+                Family::V4,
+                (u32::from_be_bytes([a[0], a[1], a[2], a[3]]) as u128, a[4]),
+            )),
+            Ip::V6(a) => Some((
+                Family::V6,
+                (u128::from_be_bytes(a[..16].try_into().unwrap()), a[16]),
+            )),
+            Ip::Any(a) if a.len() == 5 => Ip::V4(a[..].try_into().unwrap()).to_prefix(),
+            Ip::Any(a) if a.len() == 17 => Ip::V6(a[..].try_into().unwrap()).to_prefix(),
+            _ => None,
+        }
+    }
 }
 impl Default for Ip {
     fn default() -> Self {
@@ -1640,7 +1596,7 @@ impl From<Ip> for (u32, u8) {
 }
 
 impl FromStr for Ip {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Ip::from_str(s)
     }
@@ -1701,12 +1657,29 @@ impl std::hash::Hash for Ip {
 }
 
 pub static PROTO_LIST: LazyLock<Vec<Proto>> = LazyLock::new(|| {
-    Proto::import(&Path::new("/etc/protocols")).expect("Failed to import protocol information")
+    Proto::import(protocols_path()).expect("Failed to import protocol information")
 });
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Proto(u8, String);
 
+/// Protocols [`Proto::from_name`] resolves without the protocols database, under the names nft
+/// and iptables accept for them.
+const PROTO_NAMES: [(u8, &str); 12] = [
+    (1, "icmp"),
+    (2, "igmp"),
+    (6, "tcp"),
+    (17, "udp"),
+    (33, "dccp"),
+    (47, "gre"),
+    (50, "esp"),
+    (51, "ah"),
+    (58, "icmpv6"),
+    (58, "ipv6-icmp"),
+    (132, "sctp"),
+    (136, "udplite"),
+];
+
 impl Proto {
     pub fn clone(&self) -> Self {
         Self(self.0, self.1.clone())
@@ -1724,6 +1697,24 @@ impl Proto {
         write!(f, "{}", self.to_string())
     }
 
+    /// Looks a protocol up by the name or number a rule gives it, `None` when the name is
+    /// neither well known nor in [`PROTO_LIST`]. The name is kept as written.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        if let Ok(number) = u8::from_str(&name) {
+            let known = PROTO_NAMES.iter().find(|(n, _)| *n == number);
+            return Some(Proto(
+                number,
+                known.map(|(_, n)| format!("{},", n)).unwrap_or_default(),
+            ));
+        }
+        let number = match PROTO_NAMES.iter().find(|(_, n)| *n == name) {
+            Some((number, _)) => *number,
+            None => PROTO_LIST.iter().find(|p| p.get_name() == name)?.0,
+        };
+        Some(Proto(number, format!("{},", name)))
+    }
+
     pub fn from_num(number: u8) -> Self {
         PROTO_LIST
             .iter()
@@ -1732,7 +1723,9 @@ impl Proto {
             .clone()
     }
 
-    pub fn from_str(s: &str) -> std::result::Result<Self, ParseIntError> {
+    /// Parses a protocols database line, `name number # description`.
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
+        let source = s;
         let s = s.trim();
         let (main_part, description) = match s.split_once('#') {
             Some((main, desc)) => (main.trim(), desc.trim()),
@@ -1743,7 +1736,9 @@ impl Proto {
         let number_str = parts.next().unwrap_or("").trim();
 
         let (number, name) = if !number_str.is_empty() {
-            let num = u8::from_str(number_str)?;
+            let num = u8::from_str(number_str).map_err(|_| {
+                meta::Error::parse(number_str, "a protocol number 0-255").locate(source, number_str)
+            })?;
             (num, proto_name)
         } else if let Ok(num) = u8::from_str(proto_name) {
             (num, proto_name)
@@ -1926,8 +1921,8 @@ impl From<Proto> for (u8, String) {
 }
 
 impl FromStr for Proto {
-    type Err = ParseIntError;
-    fn from_str(s: &str) -> std::result::Result<Proto, ParseIntError> {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Proto, meta::Error> {
         Proto::from_str(s)
     }
 }
@@ -1950,9 +1945,8 @@ impl PartialOrd for Proto {
     }
 }
 
-pub const PORT_LIST: LazyLock<Vec<Port>> = LazyLock::new(|| {
-    Port::import(&Path::new("/etc/services")).expect("Failed to import port information")
-});
+pub static PORT_LIST: LazyLock<Vec<Port>> =
+    LazyLock::new(|| Port::import(services_path()).expect("Failed to import port information"));
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Port(u16, u8, String);
@@ -2020,9 +2014,13 @@ impl Port {
     }
 
     /// Parse Port from string in nftables style: "tcp 80" or "udp 53 # dns"
-    pub fn from_str(s: &str) -> std::result::Result<Self, ParseIntError> {
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
         let s = s.trim();
         let mut port = Port::new();
+        let number = |token: &str| {
+            u16::from_str(token)
+                .map_err(|_| meta::Error::parse(token, "a port number").locate(s, token))
+        };
         let (main, comment) = if let Some(idx) = s.find('#') {
             (s[..idx].trim(), s[idx + 1..].trim())
         } else {
@@ -2056,7 +2054,7 @@ impl Port {
                     "icmpv6" => 58,
                     _ => 254,
                 };
-                (u16::from_str(value)?, proto_num)
+                (number(value)?, proto_num)
             }
             // e.g. "tcp sport 80"
             ("tcp", "sport")
@@ -2076,7 +2074,7 @@ impl Port {
                     "icmpv6" => 58,
                     _ => 254,
                 };
-                (u16::from_str(value)?, proto_num)
+                (number(value)?, proto_num)
             }
             // e.g. "tcp 80"
             ("tcp", port_candidate)
@@ -2098,11 +2096,11 @@ impl Port {
                     "icmpv6" => 58,
                     _ => 254,
                 };
-                (u16::from_str(port_candidate)?, proto_num)
+                (number(port_candidate)?, proto_num)
             }
             // fallback: just port (numeric)
             (port_candidate, "") if !port_candidate.is_empty() && value.is_empty() => {
-                (u16::from_str(port_candidate)?, 254)
+                (number(port_candidate)?, 254)
             }
             _ => (0, 254),
         };
@@ -2204,7 +2202,7 @@ impl From<Port> for (u16, u8, String) {
 }
 
 impl FromStr for Port {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Port::from_str(s)
     }
@@ -2229,6 +2227,217 @@ impl PartialOrd for Port {
     }
 }
 
+/// A range of port numbers of one protocol, `8000-8100`; a single port has `first == last`.
+/// The protocol is a number as in [`Port`], 254 when the range applies to any.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PortRange {
+    pub proto: u8,
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn new(first: u16, last: u16, proto: u8) -> Self {
+        Self {
+            proto,
+            first: first.min(last),
+            last: first.max(last),
+        }
+    }
+    pub fn contains(&self, number: u16) -> bool {
+        (self.first..=self.last).contains(&number)
+    }
+    pub fn is_single(&self) -> bool {
+        self.first == self.last
+    }
+    /// Whether a port of `self` and one of `other` can be the same, a range of any protocol
+    /// sharing numbers with every protocol.
+    pub fn same_proto(&self, other: &PortRange) -> bool {
+        self.proto == other.proto || self.proto == 254 || other.proto == 254
+    }
+}
+
+impl From<&Port> for PortRange {
+    fn from(port: &Port) -> Self {
+        Self::new(port.0, port.0, port.1)
+    }
+}
+
+/// `80` or `8000-8100`, the way nft writes them.
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.is_single() {
+            true => write!(f, "{}", self.first),
+            false => write!(f, "{}-{}", self.first, self.last),
+        }
+    }
+}
+
+/// Ports a rule matches on one side, as sorted ranges with overlapping and adjacent ranges of
+/// the same protocol merged. An empty set matches any port.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PortSet(Vec<PortRange>);
+
+impl PortSet {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.0
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, PortRange> {
+        self.0.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    /// Adds `range`, merging it with the ranges of its protocol it overlaps or touches.
+    pub fn insert(&mut self, range: PortRange) {
+        let mut merged = range;
+        self.0.retain(|r| {
+            let touches = r.proto == merged.proto
+                && r.first as u32 <= merged.last as u32 + 1
+                && merged.first as u32 <= r.last as u32 + 1;
+            if touches {
+                merged.first = merged.first.min(r.first);
+                merged.last = merged.last.max(r.last);
+            }
+            !touches
+        });
+        let at = self.0.partition_point(|r| *r < merged);
+        self.0.insert(at, merged);
+    }
+    pub fn push(&mut self, port: Port) {
+        self.insert(PortRange::from(&port));
+    }
+    pub fn append(&mut self, other: &mut PortSet) {
+        for range in other.0.drain(..) {
+            self.insert(range);
+        }
+    }
+    /// Whether the port `number` of protocol `proto` is in the set.
+    pub fn contains(&self, number: u16, proto: u8) -> bool {
+        self.0
+            .iter()
+            .any(|r| r.contains(number) && (r.proto == proto || r.proto == 254))
+    }
+    /// Whether every port of `other` is in `self` too; the empty set holds every port.
+    pub fn covers(&self, other: &PortSet) -> bool {
+        if self.is_empty() {
+            return true;
+        } else if other.is_empty() {
+            return false;
+        }
+        other.0.iter().all(|inner| {
+            // Ranges of several protocols may together cover one range. Only a range of any
+            // protocol covers one of any protocol, a `tcp` range leaves out its `udp` ports.
+            let mut next = inner.first as u32;
+            let mut outer: Vec<&PortRange> = self
+                .0
+                .iter()
+                .filter(|r| r.proto == inner.proto || r.proto == 254)
+                .collect();
+            outer.sort_by_key(|r| r.first);
+            for range in outer {
+                if range.first as u32 <= next && range.last as u32 >= next {
+                    next = range.last as u32 + 1;
+                }
+            }
+            next > inner.last as u32
+        })
+    }
+    /// Whether some port is in both, the empty set holding every port.
+    pub fn overlaps(&self, other: &PortSet) -> bool {
+        self.is_empty()
+            || other.is_empty()
+            || self.0.iter().any(|a| {
+                other
+                    .0
+                    .iter()
+                    .any(|b| a.same_proto(b) && a.first <= b.last && b.first <= a.last)
+            })
+    }
+    /// Parses ports of protocol `proto` written as `22`, `1024-65535`, `1024:65535`, a service
+    /// name from [`PORT_LIST`], or a `{ 80, 443, 8000-8100 }` list of those.
+    pub fn parse(s: &str, proto: u8) -> std::result::Result<Self, meta::Error> {
+        let mut set = Self::new();
+        let list = s.trim().trim_start_matches('{').trim_end_matches('}');
+        for item in list.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let number = |token: &str| match token.parse() {
+                Ok(number) => Ok(number),
+                Err(_) => service(token, proto)
+                    .ok_or_else(|| meta::Error::parse(token, "a port or service").locate(s, token)),
+            };
+            let numeric = |token: &str| token.trim().parse::<u16>().is_ok();
+            // Service names may hold a dash themselves, e.g. `http-alt`.
+            let range = match item.split_once(['-', ':']) {
+                Some((first, last)) if numeric(first) && numeric(last) => {
+                    PortRange::new(number(first.trim())?, number(last.trim())?, proto)
+                }
+                Some((first, last)) if !numeric(item) && service(item, proto).is_none() => {
+                    PortRange::new(number(first.trim())?, number(last.trim())?, proto)
+                }
+                _ => {
+                    let number = number(item)?;
+                    PortRange::new(number, number, proto)
+                }
+            };
+            set.insert(range);
+        }
+        Ok(set)
+    }
+}
+
+/// Number of the service `name` of protocol `proto` (254 for any) in [`PORT_LIST`].
+pub fn service(name: &str, proto: u8) -> Option<u16> {
+    PORT_LIST
+        .iter()
+        .find(|p| {
+            let service = p.2.split(',').next().unwrap_or_default().trim();
+            service.eq_ignore_ascii_case(name) && (proto == 254 || p.1 == proto)
+        })
+        .map(|p| p.0)
+}
+
+impl From<Vec<Port>> for PortSet {
+    fn from(ports: Vec<Port>) -> Self {
+        let mut set = Self::new();
+        for port in ports {
+            set.push(port);
+        }
+        set
+    }
+}
+
+/// One range as is and several as `{ 80, 443, 8000-8100 }`.
+impl Display for PortSet {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let ranges: Vec<String> = self.0.iter().map(|r| r.to_string()).collect();
+        match ranges.as_slice() {
+            [range] => write!(f, "{}", range),
+            _ => write!(f, "{{ {} }}", ranges.join(", ")),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a PortSet {
+    type Item = &'a PortRange;
+    type IntoIter = std::slice::Iter<'a, PortRange>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::parse(s, 254)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Action {
     None,
@@ -2315,25 +2524,32 @@ impl Action {
             Action::Masquerade => "masquerade".to_string(),
             Action::Snat(addr) => format!("snat to {}", addr),
             Action::Dnat(addr) => format!("dnat to {}", addr),
-            Action::Redirect(port) => format!("redirect {}", port.unwrap_or(0)),
+            Action::Redirect(None) => "redirect".to_string(),
+            Action::Redirect(Some(port)) => format!("redirect {}", port),
             Action::Ct => "ct".to_string(),
             Action::Queue => "queue".to_string(),
             Action::Quota(bytes) => format!("quota {}", bytes),
             Action::TProxy(port, mark) => format!("tproxy to {} with mark {}", port, mark),
             Action::Counter => "counter".to_string(),
-            Action::Hashlimit(rate, burst) => format!("hashlimit {} {}", rate, burst.unwrap_or(0)),
+            Action::Hashlimit(rate, None) => format!("hashlimit {}", rate),
+            Action::Hashlimit(rate, Some(burst)) => format!("hashlimit {} {}", rate, burst),
             Action::FlowOffload => "flow offload".to_string(),
             Action::Reclassify => "reclassify".to_string(),
         }
     }
 
-    pub fn from_str(s: &str) -> std::result::Result<Self, ParseIntError> {
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
         let s = s.trim();
+        let error =
+            |token: &str, expected: &str| meta::Error::parse(token, expected).locate(s, token);
+        // Missing arguments are reported at the end of the input.
+        let missing = |expected: &str| error(&s[s.len()..], expected);
         if s.is_empty() {
             return Ok(Action::None);
         }
         let mut parts = s.split_whitespace().peekable();
-        match parts.next().unwrap_or("").to_ascii_lowercase().as_str() {
+        let verb = parts.next().unwrap_or("");
+        match verb.to_ascii_lowercase().as_str() {
             "accept" => Ok(Action::Accept),
             "drop" => Ok(Action::Drop),
             "reject" => Ok(Action::Reject),
@@ -2341,24 +2557,23 @@ impl Action {
             "limit" => {
                 while let Some(token) = parts.next() {
                     if token == "over" {
-                        if let Some(rate_token) = parts.next() {
-                            if let Some(num) = rate_token.split('/').next() {
-                                return Ok(Action::Limit(num.parse::<u32>()?));
-                            }
-                        }
+                        let rate_token = parts.next().ok_or_else(|| missing("a rate"))?;
+                        let num = rate_token.split('/').next().unwrap_or(rate_token);
+                        return Ok(Action::Limit(
+                            num.parse::<u32>().map_err(|_| error(num, "a rate"))?,
+                        ));
                     }
                 }
-                Ok(Action::Limit(0))
+                Err(missing("'over' and a rate"))
             }
             "return" => Ok(Action::Return),
             "jump" => {
-                if let Some(chain_token) = parts.next() {
-                    let chain_str = chain_token.trim_start_matches("chain_");
-                    let val = chain_str.parse::<u32>()?;
-                    Ok(Action::Jump(val))
-                } else {
-                    Ok(Action::Jump(0))
-                }
+                let chain_token = parts.next().ok_or_else(|| missing("a chain number"))?;
+                let chain_str = chain_token.trim_start_matches("chain_");
+                let val = chain_str
+                    .parse::<u32>()
+                    .map_err(|_| error(chain_token, "a chain number"))?;
+                Ok(Action::Jump(val))
             }
             "log" => {
                 let mut msg = String::new();
@@ -2380,82 +2595,125 @@ impl Action {
                 Ok(Action::Log(msg))
             }
             "mark" => {
-                if let Some(val_token) = parts.next() {
-                    Ok(Action::Mark(val_token.trim().parse::<u32>()?))
-                } else {
-                    Ok(Action::Mark(0))
-                }
+                let val_token = parts.next().ok_or_else(|| missing("a mark"))?;
+                Ok(Action::Mark(
+                    val_token
+                        .parse::<u32>()
+                        .map_err(|_| error(val_token, "a mark"))?,
+                ))
             }
             "meta" => {
-                if let Some(msg_token) = parts.next() {
-                    Ok(Action::Meta(msg_token.trim_matches('"').to_string()))
-                } else {
-                    Ok(Action::Meta(String::new()))
+                let msg_token = parts.next().ok_or_else(|| missing("a meta statement"))?;
+                // `meta mark set <mark>` is how `Action::Mark` renders.
+                if msg_token == "mark" && parts.next_if(|t| *t == "set").is_some() {
+                    let val_token = parts.next().ok_or_else(|| missing("a mark"))?;
+                    return Ok(Action::Mark(
+                        val_token
+                            .parse::<u32>()
+                            .map_err(|_| error(val_token, "a mark"))?,
+                    ));
                 }
+                Ok(Action::Meta(msg_token.trim_matches('"').to_string()))
             }
             "set" => {
-                let key = parts.next().unwrap_or("").trim_matches('"').to_string();
-                let value = parts.next().unwrap_or("").trim_matches('"').to_string();
-                Ok(Action::Set(key, value))
+                let key = parts.next().ok_or_else(|| missing("a key"))?;
+                let value = parts.next().ok_or_else(|| missing("a value"))?;
+                Ok(Action::Set(
+                    key.trim_matches('"').to_string(),
+                    value.trim_matches('"').to_string(),
+                ))
             }
             "masquerade" => Ok(Action::Masquerade),
             "snat" => {
                 if let Some(_) = parts.next_if(|t| *t == "to") {}
-                let addr = parts.next().unwrap_or("").trim_matches('"').to_string();
-                Ok(Action::Snat(addr))
+                let addr = parts.next().ok_or_else(|| missing("an address"))?;
+                Ok(Action::Snat(addr.trim_matches('"').to_string()))
             }
             "dnat" => {
                 if let Some(_) = parts.next_if(|t| *t == "to") {}
-                let addr = parts.next().unwrap_or("").trim_matches('"').to_string();
-                Ok(Action::Dnat(addr))
+                let addr = parts.next().ok_or_else(|| missing("an address"))?;
+                Ok(Action::Dnat(addr.trim_matches('"').to_string()))
             }
             "redirect" => {
-                let port = parts.next().and_then(|s| s.parse::<u16>().ok());
-                Ok(Action::Redirect(port))
+                if let Some(_) = parts.next_if(|t| *t == "to") {}
+                match parts.next() {
+                    Some(port_token) => Ok(Action::Redirect(Some(
+                        port_token
+                            .trim_start_matches(':')
+                            .parse::<u16>()
+                            .map_err(|_| error(port_token, "a port number"))?,
+                    ))),
+                    None => Ok(Action::Redirect(None)),
+                }
             }
             "ct" => Ok(Action::Ct),
             "queue" => Ok(Action::Queue),
             "quota" => {
-                if let Some(bytes_token) = parts.next() {
-                    Ok(Action::Quota(bytes_token.parse::<u64>()?))
-                } else {
-                    Ok(Action::Quota(0))
-                }
+                let bytes_token = parts.next().ok_or_else(|| missing("a byte count"))?;
+                Ok(Action::Quota(
+                    bytes_token
+                        .parse::<u64>()
+                        .map_err(|_| error(bytes_token, "a byte count"))?,
+                ))
             }
             "tproxy" => {
-                let mut port: u16 = 0;
+                let mut port = None;
                 let mut mark: u32 = 0;
                 while let Some(token) = parts.next() {
-                    if token == "to" {
-                        if let Some(port_token) = parts.next() {
-                            port = port_token.parse::<u16>().unwrap_or(0);
+                    match token {
+                        "to" => {
+                            let port_token =
+                                parts.next().ok_or_else(|| missing("a port number"))?;
+                            port = Some(
+                                port_token
+                                    .trim_start_matches(':')
+                                    .parse::<u16>()
+                                    .map_err(|_| error(port_token, "a port number"))?,
+                            );
                         }
-                    }
-                    if token == "with" {
-                        if let Some(_) = parts.next_if(|t| *t == "mark") {
-                            if let Some(mark_token) = parts.next() {
-                                mark = mark_token.parse::<u32>().unwrap_or(0);
-                            }
+                        "with" => {
+                            let mark_token = match parts.next() {
+                                Some("mark") => parts.next().ok_or_else(|| missing("a mark"))?,
+                                Some(other) => return Err(error(other, "'mark'")),
+                                None => return Err(missing("'mark'")),
+                            };
+                            mark = mark_token
+                                .parse::<u32>()
+                                .map_err(|_| error(mark_token, "a mark"))?;
                         }
+                        other => return Err(error(other, "'to' or 'with'")),
                     }
                 }
-                Ok(Action::TProxy(port, mark))
+                Ok(Action::TProxy(
+                    port.ok_or_else(|| missing("'to' and a port"))?,
+                    mark,
+                ))
             }
             "counter" => Ok(Action::Counter),
             "hashlimit" => {
-                let rate = parts.next().unwrap_or("").to_string();
-                let burst = parts.next().and_then(|s| s.parse::<u32>().ok());
-                Ok(Action::Hashlimit(rate, burst))
-            }
-            "flow" => {
-                if let Some("offload") = parts.next() {
-                    Ok(Action::FlowOffload)
-                } else {
-                    Ok(Action::None)
-                }
+                let rate = parts.next().ok_or_else(|| missing("a rate"))?;
+                let burst = match parts.next() {
+                    Some(burst_token) => Some(
+                        burst_token
+                            .parse::<u32>()
+                            .map_err(|_| error(burst_token, "a burst"))?,
+                    ),
+                    None => None,
+                };
+                Ok(Action::Hashlimit(rate.to_string(), burst))
             }
+            "flow" => match parts.next() {
+                Some("offload") => Ok(Action::FlowOffload),
+                Some(other) => Err(error(other, "'offload'")),
+                None => Err(missing("'offload'")),
+            },
             "reclassify" => Ok(Action::Reclassify),
-            _ => Ok(Action::None),
+            _ => Err(error(
+                verb,
+                "an action: accept, drop, reject, skip, limit, return, jump, log, mark, meta, \
+                 set, masquerade, snat, dnat, redirect, ct, queue, quota, tproxy, counter, \
+                 hashlimit, flow offload or reclassify",
+            )),
         }
     }
 
@@ -2622,7 +2880,7 @@ impl Display for Action {
 }
 
 impl FromStr for Action {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -2675,6 +2933,7 @@ pub struct Chain {
     rules: Vec<Rule>,
     number: u32,
     name: String,
+    base: Option<Base>,
 }
 
 impl Chain {
@@ -2687,6 +2946,7 @@ impl Chain {
             rules: self.rules.clone(),
             number: self.number,
             name: self.name.clone(),
+            base: self.base.clone(),
         }
     }
 
@@ -2694,37 +2954,15 @@ impl Chain {
         write!(f, "{}", self.to_string())
     }
 
-    pub fn from_str(s: &str) -> std::result::Result<Self, ParseIntError> {
-        let mut rules = Vec::<Rule>::new();
-        let mut name = String::new();
-        let mut number = 0u32;
-        for line in s.lines() {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            if let Some(rest) = line.strip_prefix("chain ") {
-                let mut parts = rest.split_whitespace();
-                if let Some(n) = parts.next() {
-                    name = n.to_string();
-                }
-                if let Some(part) = parts.next() {
-                    let part = part.trim_matches(|c| c == '(' || c == ')');
-                    if let Ok(n) = part.parse::<u32>() {
-                        number = n;
-                    }
-                }
-            } else if let Some(r) = line.strip_prefix("  ") {
-                rules.push(Rule::from_str(r)?);
-            } else {
-                rules.push(Rule::from_str(line)?);
-            }
-        }
-        Ok(Self {
-            rules,
-            number,
-            name,
-        })
+    /// Parses an nft `chain <name> { ... }` block, see [`nft::parse_chain`]. Jumps name their
+    /// target `chain_<number>`.
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
+        nft::parse_chain(s, &[])
+    }
+
+    /// The hook, priority and policy of a base chain, `None` for regular chains.
+    pub fn get_base(&self) -> Option<&Base> {
+        self.base.as_ref()
     }
 
     pub fn get_name(&self) -> &str {
@@ -2735,6 +2973,24 @@ impl Chain {
         self.number
     }
 
+    /// Reports rules that never match, rules a later one makes redundant and pairs of rules
+    /// whose verdict depends on their order, see [`analysis::Kind`].
+    pub fn analyze(&self) -> Vec<analysis::Finding> {
+        analysis::chain(self)
+    }
+
+    /// Rule changes and verdict shifts from this chain to `new`, see [`diff::chain`]. Jumps
+    /// are not followed, use [`Table::diff`] for those.
+    pub fn diff(&self, new: &Chain) -> diff::ChainDiff {
+        diff::chain(diff::Side::new(self), diff::Side::new(new))
+    }
+
+    /// Runs `packet` through the chain alone. Jumps are recorded but not followed and `@set`
+    /// matches never hold, use [`Table::simulate`] for those.
+    pub fn simulate(&self, packet: &simulate::Packet) -> simulate::Outcome {
+        simulate::chain(self, &[], &[], packet)
+    }
+
     pub fn get_rules(&self) -> &Vec<Rule> {
         &self.rules
     }
@@ -2743,6 +2999,10 @@ impl Chain {
         self.rules.insert(index, rule);
     }
 
+    pub fn is_base(&self) -> bool {
+        self.base.is_some()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.rules.is_empty()
     }
@@ -2756,6 +3016,7 @@ impl Chain {
             rules: Vec::new(),
             number: 0,
             name: String::new(),
+            base: None,
         }
     }
 
@@ -2767,6 +3028,10 @@ impl Chain {
         self.rules.remove(index)
     }
 
+    pub fn set_base(&mut self, base: Option<Base>) {
+        self.base = base;
+    }
+
     pub fn set_name(&mut self, name: &str) {
         self.name = name.to_string();
     }
@@ -2787,15 +3052,12 @@ impl Chain {
         self.rules.swap_remove(index)
     }
 
+    /// Renders the chain as an nft `chain <name> { ... }` block, see [`nft::chain`].
     pub fn to_string(&self) -> String {
-        let mut s = String::new();
-        s.push_str(&format!("chain {} ({})\n", self.name, self.number));
-        for rule in &self.rules {
-            s.push_str("  ");
-            s.push_str(&rule.to_string());
-            s.push('\n');
-        }
-        s
+        nft::chain(self, &[])
+            .lines()
+            .map(|line| format!("{}\n", line.strip_prefix('\t').unwrap_or(line)))
+            .collect()
     }
 }
 impl Default for Chain {
@@ -2804,6 +3066,7 @@ impl Default for Chain {
             name: "default".to_string(),
             number: 0,
             rules: Vec::new(),
+            base: None,
         }
     }
 }
@@ -2817,7 +3080,7 @@ impl Display for Chain {
 impl Eq for Chain {}
 
 impl FromStr for Chain {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -2829,12 +3092,16 @@ impl Ord for Chain {
             .cmp(&other.number)
             .then_with(|| self.name.cmp(&other.name))
             .then_with(|| self.rules.cmp(&other.rules))
+            .then_with(|| self.base.cmp(&other.base))
     }
 }
 
 impl PartialEq for Chain {
     fn eq(&self, other: &Self) -> bool {
-        self.number == other.number && self.name == other.name && self.rules == other.rules
+        self.number == other.number
+            && self.name == other.name
+            && self.rules == other.rules
+            && self.base == other.base
     }
 }
 
@@ -2843,450 +3110,1321 @@ impl PartialOrd for Chain {
         Some(self.cmp(other))
     }
 }
-#[derive(Debug, Clone)]
-pub struct Rule {
-    ips: (Vec<Ip>, Vec<Ip>),
-    ports: (Vec<Port>, Vec<Port>),
-    protocols: Vec<Proto>,
-    family: Family,
-    direction: Direction,
-    action: Action,
-    name: String,
-    comment: String,
+/// Netfilter hook a base chain attaches to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Hook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+    Postrouting,
+    Ingress,
 }
 
-impl Rule {
-    pub fn new(
-        ips: (Vec<Ip>, Vec<Ip>),
-        ports: (Vec<Port>, Vec<Port>),
-        protocols: Vec<Proto>,
-        family: Family,
-        direction: Direction,
-        action: Action,
-        name: String,
-        comment: String,
-    ) -> Self {
-        Self {
-            ips,
-            ports,
-            protocols,
-            family,
-            direction,
-            action,
-            name,
-            comment,
+impl Hook {
+    pub const ALL: [Hook; 6] = [
+        Hook::Prerouting,
+        Hook::Input,
+        Hook::Forward,
+        Hook::Output,
+        Hook::Postrouting,
+        Hook::Ingress,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Hook::Prerouting => "prerouting",
+            Hook::Input => "input",
+            Hook::Forward => "forward",
+            Hook::Output => "output",
+            Hook::Postrouting => "postrouting",
+            Hook::Ingress => "ingress",
         }
     }
-
-    pub fn clone(&self) -> Self {
-        Self {
-            ips: (self.ips.0.clone(), self.ips.1.clone()),
-            ports: (self.ports.0.clone(), self.ports.1.clone()),
-            protocols: self.protocols.clone(),
-            family: self.family,
-            direction: self.direction,
-            action: self.action.clone(),
-            name: self.name.clone(),
-            comment: self.comment.clone(),
-        }
+    pub fn from_name(s: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|hook| hook.as_str().eq_ignore_ascii_case(s.trim()))
     }
+}
 
-    pub fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl Display for Hook {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.as_str())
     }
+}
 
-    pub fn cmp(&self, other: &Self) -> Ordering {
-        self.ips
-            .0
-            .cmp(&other.ips.0)
-            .then_with(|| self.ips.1.cmp(&other.ips.1))
-            .then_with(|| self.ports.0.cmp(&other.ports.0))
-            .then_with(|| self.ports.1.cmp(&other.ports.1))
-            .then_with(|| self.protocols.cmp(&other.protocols))
-            .then_with(|| self.family.cmp(&other.family))
-            .then_with(|| self.direction.cmp(&other.direction))
-            .then_with(|| self.action.cmp(&other.action))
-            .then_with(|| self.name.cmp(&other.name))
-            .then_with(|| self.comment.cmp(&other.comment))
+impl FromStr for Hook {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| meta::Error::parse(s, "a hook"))
     }
+}
 
-    pub fn eq(&self, other: &Self) -> bool {
-        self.ips.0 == other.ips.0
-            && self.ips.1 == other.ips.1
-            && self.ports.0 == other.ports.0
-            && self.ports.1 == other.ports.1
-            && self.protocols == other.protocols
-            && self.family == other.family
-            && self.direction == other.direction
-            && self.action == other.action
-            && self.name == other.name
-            && self.comment == other.comment
-    }
+/// Type of a base chain, which decides the statements its rules may use.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ChainType {
+    #[default]
+    Filter,
+    Nat,
+    Route,
+}
 
-    pub fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
+impl ChainType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChainType::Filter => "filter",
+            ChainType::Nat => "nat",
+            ChainType::Route => "route",
+        }
     }
-
-    pub fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{self}")
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "filter" => Some(ChainType::Filter),
+            "nat" => Some(ChainType::Nat),
+            "route" => Some(ChainType::Route),
+            _ => None,
+        }
     }
+}
 
-    pub fn src_ips(&self) -> &[Ip] {
-        &self.ips.0
+impl Display for ChainType {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.as_str())
     }
-    pub fn dst_ips(&self) -> &[Ip] {
-        &self.ips.1
+}
+
+impl FromStr for ChainType {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| meta::Error::parse(s, "a chain type"))
     }
-    pub fn src_ports(&self) -> &[Port] {
-        &self.ports.0
+}
+
+/// Position of a base chain among the chains on the same hook, lower runs first.
+///
+/// Named priorities are kept as written, with their offset, so they render back unchanged.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    Number(i32),
+    Named(String, i32),
+}
+
+impl Priority {
+    /// Standard priority names and their values in the ip, ip6 and inet families.
+    pub const NAMES: [(&'static str, i32); 6] = [
+        ("raw", -300),
+        ("mangle", -150),
+        ("dstnat", -100),
+        ("filter", 0),
+        ("security", 50),
+        ("srcnat", 100),
+    ];
+    /// The numeric priority nft orders chains by.
+    pub fn value(&self) -> i32 {
+        match self {
+            Priority::Number(value) => *value,
+            Priority::Named(name, offset) => {
+                Self::NAMES
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| *value)
+                    .unwrap_or(0)
+                    + offset
+            }
+        }
     }
-    pub fn dst_ports(&self) -> &[Port] {
-        &self.ports.1
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Number(0)
     }
-    pub fn protocols(&self) -> &[Proto] {
-        &self.protocols
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Priority::Number(value) => write!(f, "{}", value),
+            Priority::Named(name, 0) => write!(f, "{}", name),
+            Priority::Named(name, offset) if *offset < 0 => write!(f, "{} - {}", name, -offset),
+            Priority::Named(name, offset) => write!(f, "{} + {}", name, offset),
+        }
     }
-    pub fn family(&self) -> &Family {
-        &self.family
+}
+
+/// Parses `-150`, `filter` or `filter + 10`.
+impl FromStr for Priority {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let compact: String = s.split_whitespace().collect();
+        let error = || meta::Error::parse(s.trim(), "a priority");
+        if let Ok(value) = compact.parse::<i32>() {
+            return Ok(Priority::Number(value));
+        }
+        let (name, offset) = match compact.find(['+', '-']) {
+            Some(i) => (
+                &compact[..i],
+                compact[i..]
+                    .trim_start_matches('+')
+                    .parse::<i32>()
+                    .map_err(|_| error())?,
+            ),
+            None => (compact.as_str(), 0),
+        };
+        match Self::NAMES.iter().find(|(n, _)| *n == name) {
+            Some((n, _)) => Ok(Priority::Named(n.to_string(), offset)),
+            None => Err(error()),
+        }
     }
-    pub fn direction(&self) -> &Direction {
-        &self.direction
+}
+
+/// What makes a chain a base chain: the hook it is attached to, its type and priority, and the
+/// policy applied to packets that reach its end.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Base {
+    kind: ChainType,
+    hook: Hook,
+    device: Option<String>,
+    priority: Priority,
+    policy: Action,
+}
+
+impl Base {
+    pub fn new(kind: ChainType, hook: Hook, priority: Priority, policy: Action) -> Self {
+        Self {
+            kind,
+            hook,
+            device: None,
+            priority,
+            policy,
+        }
     }
-    pub fn action(&self) -> &Action {
-        &self.action
+    pub fn kind(&self) -> ChainType {
+        self.kind
+    }
+    pub fn hook(&self) -> Hook {
+        self.hook
+    }
+    /// Interface an `ingress` chain is bound to.
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+    pub fn priority(&self) -> &Priority {
+        &self.priority
+    }
+    pub fn policy(&self) -> &Action {
+        &self.policy
+    }
+    pub fn set_kind(&mut self, kind: ChainType) {
+        self.kind = kind;
+    }
+    pub fn set_hook(&mut self, hook: Hook) {
+        self.hook = hook;
+    }
+    pub fn set_device(&mut self, device: Option<&str>) {
+        self.device = device.map(str::to_string);
+    }
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+    pub fn set_policy(&mut self, policy: Action) {
+        self.policy = policy;
+    }
+    /// Parses the `type filter hook input priority 0; policy drop;` header of a base chain.
+    /// The policy defaults to accept, as in nft.
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
+        let error = |message: String| meta::Error::new(message, 2);
+        let mut kind = None;
+        let mut hook = None;
+        let mut device = None;
+        let mut priority = None;
+        let mut policy = Action::Accept;
+        for statement in s.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut words = statement.split_whitespace();
+            while let Some(word) = words.next() {
+                let mut value = || {
+                    words
+                        .next()
+                        .ok_or_else(|| error(format!("missing value after '{}'", word)))
+                };
+                match word {
+                    "type" => {
+                        let value = value()?;
+                        kind = Some(ChainType::from_str(value).map_err(|e| e.locate(s, value))?)
+                    }
+                    "hook" => {
+                        let value = value()?;
+                        hook = Some(Hook::from_str(value).map_err(|e| e.locate(s, value))?)
+                    }
+                    "device" => device = Some(value()?.trim_matches('"').to_string()),
+                    "priority" => {
+                        let rest: Vec<&str> = words.by_ref().collect();
+                        priority = Some(Priority::from_str(&rest.join(" "))?);
+                    }
+                    "policy" => {
+                        policy = match value()? {
+                            "accept" => Action::Accept,
+                            "drop" => Action::Drop,
+                            other => {
+                                return Err(
+                                    meta::Error::parse(other, "accept or drop").locate(s, other)
+                                );
+                            }
+                        }
+                    }
+                    other => {
+                        return Err(
+                            meta::Error::parse(other, "a chain header keyword").locate(s, other)
+                        );
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            kind: kind.unwrap_or_default(),
+            hook: hook.ok_or_else(|| error(format!("missing hook in '{}'", s.trim())))?,
+            device,
+            priority: priority
+                .ok_or_else(|| error(format!("missing priority in '{}'", s.trim())))?,
+            policy,
+        })
+    }
+}
+
+impl Display for Base {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "type {} hook {}", self.kind, self.hook)?;
+        if let Some(device) = &self.device {
+            write!(f, " device \"{}\"", device)?;
+        }
+        write!(f, " priority {}; policy {};", self.priority, self.policy)
+    }
+}
+
+impl FromStr for Base {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_str(s)
+    }
+}
+
+/// A named set declared in a table, e.g. `set blocklist { type ipv4_addr; flags interval; }`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct NamedSet {
+    name: String,
+    kind: String,
+    flags: Vec<String>,
+    elements: Vec<String>,
+}
+
+impl NamedSet {
+    /// A set of `kind` elements, the nft type such as `ipv4_addr` or `inet_service`.
+    pub fn new(name: &str, kind: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            flags: Vec::new(),
+            elements: Vec::new(),
+        }
     }
     pub fn name(&self) -> &str {
         &self.name
     }
-    pub fn comment(&self) -> &str {
-        &self.comment
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+    pub fn flags(&self) -> &[String] {
+        &self.flags
+    }
+    pub fn elements(&self) -> &[String] {
+        &self.elements
+    }
+    pub fn set_flags(&mut self, flags: Vec<String>) {
+        self.flags = flags;
     }
+    pub fn set_elements(&mut self, elements: Vec<String>) {
+        self.elements = elements;
+    }
+    pub fn push(&mut self, element: &str) {
+        self.elements.push(element.to_string());
+    }
+}
 
-    pub fn to_string(&self) -> String {
-        let mut parts = Vec::new();
-        let family = self.family().as_str();
-
-        // Family, chain, and direction
-        let dir_str = match self.direction() {
-            Direction::In => "input",
-            Direction::Out => "output",
-            Direction::Fwd => "forward",
-            Direction::None => "",
-        };
-        if !dir_str.is_empty() {
-            parts.push(format!("{} filter {}", family, dir_str));
-        } else {
-            parts.push(format!("{} filter", family));
+/// A named map declared in a table, e.g. `map ports { type inet_service : verdict; }`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct NamedMap {
+    name: String,
+    key: String,
+    value: String,
+    flags: Vec<String>,
+    elements: Vec<(String, String)>,
+}
+
+impl NamedMap {
+    pub fn new(name: &str, key: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            flags: Vec::new(),
+            elements: Vec::new(),
         }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+    pub fn flags(&self) -> &[String] {
+        &self.flags
+    }
+    pub fn elements(&self) -> &[(String, String)] {
+        &self.elements
+    }
+    pub fn set_flags(&mut self, flags: Vec<String>) {
+        self.flags = flags;
+    }
+    pub fn set_elements(&mut self, elements: Vec<(String, String)>) {
+        self.elements = elements;
+    }
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.elements.push((key.to_string(), value.to_string()));
+    }
+}
 
-        // Source IPs
-        if !self.ips.0.is_empty() {
-            let ips: Vec<_> = self
-                .ips
-                .0
-                .iter()
-                .filter(|ip| family == "any" || ip.family().as_str() == family)
-                .map(|ip| ip.to_string())
-                .collect();
-            if !ips.is_empty() {
-                if ips.len() > 1 {
-                    parts.push(format!("saddr {{ {} }}", ips.join(", ")));
-                } else {
-                    parts.push(format!("saddr {}", ips[0]));
+/// A table: the unit nft loads chains, sets and maps in. [`Family::Any`] is the `inet` family.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Table {
+    family: Family,
+    name: String,
+    chains: Vec<Chain>,
+    sets: Vec<NamedSet>,
+    maps: Vec<NamedMap>,
+}
+
+impl Table {
+    pub fn new(family: Family, name: &str) -> Self {
+        Self {
+            family,
+            name: name.to_string(),
+            chains: Vec::new(),
+            sets: Vec::new(),
+            maps: Vec::new(),
+        }
+    }
+    pub fn family(&self) -> Family {
+        self.family
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn chains(&self) -> &[Chain] {
+        &self.chains
+    }
+    pub fn chains_mut(&mut self) -> &mut Vec<Chain> {
+        &mut self.chains
+    }
+    pub fn sets(&self) -> &[NamedSet] {
+        &self.sets
+    }
+    pub fn maps(&self) -> &[NamedMap] {
+        &self.maps
+    }
+    pub fn chain(&self, name: &str) -> Option<&Chain> {
+        self.chains.iter().find(|chain| chain.name == name)
+    }
+    pub fn set(&self, name: &str) -> Option<&NamedSet> {
+        self.sets.iter().find(|set| set.name == name)
+    }
+    pub fn map(&self, name: &str) -> Option<&NamedMap> {
+        self.maps.iter().find(|map| map.name == name)
+    }
+    pub fn set_family(&mut self, family: Family) {
+        self.family = family;
+    }
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    /// Adds a chain, numbering it after the last chain when it has no number yet so jumps
+    /// can refer to it.
+    pub fn push_chain(&mut self, mut chain: Chain) {
+        if chain.number == 0 {
+            chain.number = self.chains.iter().map(|c| c.number).max().unwrap_or(0) + 1;
+        }
+        self.chains.push(chain);
+    }
+    pub fn push_set(&mut self, set: NamedSet) {
+        self.sets.push(set);
+    }
+    pub fn push_map(&mut self, map: NamedMap) {
+        self.maps.push(map);
+    }
+    /// Changes from this table to `new`, chain by chain, see [`diff::chains`].
+    pub fn diff(&self, new: &Table) -> diff::Diff {
+        diff::chains(&self.chains, &new.chains, (&self.sets, &new.sets))
+    }
+    /// Runs `packet` through the base chains of the table on its hook, see [`simulate::tables`].
+    pub fn simulate(&self, packet: &simulate::Packet) -> simulate::Outcome {
+        simulate::tables([self], packet)
+    }
+    /// Checks the table for problems nft would reject or that make rules dead.
+    ///
+    /// Reports duplicate chain, set and map names, base chain policies other than accept and
+    /// drop, jumps to chains that do not exist, addresses and ICMP types of a family an
+    /// `ip`/`ip6` table never sees, interfaces a base chain's hook does not know, such as
+    /// `oifname` on input, and rules whose ports disagree with their protocols, see
+    /// [`Rule::validate`].
+    pub fn validate(&self) -> Vec<meta::Error> {
+        let mut problems = Vec::new();
+        let mut problem = |message: String| {
+            problems.push(meta::Error::new(
+                format!(
+                    "table {} {}: {}",
+                    nft::family(self.family),
+                    self.name,
+                    message
+                ),
+                2,
+            ))
+        };
+        let mut names: Vec<(&str, &str)> = Vec::new();
+        let declared = self
+            .chains
+            .iter()
+            .map(|c| ("chain", c.name.as_str()))
+            .chain(self.sets.iter().map(|s| ("set", s.name.as_str())))
+            .chain(self.maps.iter().map(|m| ("map", m.name.as_str())));
+        for (kind, name) in declared {
+            if names.contains(&(kind, name)) {
+                problem(format!("duplicate {} '{}'", kind, name));
+            }
+            names.push((kind, name));
+        }
+        for chain in &self.chains {
+            if let Some(base) = &chain.base
+                && !matches!(base.policy, Action::Accept | Action::Drop)
+            {
+                problem(format!(
+                    "base chain {} has policy {}, only accept and drop are allowed",
+                    chain.name, base.policy
+                ));
+            }
+            for (i, rule) in chain.rules.iter().enumerate() {
+                for e in rule.validate() {
+                    problem(format!(
+                        "chain {} rule {}: {}",
+                        chain.name,
+                        i + 1,
+                        e.message
+                    ));
+                }
+                // No output interface is known before routing, no input one for local packets.
+                let unknown = match chain.base.as_ref().map(|b| b.hook) {
+                    Some(hook @ (Hook::Prerouting | Hook::Input | Hook::Ingress)) => {
+                        Some((hook, "oifname", &rule.interfaces.1))
+                    }
+                    Some(hook @ Hook::Output) => Some((hook, "iifname", &rule.interfaces.0)),
+                    _ => None,
+                };
+                if let Some((hook, field, names)) = unknown
+                    && !names.is_empty()
+                {
+                    problem(format!(
+                        "chain {} matches {} on the {} hook where it is never set",
+                        chain.name, field, hook
+                    ));
+                }
+                if let Action::Jump(number) = rule.action
+                    && !self.chains.iter().any(|c| c.number == number)
+                {
+                    problem(format!(
+                        "chain {} jumps to unknown chain {}",
+                        chain.name, number
+                    ));
+                }
+                let restricted = self.family.is_v4() != self.family.is_v6();
+                for ip in rule.ips.0.iter().chain(rule.ips.1.iter()) {
+                    if restricted
+                        && matches!(ip, Ip::V4(_) | Ip::V6(_))
+                        && ip.family() != self.family
+                    {
+                        problem(format!(
+                            "chain {} matches {} address {} which never reaches this table",
+                            chain.name,
+                            ip.family(),
+                            ip
+                        ));
+                    }
+                }
+                for icmp in rule
+                    .icmp
+                    .iter()
+                    .filter(|i| restricted && i.family != self.family)
+                {
+                    problem(format!(
+                        "chain {} matches {} type {} which never reaches this table",
+                        chain.name,
+                        nft::icmp_keyword(icmp.family),
+                        icmp
+                    ));
                 }
             }
         }
+        problems
+    }
+}
 
-        // Destination IPs
-        if !self.ips.1.is_empty() {
-            let ips: Vec<_> = self
-                .ips
-                .1
+impl Default for Table {
+    fn default() -> Self {
+        Self::new(Family::Any, nft::TABLE)
+    }
+}
+
+/// Renders the table as an `nft -f` script, see [`nft::table`].
+impl Display for Table {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", nft::table(self))
+    }
+}
+
+/// Several tables loaded together, e.g. the output of `nft list ruleset`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Ruleset {
+    tables: Vec<Table>,
+}
+
+impl Ruleset {
+    pub fn new() -> Self {
+        Self { tables: Vec::new() }
+    }
+    pub fn tables(&self) -> &[Table] {
+        &self.tables
+    }
+    pub fn tables_mut(&mut self) -> &mut Vec<Table> {
+        &mut self.tables
+    }
+    pub fn table(&self, family: Family, name: &str) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|table| table.family == family && table.name == name)
+    }
+    pub fn push(&mut self, table: Table) {
+        self.tables.push(table);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+    /// Runs `packet` through the base chains of every table on its hook, see
+    /// [`simulate::tables`].
+    pub fn simulate(&self, packet: &simulate::Packet) -> simulate::Outcome {
+        simulate::tables(&self.tables, packet)
+    }
+    /// Validates every table, see [`Table::validate`], and reports tables declared twice.
+    pub fn validate(&self) -> Vec<meta::Error> {
+        let mut problems = Vec::new();
+        for (i, table) in self.tables.iter().enumerate() {
+            if self.tables[..i]
                 .iter()
-                .filter(|ip| family == "any" || ip.family().as_str() == family)
-                .map(|ip| ip.to_string())
-                .collect();
-            if !ips.is_empty() {
-                if ips.len() > 1 {
-                    parts.push(format!("daddr {{ {} }}", ips.join(", ")));
-                } else {
-                    parts.push(format!("daddr {}", ips[0]));
-                }
+                .any(|t| t.family == table.family && t.name == table.name)
+            {
+                problems.push(meta::Error::new(
+                    format!(
+                        "table {} {} is declared more than once",
+                        nft::family(table.family),
+                        table.name
+                    ),
+                    2,
+                ));
             }
+            problems.append(&mut table.validate());
         }
+        problems
+    }
+    /// Parses the nft script format the ruleset renders to.
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
+        nft::parse(s)
+    }
+}
 
-        // Protocols
-        if !self.protocols.is_empty() {
-            let protos: Vec<_> = self.protocols.iter().map(|p| p.to_string()).collect();
-            if protos.len() > 1 {
-                parts.push(format!("protocol {{ {} }}", protos.join(", ")));
-            } else {
-                parts.push(format!("protocol {}", protos[0]));
+impl Display for Ruleset {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for (i, table) in self.tables.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
             }
+            write!(f, "{}", table)?;
         }
+        Ok(())
+    }
+}
 
-        // Source Ports
-        if !self.ports.0.is_empty() {
-            let ports: Vec<_> = self.ports.0.iter().map(|p| p.to_string()).collect();
-            if ports.len() > 1 {
-                parts.push(format!("sport {{ {} }}", ports.join(", ")));
-            } else {
-                parts.push(format!("sport {}", ports[0]));
-            }
+impl FromStr for Ruleset {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_str(s)
+    }
+}
+
+/// State of a connection as seen by connection tracking.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CtState {
+    New,
+    Established,
+    Related,
+    Invalid,
+    Untracked,
+}
+
+impl CtState {
+    pub const ALL: [CtState; 5] = [
+        CtState::New,
+        CtState::Established,
+        CtState::Related,
+        CtState::Invalid,
+        CtState::Untracked,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CtState::New => "new",
+            CtState::Established => "established",
+            CtState::Related => "related",
+            CtState::Invalid => "invalid",
+            CtState::Untracked => "untracked",
         }
+    }
+    pub fn from_name(s: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|state| state.as_str().eq_ignore_ascii_case(s.trim()))
+    }
+}
 
-        // Destination Ports
-        if !self.ports.1.is_empty() {
-            let ports: Vec<_> = self.ports.1.iter().map(|p| p.to_string()).collect();
-            if ports.len() > 1 {
-                parts.push(format!("dport {{ {} }}", ports.join(", ")));
-            } else {
-                parts.push(format!("dport {}", ports[0]));
-            }
+impl Display for CtState {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CtState {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| meta::Error::parse(s, "a ct state"))
+    }
+}
+
+/// Parses a mark or other 32 bit value written in decimal or as `0x` hex, as nft and iptables
+/// list them.
+pub fn parse_mark(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Whether `name` is a valid interface name or pattern: at most 15 bytes, no whitespace, quotes
+/// or slashes, and a `*` wildcard only at the end.
+pub fn is_interface(name: &str) -> bool {
+    let base = name.strip_suffix('*').unwrap_or(name);
+    !name.is_empty()
+        && name.len() < 16
+        && !base.contains(['*', '/', '"', '\'', '{', '}', ','])
+        && !base.chars().any(char::is_whitespace)
+}
+
+/// Whether the interface `name` matches `pattern`, `eth*` matching every name starting `eth`.
+pub fn interface_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// Status bits of a tracked connection, as `ct status` names them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CtStatus {
+    Expected,
+    SeenReply,
+    Assured,
+    Confirmed,
+    Snat,
+    Dnat,
+    Dying,
+}
+
+impl CtStatus {
+    pub const ALL: [CtStatus; 7] = [
+        CtStatus::Expected,
+        CtStatus::SeenReply,
+        CtStatus::Assured,
+        CtStatus::Confirmed,
+        CtStatus::Snat,
+        CtStatus::Dnat,
+        CtStatus::Dying,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CtStatus::Expected => "expected",
+            CtStatus::SeenReply => "seen-reply",
+            CtStatus::Assured => "assured",
+            CtStatus::Confirmed => "confirmed",
+            CtStatus::Snat => "snat",
+            CtStatus::Dnat => "dnat",
+            CtStatus::Dying => "dying",
         }
+    }
+    /// Also accepts the iptables spelling, e.g. `SEEN_REPLY`.
+    pub fn from_name(s: &str) -> Option<Self> {
+        let name = s.trim().replace('_', "-");
+        Self::ALL
+            .into_iter()
+            .find(|status| status.as_str().eq_ignore_ascii_case(&name))
+    }
+}
+
+impl Display for CtStatus {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for CtStatus {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| meta::Error::parse(s, "a ct status"))
+    }
+}
+
+/// Connection tracking match of a rule, e.g. `ct state established,related`. Each part left
+/// empty matches every packet, so an empty match matches them all.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Conntrack {
+    state: Vec<CtState>,
+    status: Vec<CtStatus>,
+    zone: Option<u16>,
+    mark: Option<u32>,
+    helper: Option<String>,
+}
 
-        // Action
-        let action_str = self.action().to_string();
-        if !action_str.is_empty() {
-            parts.push(action_str);
+impl Conntrack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn state(&self) -> &[CtState] {
+        &self.state
+    }
+    pub fn set_state(&mut self, state: Vec<CtState>) {
+        self.state = state;
+    }
+    pub fn status(&self) -> &[CtStatus] {
+        &self.status
+    }
+    pub fn set_status(&mut self, status: Vec<CtStatus>) {
+        self.status = status;
+    }
+    pub fn zone(&self) -> Option<u16> {
+        self.zone
+    }
+    pub fn set_zone(&mut self, zone: Option<u16>) {
+        self.zone = zone;
+    }
+    pub fn mark(&self) -> Option<u32> {
+        self.mark
+    }
+    pub fn set_mark(&mut self, mark: Option<u32>) {
+        self.mark = mark;
+    }
+    /// Name of the helper the connection is assigned to, e.g. `ftp`.
+    pub fn helper(&self) -> Option<&str> {
+        self.helper.as_deref()
+    }
+    pub fn set_helper(&mut self, helper: Option<String>) {
+        self.helper = helper;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+            && self.status.is_empty()
+            && self.zone.is_none()
+            && self.mark.is_none()
+            && self.helper.is_none()
+    }
+}
+
+/// Lists the parts as `ct <key> <value>`, several values joined by commas.
+impl Display for Conntrack {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let mut parts = Vec::new();
+        if !self.state.is_empty() {
+            let states: Vec<&str> = self.state.iter().map(|s| s.as_str()).collect();
+            parts.push(format!("ct state {}", states.join(",")));
+        }
+        if !self.status.is_empty() {
+            let status: Vec<&str> = self.status.iter().map(|s| s.as_str()).collect();
+            parts.push(format!("ct status {}", status.join(",")));
         }
+        if let Some(zone) = self.zone {
+            parts.push(format!("ct zone {}", zone));
+        }
+        if let Some(mark) = self.mark {
+            parts.push(format!("ct mark {:#x}", mark));
+        }
+        if let Some(helper) = &self.helper {
+            parts.push(format!("ct helper \"{}\"", helper));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// ICMP message types by the names nft lists them with.
+pub const ICMP_TYPES: [(u8, &str); 15] = [
+    (0, "echo-reply"),
+    (3, "destination-unreachable"),
+    (4, "source-quench"),
+    (5, "redirect"),
+    (8, "echo-request"),
+    (9, "router-advertisement"),
+    (10, "router-solicitation"),
+    (11, "time-exceeded"),
+    (12, "parameter-problem"),
+    (13, "timestamp-request"),
+    (14, "timestamp-reply"),
+    (15, "info-request"),
+    (16, "info-reply"),
+    (17, "address-mask-request"),
+    (18, "address-mask-reply"),
+];
+
+/// ICMPv6 message types by the names nft lists them with.
+pub const ICMPV6_TYPES: [(u8, &str); 18] = [
+    (1, "destination-unreachable"),
+    (2, "packet-too-big"),
+    (3, "time-exceeded"),
+    (4, "parameter-problem"),
+    (128, "echo-request"),
+    (129, "echo-reply"),
+    (130, "mld-listener-query"),
+    (131, "mld-listener-report"),
+    (132, "mld-listener-done"),
+    (133, "nd-router-solicit"),
+    (134, "nd-router-advert"),
+    (135, "nd-neighbor-solicit"),
+    (136, "nd-neighbor-advert"),
+    (137, "nd-redirect"),
+    (138, "router-renumbering"),
+    (141, "ind-neighbor-solicit"),
+    (142, "ind-neighbor-advert"),
+    (143, "mld2-listener-report"),
+];
+
+/// ip6tables names of the ICMPv6 types nft calls differently.
+const ICMPV6_ALIASES: [(u8, &str); 8] = [
+    (132, "mld-listener-reduction"),
+    (133, "router-solicitation"),
+    (134, "router-advertisement"),
+    (135, "neighbour-solicitation"),
+    (135, "neighbor-solicitation"),
+    (136, "neighbour-advertisement"),
+    (136, "neighbor-advertisement"),
+    (137, "redirect"),
+];
+
+/// An ICMP or ICMPv6 message type, optionally narrowed to one code.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Icmp {
+    /// [`Family::V4`] for ICMP, [`Family::V6`] for ICMPv6.
+    pub family: Family,
+    pub kind: u8,
+    pub code: Option<u8>,
+}
 
-        // Name (if present)
-        if !self.name.is_empty() {
-            parts.push(format!("name \"{}\"", self.name));
+impl Icmp {
+    pub fn new(family: Family, kind: u8, code: Option<u8>) -> Self {
+        Self { family, kind, code }
+    }
+    /// Protocol number the messages are carried in, 1 or 58.
+    pub fn protocol(&self) -> u8 {
+        match self.family {
+            Family::V6 => 58,
+            _ => 1,
         }
+    }
+    /// The nft name of the type, `None` for types without one.
+    pub fn name(&self) -> Option<&'static str> {
+        let types: &[(u8, &str)] = match self.family {
+            Family::V6 => &ICMPV6_TYPES,
+            _ => &ICMP_TYPES,
+        };
+        types
+            .iter()
+            .find(|(kind, _)| *kind == self.kind)
+            .map(|(_, name)| *name)
+    }
+    /// Parses `echo-request`, `8` or `destination-unreachable/3` as a type of `family`,
+    /// accepting the ip6tables names of ICMPv6 types too.
+    pub fn from_name(family: Family, s: &str) -> Option<Self> {
+        let (name, code) = match s.trim().split_once('/') {
+            Some((name, code)) => (name, Some(code.parse().ok()?)),
+            None => (s.trim(), None),
+        };
+        let types: &[(u8, &str)] = match family {
+            Family::V6 => &ICMPV6_TYPES,
+            _ => &ICMP_TYPES,
+        };
+        let aliases: &[(u8, &str)] = match family {
+            Family::V6 => &ICMPV6_ALIASES,
+            _ => &[],
+        };
+        let kind = match name.parse() {
+            Ok(kind) => kind,
+            Err(_) => {
+                types
+                    .iter()
+                    .chain(aliases)
+                    .find(|(_, n)| n.eq_ignore_ascii_case(name))?
+                    .0
+            }
+        };
+        let family = match family {
+            Family::V6 => Family::V6,
+            _ => Family::V4,
+        };
+        Some(Self { family, kind, code })
+    }
+}
 
-        // Comment
-        if !self.comment.is_empty() {
-            parts.push(format!("comment \"{}\"", self.comment));
+/// The type name, or number when it has none, followed by `/code` when narrowed to one.
+impl Display for Icmp {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{}", self.kind)?,
+        }
+        match self.code {
+            Some(code) => write!(f, "/{}", code),
+            None => Ok(()),
         }
+    }
+}
 
-        parts.join(" ")
+#[derive(Debug, Clone)]
+pub struct Rule {
+    ips: (Vec<Ip>, Vec<Ip>),
+    sets: (Vec<String>, Vec<String>),
+    /// Input and output interface names, `*` at the end matching any suffix.
+    interfaces: (Vec<String>, Vec<String>),
+    conntrack: Conntrack,
+    icmp: Vec<Icmp>,
+    ports: (PortSet, PortSet),
+    protocols: Vec<Proto>,
+    family: Family,
+    direction: Direction,
+    action: Action,
+    name: String,
+    comment: String,
+}
+
+impl Rule {
+    pub fn new(
+        ips: (Vec<Ip>, Vec<Ip>),
+        ports: (Vec<Port>, Vec<Port>),
+        protocols: Vec<Proto>,
+        family: Family,
+        direction: Direction,
+        action: Action,
+        name: String,
+        comment: String,
+    ) -> Self {
+        Self {
+            ips,
+            sets: (Vec::new(), Vec::new()),
+            interfaces: (Vec::new(), Vec::new()),
+            conntrack: Conntrack::new(),
+            icmp: Vec::new(),
+            ports: (PortSet::from(ports.0), PortSet::from(ports.1)),
+            protocols,
+            family,
+            direction,
+            action,
+            name,
+            comment,
+        }
     }
-    /// Parse a rule string in nftables style into a Rule struct.
-    pub fn from_str(s: &str) -> std::result::Result<Self, ParseIntError> {
-        let mut rule = Self::default();
-        let mut tokens = s.trim().split_whitespace().peekable();
 
-        while let Some(token) = tokens.next() {
-            let token_lc = token.to_lowercase();
-            match token_lc.as_str() {
-                "ip" => rule.family = Family::Any,
-                "ip4" => rule.family = Family::V4,
-                "ip6" => rule.family = Family::V6,
-                "input" => rule.direction = Direction::In,
-                "output" => rule.direction = Direction::Out,
-                "forward" => rule.direction = Direction::Fwd,
-                "saddr" | "daddr" => {
-                    let is_src = token_lc == "saddr";
-                    let ip_set = if is_src {
-                        &mut rule.ips.0
-                    } else {
-                        &mut rule.ips.1
-                    };
-                    if let Some(next) = tokens.peek() {
-                        if next.starts_with('{') {
-                            let mut block = String::new();
-                            while let Some(ip_token) = tokens.next() {
-                                block.push_str(ip_token);
-                                if ip_token.ends_with('}') {
-                                    break;
-                                }
-                                block.push(' ');
-                            }
-                            let ips_trim =
-                                block.trim().trim_start_matches('{').trim_end_matches('}');
-                            for ip_str in
-                                ips_trim.split(',').map(str::trim).filter(|s| !s.is_empty())
-                            {
-                                ip_set.push(Ip::from_str(ip_str)?);
-                            }
-                        } else if let Some(ip_token) = tokens.next() {
-                            let ip = ip_token.trim_end_matches('}').trim();
-                            if !ip.is_empty() {
-                                ip_set.push(Ip::from_str(ip)?);
-                            }
-                        }
-                    }
-                }
-                "protocol" | "proto" => {
-                    if let Some(next) = tokens.peek() {
-                        if next.starts_with('{') {
-                            let mut block = String::new();
-                            while let Some(proto_token) = tokens.next() {
-                                block.push_str(proto_token);
-                                if proto_token.ends_with('}') {
-                                    break;
-                                }
-                                block.push(' ');
-                            }
-                            let protos_trim =
-                                block.trim().trim_start_matches('{').trim_end_matches('}');
-                            for proto in protos_trim
-                                .split(',')
-                                .map(str::trim)
-                                .filter(|s| !s.is_empty())
-                            {
-                                rule.protocols.push(Proto::from_str(proto)?);
-                            }
-                        } else if let Some(proto_token) = tokens.next() {
-                            let proto = proto_token.trim_end_matches('}').trim();
-                            if !proto.is_empty() {
-                                rule.protocols.push(Proto::from_str(proto)?);
-                            }
-                        }
-                    }
-                }
-                "tcp" | "udp" | "sctp" | "icmp" | "icmpv6" | "igmp" => {
-                    if rule.protocols.iter().all(|p| p.get_name() != token_lc) {
-                        rule.protocols.push(Proto::from_str(&token_lc)?);
-                    }
-                    while let Some(next) = tokens.peek() {
-                        match next.as_ref() {
-                            "sport" | "dport" => {
-                                let is_src = *next == "sport";
-                                tokens.next();
-                                if let Some(val_token) = tokens.peek() {
-                                    if val_token.starts_with('{') {
-                                        let mut block = String::new();
-                                        while let Some(port_token) = tokens.next() {
-                                            block.push_str(port_token);
-                                            if port_token.ends_with('}') {
-                                                break;
-                                            }
-                                            block.push(' ');
-                                        }
-                                        let ports_trim = block
-                                            .trim()
-                                            .trim_start_matches('{')
-                                            .trim_end_matches('}');
-                                        for p in ports_trim
-                                            .split(',')
-                                            .map(str::trim)
-                                            .filter(|s| !s.is_empty())
-                                        {
-                                            let port =
-                                                Port::from_str(&format!("{} {}", token_lc, p))?;
-                                            if is_src {
-                                                rule.ports.0.push(port);
-                                            } else {
-                                                rule.ports.1.push(port);
-                                            }
-                                        }
-                                    } else if let Some(p) = tokens.next() {
-                                        let port = p.trim();
-                                        if !port.is_empty() {
-                                            let port =
-                                                Port::from_str(&format!("{} {}", token_lc, port))?;
-                                            if is_src {
-                                                rule.ports.0.push(port);
-                                            } else {
-                                                rule.ports.1.push(port);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            _ => break,
-                        }
-                    }
-                }
-                "sport" | "dport" => {
-                    let is_src = token_lc == "sport";
-                    if let Some(next) = tokens.peek() {
-                        if next.starts_with('{') {
-                            let mut block = String::new();
-                            while let Some(port_token) = tokens.next() {
-                                block.push_str(port_token);
-                                if port_token.ends_with('}') {
-                                    break;
-                                }
-                                block.push(' ');
-                            }
-                            let ports_trim =
-                                block.trim().trim_start_matches('{').trim_end_matches('}');
-                            for p in ports_trim
-                                .split(',')
-                                .map(str::trim)
-                                .filter(|s| !s.is_empty())
-                            {
-                                let port = Port::from_str(p)?;
-                                if is_src {
-                                    rule.ports.0.push(port);
-                                } else {
-                                    rule.ports.1.push(port);
-                                }
-                            }
-                        } else if let Some(p) = tokens.next() {
-                            let port = Port::from_str(p.trim())?;
-                            if is_src {
-                                rule.ports.0.push(port);
-                            } else {
-                                rule.ports.1.push(port);
-                            }
-                        }
-                    }
-                }
-                "accept" | "drop" | "reject" | "skip" | "return" => {
-                    rule.action = Action::from_str(&token_lc).unwrap_or(Action::Drop);
-                }
-                "limit" | "jump" | "log" | "mark" | "meta" => {
-                    if let Some(arg) = tokens.next() {
-                        rule.action = Action::from_str(&format!("{} {}", token_lc, arg))
-                            .unwrap_or(Action::Drop);
-                    }
-                }
-                "comment" => {
-                    if let Some(c) = tokens.next() {
-                        if c.starts_with('"') {
-                            let mut comment_str = c.trim_start_matches('"').to_string();
-                            if !c.ends_with('"') {
-                                while let Some(next_c) = tokens.next() {
-                                    comment_str.push(' ');
-                                    comment_str.push_str(next_c);
-                                    if next_c.ends_with('"') {
-                                        break;
-                                    }
-                                }
-                            }
-                            rule.comment = comment_str.trim_end_matches('"').trim().to_string();
-                        } else {
-                            rule.comment = c.to_string();
-                        }
-                    }
-                }
-                "name" => {
-                    if let Some(n) = tokens.next() {
-                        if n.starts_with('"') {
-                            let mut name_str = n.trim_start_matches('"').to_string();
-                            if !n.ends_with('"') {
-                                while let Some(next_n) = tokens.next() {
-                                    name_str.push(' ');
-                                    name_str.push_str(next_n);
-                                    if next_n.ends_with('"') {
-                                        break;
-                                    }
-                                }
-                            }
-                            rule.name = name_str.trim_end_matches('"').trim().to_string();
-                        } else {
-                            rule.name = n.to_string();
-                        }
-                    }
-                }
-                _ => {}
-            }
+    pub fn clone(&self) -> Self {
+        Self {
+            ips: (self.ips.0.clone(), self.ips.1.clone()),
+            sets: (self.sets.0.clone(), self.sets.1.clone()),
+            interfaces: (self.interfaces.0.clone(), self.interfaces.1.clone()),
+            conntrack: self.conntrack.clone(),
+            icmp: self.icmp.clone(),
+            ports: (self.ports.0.clone(), self.ports.1.clone()),
+            protocols: self.protocols.clone(),
+            family: self.family,
+            direction: self.direction,
+            action: self.action.clone(),
+            name: self.name.clone(),
+            comment: self.comment.clone(),
         }
+    }
+
+    pub fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+
+    pub fn cmp(&self, other: &Self) -> Ordering {
+        self.ips
+            .0
+            .cmp(&other.ips.0)
+            .then_with(|| self.ips.1.cmp(&other.ips.1))
+            .then_with(|| self.sets.cmp(&other.sets))
+            .then_with(|| self.interfaces.cmp(&other.interfaces))
+            .then_with(|| self.conntrack.cmp(&other.conntrack))
+            .then_with(|| self.icmp.cmp(&other.icmp))
+            .then_with(|| self.ports.0.cmp(&other.ports.0))
+            .then_with(|| self.ports.1.cmp(&other.ports.1))
+            .then_with(|| self.protocols.cmp(&other.protocols))
+            .then_with(|| self.family.cmp(&other.family))
+            .then_with(|| self.direction.cmp(&other.direction))
+            .then_with(|| self.action.cmp(&other.action))
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.comment.cmp(&other.comment))
+    }
+
+    pub fn eq(&self, other: &Self) -> bool {
+        self.ips.0 == other.ips.0
+            && self.ips.1 == other.ips.1
+            && self.sets == other.sets
+            && self.interfaces == other.interfaces
+            && self.conntrack == other.conntrack
+            && self.icmp == other.icmp
+            && self.ports.0 == other.ports.0
+            && self.ports.1 == other.ports.1
+            && self.protocols == other.protocols
+            && self.family == other.family
+            && self.direction == other.direction
+            && self.action == other.action
+            && self.name == other.name
+            && self.comment == other.comment
+    }
+
+    pub fn ne(&self, other: &Self) -> bool {
+        !self.eq(other)
+    }
+
+    pub fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{self}")
+    }
 
-        Ok(rule)
+    pub fn src_ips(&self) -> &[Ip] {
+        &self.ips.0
+    }
+    pub fn dst_ips(&self) -> &[Ip] {
+        &self.ips.1
+    }
+    /// Names of the sets the source address is looked up in, `saddr @name`.
+    pub fn src_sets(&self) -> &[String] {
+        &self.sets.0
+    }
+    /// Names of the sets the destination address is looked up in, `daddr @name`.
+    pub fn dst_sets(&self) -> &[String] {
+        &self.sets.1
+    }
+    pub fn set_sets(&mut self, src: Vec<String>, dst: Vec<String>) {
+        self.sets = (src, dst);
+    }
+    /// Interfaces the packet arrives on, `iifname`, any of them matching.
+    pub fn in_interfaces(&self) -> &[String] {
+        &self.interfaces.0
+    }
+    /// Interfaces the packet leaves on, `oifname`, any of them matching.
+    pub fn out_interfaces(&self) -> &[String] {
+        &self.interfaces.1
+    }
+    pub fn set_interfaces(&mut self, input: Vec<String>, output: Vec<String>) {
+        self.interfaces = (input, output);
+    }
+    pub fn conntrack(&self) -> &Conntrack {
+        &self.conntrack
+    }
+    pub fn set_conntrack(&mut self, conntrack: Conntrack) {
+        self.conntrack = conntrack;
+    }
+    /// ICMP and ICMPv6 types the rule matches, any of them matching.
+    pub fn icmp(&self) -> &[Icmp] {
+        &self.icmp
+    }
+    /// Refuses types of a family the rule is restricted away from, e.g. ICMP in an `ip6` rule.
+    pub fn set_icmp(&mut self, icmp: Vec<Icmp>) -> std::result::Result<(), meta::Error> {
+        if let Some(wrong) = self.wrong_icmp(&icmp) {
+            return Err(meta::Error::new(wrong, 2));
+        }
+        self.icmp = icmp;
+        Ok(())
+    }
+    /// Describes the first type in `icmp` a packet of the rule's family never carries.
+    fn wrong_icmp(&self, icmp: &[Icmp]) -> Option<String> {
+        let family = self.packet_family();
+        let restricted = family.is_v4() != family.is_v6();
+        let wrong = icmp.iter().find(|i| restricted && i.family != family)?;
+        Some(format!(
+            "{} type {} in a {} rule",
+            nft::icmp_keyword(wrong.family),
+            wrong,
+            family
+        ))
+    }
+    /// The family of the packets the rule can match: its own when restricted to one, else the
+    /// family of its addresses and sets when they are all of one.
+    fn packet_family(&self) -> Family {
+        if self.family.is_v4() != self.family.is_v6() {
+            return self.family;
+        }
+        let mut families = self
+            .ips
+            .0
+            .iter()
+            .chain(&self.ips.1)
+            .filter(|ip| matches!(ip, Ip::V4(_) | Ip::V6(_)))
+            .map(Ip::family)
+            .chain(
+                self.sets
+                    .0
+                    .iter()
+                    .chain(&self.sets.1)
+                    .map(|s| self.set_family(s)),
+            );
+        match families.next() {
+            Some(first) if families.all(|f| f == first) => first,
+            _ => self.family,
+        }
+    }
+    /// Family of the addresses in the set `name`: the rule's family when it is restricted to
+    /// one, otherwise the family the name was generated for.
+    pub fn set_family(&self, name: &str) -> Family {
+        if self.family.is_v4() != self.family.is_v6() {
+            self.family
+        } else {
+            ipset::family_of(name)
+        }
+    }
+    pub fn src_ports(&self) -> &PortSet {
+        &self.ports.0
+    }
+    pub fn dst_ports(&self) -> &PortSet {
+        &self.ports.1
+    }
+    pub fn set_ports(&mut self, src: PortSet, dst: PortSet) {
+        self.ports = (src, dst);
+    }
+    pub fn protocols(&self) -> &[Proto] {
+        &self.protocols
+    }
+    pub fn family(&self) -> &Family {
+        &self.family
+    }
+    pub fn direction(&self) -> &Direction {
+        &self.direction
+    }
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn comment(&self) -> &str {
+        &self.comment
     }
 
-    pub fn append(&mut self, other: &mut Self) {
+    /// Renders the rule as an nft statement, see [`nft::rule`]. A rule nft needs several
+    /// statements for, one per family or port protocol, renders them separated by `; `.
+    pub fn to_string(&self) -> String {
+        nft::rule(self, &[]).join("; ")
+    }
+    /// Names of the protocols the rule matches, numbers for those without one.
+    fn protocol_names(&self) -> Vec<String> {
+        self.protocols
+            .iter()
+            .map(|p| match p.get_name().as_str() {
+                "unknown" => p.get_number().to_string(),
+                name => name.to_string(),
+            })
+            .collect()
+    }
+
+    /// Reports ports of a protocol the rule's `protocols` leave out, e.g. a udp port in a rule
+    /// matching only tcp, and ports in a rule matching only protocols without ports.
+    pub fn validate(&self) -> Vec<meta::Error> {
+        let protos = self.protocol_names();
+        let mut problems = Vec::new();
+        if protos.is_empty() {
+            return problems;
+        }
+        let mut reported: Vec<u8> = Vec::new();
+        for range in self.ports.0.iter().chain(&self.ports.1) {
+            if reported.contains(&range.proto) {
+                continue;
+            }
+            let carried = match nft::PORT_PROTOCOLS.iter().find(|(n, _)| *n == range.proto) {
+                Some((number, name)) => {
+                    protos.iter().any(|p| p == name || *p == number.to_string())
+                }
+                None => protos
+                    .iter()
+                    .any(|p| nft::PORT_PROTOCOLS.iter().any(|(_, name)| name == p)),
+            };
+            if !carried {
+                let ports = match nft::PORT_PROTOCOLS.iter().find(|(n, _)| *n == range.proto) {
+                    Some((_, name)) => format!("{} ports", name),
+                    None => "ports".to_string(),
+                };
+                problems.push(meta::Error::new(
+                    format!(
+                        "{} in a rule matching only protocol {}",
+                        ports,
+                        protos.join(", ")
+                    ),
+                    2,
+                ));
+                reported.push(range.proto);
+            }
+        }
+        problems
+    }
+
+    /// Parses a single nft rule statement, see [`nft::parse_rule`]. Jumps name their target
+    /// `chain_<number>`.
+    pub fn from_str(s: &str) -> std::result::Result<Self, meta::Error> {
+        nft::parse_rule(s, &[])
+    }
+
+    /// Merges the addresses, sets, ports and protocols of `other` into this rule. Rules whose
+    /// other matches differ are refused: no single rule matches the packets of both.
+    pub fn append(&mut self, other: &mut Self) -> std::result::Result<(), meta::Error> {
+        let differs = [
+            ("interface", self.interfaces != other.interfaces),
+            ("ICMP type", self.icmp != other.icmp),
+            ("ct", self.conntrack != other.conntrack),
+        ];
+        if let Some((what, _)) = differs.iter().find(|(_, differs)| *differs) {
+            return Err(meta::Error::new(
+                format!("rules with different {} matches cannot be merged", what),
+                2,
+            ));
+        }
         self.ips.0.append(&mut other.ips.0);
         self.ips.1.append(&mut other.ips.1);
+        self.sets.0.append(&mut other.sets.0);
+        self.sets.1.append(&mut other.sets.1);
         self.ports.0.append(&mut other.ports.0);
         self.ports.1.append(&mut other.ports.1);
         self.protocols.append(&mut other.protocols);
+        Ok(())
     }
 
     pub const ACCEPT: &'static str = "ACCEPT";
@@ -3392,7 +4530,11 @@ impl Default for Rule {
     fn default() -> Self {
         Self {
             ips: (Vec::new(), Vec::new()),
-            ports: (Vec::new(), Vec::new()),
+            sets: (Vec::new(), Vec::new()),
+            interfaces: (Vec::new(), Vec::new()),
+            conntrack: Conntrack::new(),
+            icmp: Vec::new(),
+            ports: (PortSet::new(), PortSet::new()),
             protocols: Vec::new(),
             action: Action::None,
             family: Family::None,
@@ -3410,12 +4552,14 @@ impl Display for Rule {
 }
 
 impl Eq for Rule {}
-impl From<Rule>
+/// The tuple has no place for sets, interfaces, ct matches or ICMP types, so rules using them
+/// are refused.
+impl TryFrom<Rule>
     for (
         Vec<Ip>,
         Vec<Ip>,
-        Vec<Port>,
-        Vec<Port>,
+        PortSet,
+        PortSet,
         Vec<Proto>,
         Family,
         Direction,
@@ -3424,8 +4568,24 @@ impl From<Rule>
         String,
     )
 {
-    fn from(rule: Rule) -> Self {
-        (
+    type Error = meta::Error;
+    fn try_from(rule: Rule) -> std::result::Result<Self, Self::Error> {
+        let unsupported = [
+            ("sets", !rule.sets.0.is_empty() || !rule.sets.1.is_empty()),
+            (
+                "interfaces",
+                !rule.interfaces.0.is_empty() || !rule.interfaces.1.is_empty(),
+            ),
+            ("ct", rule.conntrack != Conntrack::new()),
+            ("ICMP types", !rule.icmp.is_empty()),
+        ];
+        if let Some((what, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(meta::Error::new(
+                format!("a rule matching {} has no tuple form", what),
+                2,
+            ));
+        }
+        Ok((
             rule.ips.0,
             rule.ips.1,
             rule.ports.0,
@@ -3436,15 +4596,15 @@ impl From<Rule>
             rule.action,
             rule.name,
             rule.comment,
-        )
+        ))
     }
 }
 impl
     From<(
         Vec<Ip>,
         Vec<Ip>,
-        Vec<Port>,
-        Vec<Port>,
+        PortSet,
+        PortSet,
         Vec<Proto>,
         Family,
         Direction,
@@ -3457,8 +4617,8 @@ impl
         value: (
             Vec<Ip>,
             Vec<Ip>,
-            Vec<Port>,
-            Vec<Port>,
+            PortSet,
+            PortSet,
             Vec<Proto>,
             Family,
             Direction,
@@ -3469,6 +4629,10 @@ impl
     ) -> Self {
         Self {
             ips: (value.0, value.1),
+            sets: (Vec::new(), Vec::new()),
+            interfaces: (Vec::new(), Vec::new()),
+            conntrack: Conntrack::new(),
+            icmp: Vec::new(),
             ports: (value.2, value.3),
             protocols: value.4,
             family: value.5,
@@ -3480,7 +4644,7 @@ impl
     }
 }
 impl FromStr for Rule {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -3489,6 +4653,10 @@ impl Hash for Rule {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.ips.0.hash(state);
         self.ips.1.hash(state);
+        self.sets.hash(state);
+        self.interfaces.hash(state);
+        self.conntrack.hash(state);
+        self.icmp.hash(state);
         self.ports.0.hash(state);
         self.ports.1.hash(state);
         self.protocols.hash(state);
@@ -3505,6 +4673,10 @@ impl Ord for Rule {
             .0
             .cmp(&other.ips.0)
             .then_with(|| self.ips.1.cmp(&other.ips.1))
+            .then_with(|| self.sets.cmp(&other.sets))
+            .then_with(|| self.interfaces.cmp(&other.interfaces))
+            .then_with(|| self.conntrack.cmp(&other.conntrack))
+            .then_with(|| self.icmp.cmp(&other.icmp))
             .then_with(|| self.ports.0.cmp(&other.ports.0))
             .then_with(|| self.ports.1.cmp(&other.ports.1))
             .then_with(|| self.protocols.cmp(&other.protocols))
@@ -3519,6 +4691,10 @@ impl Ord for Rule {
 impl PartialEq for Rule {
     fn eq(&self, other: &Self) -> bool {
         self.ips == other.ips
+            && self.sets == other.sets
+            && self.interfaces == other.interfaces
+            && self.conntrack == other.conntrack
+            && self.icmp == other.icmp
             && self.ports == other.ports
             && self.protocols == other.protocols
             && self.family == other.family
@@ -3534,3 +4710,308 @@ impl PartialOrd for Rule {
         Some(self.cmp(other))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tuple form of a rule.
+    type Tuple = (
+        Vec<Ip>,
+        Vec<Ip>,
+        PortSet,
+        PortSet,
+        Vec<Proto>,
+        Family,
+        Direction,
+        Action,
+        String,
+        String,
+    );
+
+    #[test]
+    fn ip_parse_errors_are_located() {
+        let e = "10.0.0.1/40".parse::<Ip>().unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (10, "40"));
+        let e = "2001:db8::g".parse::<Ip>().unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (1, "2001:db8::g"));
+        assert!("10.1".parse::<Ip>().is_err());
+        assert_eq!(
+            "::ffff:10.0.0.1".parse::<Ip>().unwrap().to_string(),
+            "::ffff:10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn unknown_words_are_errors() {
+        assert_eq!("Forward".parse::<Direction>().unwrap(), Direction::Fwd);
+        let e = "sideways".parse::<Direction>().unwrap_err();
+        assert_eq!(e.token, "sideways");
+        assert_eq!(" ip6".parse::<Family>().unwrap(), Family::V6);
+        let e = " ip5".parse::<Family>().unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (2, "ip5"));
+    }
+
+    #[test]
+    fn actions_round_trip_and_report_bad_arguments() {
+        for action in [
+            Action::Limit(10),
+            Action::Jump(3),
+            Action::Mark(7),
+            Action::Redirect(None),
+            Action::Redirect(Some(8080)),
+            Action::Quota(1024),
+            Action::TProxy(3128, 1),
+            Action::Hashlimit("10/second".to_string(), None),
+            Action::Hashlimit("10/second".to_string(), Some(5)),
+            Action::FlowOffload,
+        ] {
+            assert_eq!(action.to_string().parse::<Action>().unwrap(), action);
+        }
+        assert_eq!(
+            "tproxy to :3128".parse::<Action>().unwrap(),
+            Action::TProxy(3128, 0)
+        );
+        let e = "accept"
+            .parse::<Action>()
+            .map(|_| ())
+            .and("acept".parse::<Action>());
+        let e = e.unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (1, "acept"));
+        assert!(e.message().contains("accept, drop, reject"));
+        for (input, column, token) in [
+            ("flow", 5, ""),
+            ("flow control", 6, "control"),
+            ("redirect to :http", 13, ":http"),
+            ("tproxy with mark x", 18, "x"),
+            ("tproxy with mark 1", 19, ""),
+            ("hashlimit", 10, ""),
+            ("hashlimit 10/second many", 21, "many"),
+            ("mark", 5, ""),
+            ("mark -1", 6, "-1"),
+            ("quota lots", 7, "lots"),
+        ] {
+            let e = input.parse::<Action>().unwrap_err();
+            assert_eq!((e.column, e.token.as_str()), (column, token), "{}", input);
+        }
+    }
+
+    #[test]
+    fn table_numbers_chains_and_renders_as_a_script() {
+        let mut table = Table::new(Family::V4, "fw");
+        table.push_chain(
+            Chain::from_str("chain input { type filter hook input priority 0; policy drop; }")
+                .unwrap(),
+        );
+        table.push_chain(Chain::from_str("chain ssh { tcp dport 22 accept }").unwrap());
+        let numbers: Vec<u32> = table.chains().iter().map(|c| c.number).collect();
+        assert_eq!(numbers, [1, 2]);
+        assert_eq!(table.chain("ssh").unwrap().rules.len(), 1);
+        assert!(table.chain("output").is_none());
+        let parsed = nft::parse(&table.to_string()).unwrap();
+        assert_eq!(parsed.tables(), [table]);
+    }
+
+    #[test]
+    fn ruleset_finds_tables_and_reports_duplicates() {
+        let mut ruleset = Ruleset::new();
+        ruleset.push(Table::new(Family::V4, "fw"));
+        ruleset.push(Table::new(Family::V6, "fw"));
+        assert!(ruleset.validate().is_empty());
+        assert_eq!(
+            ruleset.table(Family::V6, "fw").unwrap().family(),
+            Family::V6
+        );
+        ruleset.push(Table::new(Family::V4, "fw"));
+        let problems: Vec<String> = ruleset
+            .validate()
+            .iter()
+            .map(|e| e.message().clone())
+            .collect();
+        assert_eq!(problems, ["table ip fw is declared more than once"]);
+    }
+
+    #[test]
+    fn base_chain_header_round_trips() {
+        let base =
+            Base::from_str("type nat hook prerouting priority dstnat - 10; policy drop;").unwrap();
+        assert_eq!(
+            (base.kind(), base.hook()),
+            (ChainType::Nat, Hook::Prerouting)
+        );
+        assert_eq!(base.priority().value(), -110);
+        assert_eq!(base.policy(), &Action::Drop);
+        assert_eq!(Base::from_str(&base.to_string()).unwrap(), base);
+        let base = Base::from_str("type filter hook ingress device \"eth0\" priority 0;").unwrap();
+        assert_eq!(
+            (base.device(), base.policy()),
+            (Some("eth0"), &Action::Accept)
+        );
+        assert_eq!(
+            base.to_string(),
+            "type filter hook ingress device \"eth0\" priority 0; policy accept;"
+        );
+    }
+
+    #[test]
+    fn chain_order_agrees_with_equality() {
+        let chain = |header: &str| {
+            Chain::from_str(&format!("chain c {{ {} tcp dport 22 accept }}", header)).unwrap()
+        };
+        let mut chains = vec![
+            chain("type filter hook input priority 0; policy drop;"),
+            chain("type filter hook input priority 0; policy accept;"),
+            chain("type filter hook output priority 0; policy drop;"),
+            chain("type filter hook input priority filter; policy drop;"),
+            chain("type filter hook input priority 0; policy drop;"),
+            chain(""),
+        ];
+        for a in &chains {
+            for b in &chains {
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b);
+            }
+        }
+        chains.sort();
+        chains.dedup();
+        assert_eq!(chains.len(), 5);
+        let accept = chain("").rules[0].clone();
+        let mut drop = accept.clone();
+        drop.action = Action::Drop;
+        assert_eq!(accept.cmp(&drop), Ordering::Less);
+    }
+
+    #[test]
+    fn base_chain_header_errors() {
+        let e = Base::from_str("type filter hook input priority 0; policy reject;").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (43, "reject"));
+        let e = Base::from_str("type filter priority 0;").unwrap_err();
+        assert_eq!(e.message(), "missing hook in 'type filter priority 0;'");
+    }
+
+    #[test]
+    fn interface_wildcards_match_by_prefix() {
+        assert!(interface_matches("eth*", "eth0"));
+        assert!(interface_matches("eth*", "eth"));
+        assert!(!interface_matches("eth*", "wg0"));
+        assert!(interface_matches("wg0", "wg0"));
+        assert!(!interface_matches("wg0", "wg01"));
+    }
+
+    #[test]
+    fn interfaces_tell_rules_apart() {
+        use std::collections::HashSet;
+        let wg = Rule::from_str("iifname \"wg0\" accept").unwrap();
+        let eth = Rule::from_str("iifname \"eth*\" accept").unwrap();
+        assert_ne!(wg, eth);
+        assert_ne!(wg.cmp(&eth), std::cmp::Ordering::Equal);
+        assert_eq!(HashSet::from([wg.clone(), eth, wg]).len(), 2);
+    }
+
+    #[test]
+    fn interfaces_a_hook_never_sets_are_reported() {
+        let table = nft::parse(
+            "table inet f {
+              chain input { type filter hook input priority 0; oifname \"eth0\" drop }
+              chain output { type filter hook output priority 0; iifname \"eth0\" drop }
+              chain forward { type filter hook forward priority 0;
+                iifname \"wg0\" oifname \"eth*\" accept }
+            }",
+        )
+        .unwrap();
+        let problems: Vec<String> = table.tables()[0]
+            .validate()
+            .iter()
+            .map(|e| e.message.clone())
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "table inet f: chain input matches oifname on the input hook where it is never set",
+                "table inet f: chain output matches iifname on the output hook where it is never set",
+            ]
+        );
+    }
+
+    #[test]
+    fn ports_of_a_protocol_the_rule_leaves_out_are_reported() {
+        let mut rule = Rule::from_str("meta l4proto tcp accept").unwrap();
+        rule.ports.1.push(Port(53, 17, "udp".into()));
+        let problems: Vec<String> = rule.validate().iter().map(|e| e.message.clone()).collect();
+        assert_eq!(problems, ["udp ports in a rule matching only protocol tcp"]);
+        let mut rule = Rule::from_str("meta l4proto icmp accept").unwrap();
+        rule.ports.1.push(Port(53, 254, String::new()));
+        let problems: Vec<String> = rule.validate().iter().map(|e| e.message.clone()).collect();
+        assert_eq!(problems, ["ports in a rule matching only protocol icmp"]);
+        for text in [
+            "tcp dport 22 accept",
+            "meta l4proto { tcp, udp } th dport 53 accept",
+            "udp dport 53 accept",
+        ] {
+            assert!(
+                Rule::from_str(text).unwrap().validate().is_empty(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn merging_rules_keeps_their_sets_and_ct_matches() {
+        let mut rule = Rule::from_str("ip saddr 10.0.0.1 ct state established accept").unwrap();
+        let mut other = Rule::from_str("ip saddr 10.0.0.2 ct state established accept").unwrap();
+        other.set_sets(vec!["trusted".to_string()], Vec::new());
+        rule.append(&mut other).unwrap();
+        assert_eq!(rule.ips.0.len(), 2);
+        assert_eq!(rule.src_sets(), ["trusted"]);
+        assert_eq!(rule.conntrack.state(), [CtState::Established]);
+        let mut new = Rule::from_str("ip saddr 10.0.0.3 ct state new accept").unwrap();
+        let e = rule.append(&mut new).unwrap_err();
+        assert_eq!(
+            e.message(),
+            "rules with different ct matches cannot be merged"
+        );
+        assert_eq!(rule.ips.0.len(), 2);
+        let e = Tuple::try_from(rule).unwrap_err();
+        assert_eq!(e.message(), "a rule matching sets has no tuple form");
+        let e = Tuple::try_from(new).unwrap_err();
+        assert_eq!(e.message(), "a rule matching ct has no tuple form");
+        let tuple = Tuple::try_from(Rule::from_str("tcp dport 22 accept").unwrap()).unwrap();
+        assert_eq!(
+            Rule::from(tuple),
+            Rule::from_str("tcp dport 22 accept").unwrap()
+        );
+    }
+
+    #[test]
+    fn merging_rules_of_other_interfaces_is_refused() {
+        let mut any = Rule::from_str("tcp dport 22 accept").unwrap();
+        let mut wg = Rule::from_str("iifname \"wg0\" tcp dport 51820 accept").unwrap();
+        let e = any.append(&mut wg).unwrap_err();
+        assert_eq!(
+            e.message(),
+            "rules with different interface matches cannot be merged"
+        );
+        assert!(any.interfaces.0.is_empty());
+        let mut wg_ssh = Rule::from_str("iifname \"wg0\" tcp dport 22 accept").unwrap();
+        wg.append(&mut wg_ssh).unwrap();
+        assert_eq!(wg.ports.1.len(), 2);
+        let e = Tuple::try_from(wg).unwrap_err();
+        assert_eq!(e.message(), "a rule matching interfaces has no tuple form");
+    }
+
+    #[test]
+    fn merging_rules_of_other_icmp_types_is_refused() {
+        let mut ping = Rule::from_str("icmp type echo-request accept").unwrap();
+        let mut pong = Rule::from_str("icmp type echo-reply accept").unwrap();
+        let e = ping.append(&mut pong).unwrap_err();
+        assert_eq!(
+            e.message(),
+            "rules with different ICMP type matches cannot be merged"
+        );
+        let mut again = Rule::from_str("ip saddr 10.0.0.1 icmp type echo-request accept").unwrap();
+        ping.append(&mut again).unwrap();
+        assert_eq!(ping.icmp.len(), 1);
+        let e = Tuple::try_from(ping).unwrap_err();
+        assert_eq!(e.message(), "a rule matching ICMP types has no tuple form");
+    }
+}
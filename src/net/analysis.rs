@@ -0,0 +1,387 @@
+use super::{
+    Action, Chain, Direction, Family, Ip, Rule,
+    cidr::{self, Interval, Prefix},
+    interface_matches,
+};
+use std::fmt::{Display, Formatter};
+
+/// What is wrong with a rule given the rules around it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Kind {
+    /// An earlier terminal rule matches every packet the rule matches, so it never matches.
+    Shadowed,
+    /// A later rule with the same verdict matches every packet the rule matches and nothing in
+    /// between decides otherwise, so removing the rule changes nothing.
+    Redundant,
+    /// Two rules match some packets in common with different verdicts, which one applies
+    /// depends only on their order.
+    Conflict,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Shadowed => "shadowed",
+            Kind::Redundant => "redundant",
+            Kind::Conflict => "conflict",
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A problem with the rule at index `rule` of a chain, caused by the rule at index `by`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Finding {
+    pub kind: Kind,
+    pub rule: usize,
+    pub by: usize,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+/// Addresses one side of a rule matches, as intervals per family. `None` matches any address.
+type Addresses = Option<[Vec<Interval>; 2]>;
+
+/// Everything a rule matches on, with addresses turned into intervals so coverage is exact.
+struct Space<'a> {
+    rule: &'a Rule,
+    /// Whether IPv4 and IPv6 packets can match at all.
+    families: [bool; 2],
+    src: Addresses,
+    dst: Addresses,
+}
+
+const BITS: [u8; 2] = [32, 128];
+
+impl<'a> Space<'a> {
+    fn new(rule: &'a Rule) -> Self {
+        let mut families = match rule.family {
+            Family::V4 => [true, false],
+            Family::V6 => [false, true],
+            Family::Any | Family::None => [true, true],
+        };
+        let src = addresses(&rule.ips.0);
+        let dst = addresses(&rule.ips.1);
+        // `ip saddr` with only IPv4 networks never matches an IPv6 packet.
+        for side in [&src, &dst].into_iter().flatten() {
+            for (family, intervals) in side.iter().enumerate() {
+                families[family] &= !intervals.is_empty();
+            }
+        }
+        // ICMP types of one version only are never carried by the other family.
+        if !rule.icmp.is_empty() {
+            for (family, other) in [(Family::V4, 1), (Family::V6, 0)] {
+                if rule.icmp.iter().all(|i| i.family == family) {
+                    families[other] = false;
+                }
+            }
+        }
+        for set in rule.sets.0.iter().chain(&rule.sets.1) {
+            match rule.set_family(set) {
+                Family::V4 => families[1] = false,
+                Family::V6 => families[0] = false,
+                _ => {}
+            }
+        }
+        Self {
+            rule,
+            families,
+            src,
+            dst,
+        }
+    }
+
+    /// Whether every packet `other` matches is matched by `self` too.
+    fn covers(&self, other: &Space) -> bool {
+        let (a, b) = (self.rule, other.rule);
+        (a.direction == Direction::None || a.direction == b.direction)
+            && (0..2).all(|f| self.families[f] || !other.families[f])
+            && covers_addresses(&self.src, &other.src, &other.families)
+            && covers_addresses(&self.dst, &other.dst, &other.families)
+            && a.sets.0.iter().all(|s| b.sets.0.contains(s))
+            && a.sets.1.iter().all(|s| b.sets.1.contains(s))
+            && covers(&a.interfaces.0, &b.interfaces.0, |x, y| {
+                interface_matches(x, y)
+            })
+            && covers(&a.interfaces.1, &b.interfaces.1, |x, y| {
+                interface_matches(x, y)
+            })
+            && covers(&a.conntrack.state, &b.conntrack.state, |x, y| x == y)
+            && covers(&a.conntrack.status, &b.conntrack.status, |x, y| x == y)
+            && covers_one(&a.conntrack.zone, &b.conntrack.zone)
+            && covers_one(&a.conntrack.mark, &b.conntrack.mark)
+            && covers_one(&a.conntrack.helper, &b.conntrack.helper)
+            && covers(&a.protocols, &b.protocols, |x, y| x.0 == y.0)
+            && covers(&a.icmp, &b.icmp, |x, y| {
+                x.family == y.family && x.kind == y.kind && (x.code.is_none() || x.code == y.code)
+            })
+            && a.ports.0.covers(&b.ports.0)
+            && a.ports.1.covers(&b.ports.1)
+    }
+
+    /// Whether some packet can match both. Named sets are assumed to share elements.
+    fn overlaps(&self, other: &Space) -> bool {
+        let (a, b) = (self.rule, other.rule);
+        let families = [
+            self.families[0] && other.families[0],
+            self.families[1] && other.families[1],
+        ];
+        (a.direction == Direction::None
+            || b.direction == Direction::None
+            || a.direction == b.direction)
+            && families.contains(&true)
+            && overlaps_addresses(&self.src, &other.src, &families)
+            && overlaps_addresses(&self.dst, &other.dst, &families)
+            && overlaps(&a.interfaces.0, &b.interfaces.0, same_interface)
+            && overlaps(&a.interfaces.1, &b.interfaces.1, same_interface)
+            && overlaps(&a.conntrack.state, &b.conntrack.state, |x, y| x == y)
+            && overlaps(&a.conntrack.status, &b.conntrack.status, |x, y| x == y)
+            && overlaps_one(&a.conntrack.zone, &b.conntrack.zone)
+            && overlaps_one(&a.conntrack.mark, &b.conntrack.mark)
+            && overlaps_one(&a.conntrack.helper, &b.conntrack.helper)
+            && overlaps(&a.protocols, &b.protocols, |x, y| x.0 == y.0)
+            && overlaps(&a.icmp, &b.icmp, |x, y| {
+                x.family == y.family && x.kind == y.kind && overlaps_one(&x.code, &y.code)
+            })
+            && a.ports.0.overlaps(&b.ports.0)
+            && a.ports.1.overlaps(&b.ports.1)
+    }
+}
+
+/// The networks of one side as intervals, IPv4 first. An empty list matches any address.
+fn addresses(ips: &[Ip]) -> Addresses {
+    if ips.is_empty() {
+        return None;
+    }
+    let mut prefixes: [Vec<Prefix>; 2] = [Vec::new(), Vec::new()];
+    for (family, prefix) in ips.iter().filter_map(Ip::to_prefix) {
+        prefixes[(family == Family::V6) as usize].push(prefix);
+    }
+    Some([
+        cidr::intervals(&prefixes[0], BITS[0]),
+        cidr::intervals(&prefixes[1], BITS[1]),
+    ])
+}
+
+fn covers_addresses(outer: &Addresses, inner: &Addresses, families: &[bool; 2]) -> bool {
+    match (outer, inner) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(outer), Some(inner)) => (0..2)
+            .filter(|&f| families[f])
+            .all(|f| cidr::difference(&inner[f], &outer[f]).is_empty()),
+    }
+}
+
+fn overlaps_addresses(a: &Addresses, b: &Addresses, families: &[bool; 2]) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (0..2)
+            .filter(|&f| families[f])
+            .any(|f| !cidr::intersection(&a[f], &b[f]).is_empty()),
+        _ => true,
+    }
+}
+
+/// Whether some interface name matches both patterns, `eth*` and `eth0` or `e*` and `eth*`.
+fn same_interface(a: &String, b: &String) -> bool {
+    match (a.strip_suffix('*'), b.strip_suffix('*')) {
+        (Some(a), Some(b)) => a.starts_with(b) || b.starts_with(a),
+        (Some(prefix), None) => b.starts_with(prefix),
+        (None, Some(prefix)) => a.starts_with(prefix),
+        (None, None) => a == b,
+    }
+}
+
+/// Coverage of a match on any of `outer` over one on any of `inner`, empty matching anything.
+fn covers<T>(outer: &[T], inner: &[T], same: impl Fn(&T, &T) -> bool) -> bool {
+    outer.is_empty()
+        || (!inner.is_empty() && inner.iter().all(|i| outer.iter().any(|o| same(o, i))))
+}
+
+fn overlaps<T>(a: &[T], b: &[T], same: impl Fn(&T, &T) -> bool) -> bool {
+    a.is_empty() || b.is_empty() || a.iter().any(|x| b.iter().any(|y| same(x, y)))
+}
+
+/// Coverage of a match on a single value, `None` matching anything.
+fn covers_one<T: PartialEq>(outer: &Option<T>, inner: &Option<T>) -> bool {
+    outer.is_none() || outer == inner
+}
+
+fn overlaps_one<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+    a.is_none() || b.is_none() || a == b
+}
+
+/// Whether a rule with `action` in front of another can change the verdict of its packets.
+/// Logging and counting pass every packet on unchanged.
+fn decides(action: &Action) -> bool {
+    !matches!(action, Action::None | Action::Log(_) | Action::Counter)
+}
+
+/// `rule 3` or `rule 3 (ssh)`, counting from 1 as `nft list` users do.
+fn label(rules: &[Rule], index: usize) -> String {
+    match rules[index].name.as_str() {
+        "" => format!("rule {}", index + 1),
+        name => format!("rule {} ({})", index + 1, name),
+    }
+}
+
+/// Walks the rules of `chain` in order and reports shadowed and redundant rules and
+/// conflicting pairs, see [`Kind`]. Each rule is reported shadowed or redundant at most once.
+pub fn chain(chain: &Chain) -> Vec<Finding> {
+    let rules = &chain.rules;
+    let spaces: Vec<Space> = rules.iter().map(Space::new).collect();
+    let mut findings = Vec::new();
+    let mut shadowed = vec![false; rules.len()];
+    for (j, later) in spaces.iter().enumerate() {
+        let by = (0..j).find(|&i| rules[i].action.is_terminal() && spaces[i].covers(later));
+        if let Some(i) = by {
+            shadowed[j] = true;
+            findings.push(Finding {
+                kind: Kind::Shadowed,
+                rule: j,
+                by: i,
+                message: format!(
+                    "{} never matches, {} ({}) matches all of its packets first",
+                    label(rules, j),
+                    label(rules, i),
+                    rules[i].action
+                ),
+            });
+        }
+    }
+    for (i, earlier) in spaces.iter().enumerate() {
+        let action = &rules[i].action;
+        if shadowed[i] || !action.is_terminal() {
+            continue;
+        }
+        // Past a rule deciding other packets of this one, a later cover is not redundancy.
+        let redundant = (i + 1..rules.len())
+            .filter(|&j| !shadowed[j] && spaces[j].overlaps(earlier))
+            .find(|&j| match rules[j].action == *action {
+                true => spaces[j].covers(earlier),
+                false => decides(&rules[j].action),
+            })
+            .filter(|&j| rules[j].action == *action);
+        if let Some(j) = redundant {
+            findings.push(Finding {
+                kind: Kind::Redundant,
+                rule: i,
+                by: j,
+                message: format!(
+                    "{} is redundant, {} gives all of its packets the same verdict later",
+                    label(rules, i),
+                    label(rules, j)
+                ),
+            });
+        }
+        for (j, later) in spaces.iter().enumerate().skip(i + 1) {
+            let verdict = &rules[j].action;
+            if shadowed[j]
+                || !verdict.is_terminal()
+                || verdict == action
+                || later.covers(earlier)
+                || !later.overlaps(earlier)
+            {
+                continue;
+            }
+            findings.push(Finding {
+                kind: Kind::Conflict,
+                rule: j,
+                by: i,
+                message: format!(
+                    "{} ({}) and {} ({}) match some packets in common, the first one wins",
+                    label(rules, i),
+                    action,
+                    label(rules, j),
+                    verdict
+                ),
+            });
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::nft;
+
+    fn parse(rules: &str) -> Chain {
+        let ruleset = nft::parse(&format!("table inet t {{ chain c {{ {} }} }}", rules)).unwrap();
+        ruleset.tables()[0].chains()[0].clone()
+    }
+
+    fn kinds(rules: &str) -> Vec<(Kind, usize, usize)> {
+        let chain = nft::parse_chain(&format!("chain c {{ {} }}", rules), &[]).unwrap();
+        self::chain(&chain)
+            .iter()
+            .map(|f| (f.kind, f.rule, f.by))
+            .collect()
+    }
+
+    #[test]
+    fn ports_of_one_protocol_do_not_cover_ports_of_any() {
+        assert_eq!(kinds("tcp dport 22 drop; th dport 22 accept"), []);
+        assert_eq!(
+            kinds("tcp dport 22 drop; meta l4proto { tcp, udp } th dport 22 accept"),
+            [(Kind::Conflict, 1, 0)]
+        );
+        assert_eq!(
+            kinds("th dport 22 drop; tcp dport 22 accept"),
+            [(Kind::Shadowed, 1, 0)]
+        );
+    }
+
+    #[test]
+    fn wider_earlier_rule_shadows() {
+        let chain = parse(
+            "ip saddr 10.0.0.0/8 drop; ip saddr 10.1.0.0/16 tcp dport 22 accept comment \"[ssh]\"",
+        );
+        let findings = self::chain(&chain);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(
+            findings[0].to_string(),
+            "shadowed: rule 2 (ssh) never matches, rule 1 (drop) matches all of its packets first"
+        );
+    }
+
+    #[test]
+    fn narrower_rule_with_the_later_verdict_is_redundant() {
+        assert_eq!(
+            kinds("ip saddr 10.1.0.0/16 accept; ip saddr 10.0.0.0/8 accept"),
+            [(Kind::Redundant, 0, 1)]
+        );
+        // A rule deciding its packets otherwise in between keeps it.
+        assert_eq!(
+            kinds(
+                "ip saddr 10.1.0.0/16 accept; ip saddr 10.0.0.0/12 drop; ip saddr 10.0.0.0/8 accept"
+            ),
+            []
+        );
+        // Counting in between does not.
+        assert_eq!(
+            kinds("ip saddr 10.1.0.0/16 accept; counter; ip saddr 10.0.0.0/8 accept"),
+            [(Kind::Redundant, 0, 2)]
+        );
+    }
+
+    #[test]
+    fn partial_overlap_with_other_verdict_conflicts() {
+        assert_eq!(
+            kinds("tcp dport 20-30 accept; tcp dport 25-40 drop"),
+            [(Kind::Conflict, 1, 0)]
+        );
+        assert_eq!(kinds("tcp dport 20-30 accept; tcp dport 31-40 drop"), []);
+        assert_eq!(kinds("ip saddr 10.0.0.0/8 accept; ip6 saddr ::/0 drop"), []);
+    }
+}
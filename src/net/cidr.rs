@@ -0,0 +1,206 @@
+use std::fmt::{Display, Formatter};
+
+/// A prefix as `(network, length)` on an address space of up to 128 bits.
+pub type Prefix = (u128, u8);
+
+/// Outcome of aggregating a set of prefixes.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Report {
+    pub entries_in: usize,
+    pub entries_out: usize,
+    pub addresses: u128,
+}
+
+impl Report {
+    pub fn removed(&self) -> usize {
+        self.entries_in.saturating_sub(self.entries_out)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entries in, {} entries out ({} removed), {} addresses covered",
+            self.entries_in,
+            self.entries_out,
+            self.removed(),
+            self.addresses
+        )
+    }
+}
+
+/// Host part of a `len` bit prefix on a `bits` wide address space.
+pub fn host_mask(bits: u8, len: u8) -> u128 {
+    let host = bits.saturating_sub(len);
+    if host == 0 {
+        0
+    } else {
+        u128::MAX >> (128 - host as u32)
+    }
+}
+
+/// Clamps the length to the address width and clears the host bits.
+pub fn normalize((network, len): Prefix, bits: u8) -> Prefix {
+    let len = len.min(bits);
+    (network & !host_mask(bits, len), len)
+}
+
+/// First and last address of a prefix.
+pub fn bounds(prefix: Prefix, bits: u8) -> (u128, u128) {
+    let (network, len) = normalize(prefix, bits);
+    (network, network | host_mask(bits, len))
+}
+
+/// Number of addresses covered by non-overlapping prefixes, saturating at `u128::MAX`.
+pub fn addresses(prefixes: &[Prefix], bits: u8) -> u128 {
+    prefixes.iter().fold(0u128, |total, &(_, len)| {
+        total.saturating_add(host_mask(bits, len)).saturating_add(1)
+    })
+}
+
+/// Reduces `prefixes` to the minimal set of prefixes covering the same addresses.
+///
+/// The prefixes are sorted by network, prefixes covered by an earlier one are dropped, and
+/// siblings of equal length are merged into their parent. Merging runs on a stack, so a merged
+/// parent is immediately retried against its own sibling and cascades reach the fixpoint in a
+/// single pass over the sorted input.
+pub fn aggregate(prefixes: &[Prefix], bits: u8) -> Vec<Prefix> {
+    let mut sorted: Vec<Prefix> = prefixes.iter().map(|&p| normalize(p, bits)).collect();
+    sorted.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut merged: Vec<Prefix> = Vec::with_capacity(sorted.len());
+    let mut covered_to: Option<u128> = None;
+    for prefix in sorted {
+        let (first, last) = bounds(prefix, bits);
+        if covered_to.is_some_and(|end| first <= end) {
+            continue;
+        }
+        covered_to = Some(last);
+        merged.push(prefix);
+        while merged.len() > 1 {
+            let (a, a_len) = merged[merged.len() - 2];
+            let (b, b_len) = merged[merged.len() - 1];
+            if a_len != b_len || a_len == 0 {
+                break;
+            }
+            let bit = 1u128 << (bits - a_len);
+            if a & bit != 0 || b != a | bit {
+                break;
+            }
+            merged.truncate(merged.len() - 2);
+            merged.push((a, a_len - 1));
+        }
+    }
+    merged
+}
+
+/// An inclusive `(first, last)` address range.
+pub type Interval = (u128, u128);
+
+/// Converts prefixes to sorted, non-overlapping and non-adjacent intervals.
+pub fn intervals(prefixes: &[Prefix], bits: u8) -> Vec<Interval> {
+    merge(prefixes.iter().map(|&p| bounds(p, bits)).collect())
+}
+
+fn merge(mut all: Vec<Interval>) -> Vec<Interval> {
+    all.sort_unstable();
+    let mut merged: Vec<Interval> = Vec::with_capacity(all.len());
+    for (first, last) in all {
+        match merged.last_mut() {
+            Some((_, end)) if end.checked_add(1).is_none_or(|next| first <= next) => {
+                *end = (*end).max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+/// Splits an interval into the minimal list of prefixes covering exactly its addresses.
+pub fn decompose((mut first, last): Interval, bits: u8) -> Vec<Prefix> {
+    let mut prefixes = Vec::new();
+    loop {
+        // Largest block aligned on `first` that does not run past `last`.
+        let mut len = bits - (first.trailing_zeros().min(bits as u32) as u8);
+        while first | host_mask(bits, len) > last {
+            len += 1;
+        }
+        let end = first | host_mask(bits, len);
+        prefixes.push((first, len));
+        if end >= last {
+            return prefixes;
+        }
+        first = end + 1;
+    }
+}
+
+/// Converts sorted, non-overlapping intervals back to the minimal list of prefixes.
+pub fn prefixes(intervals: &[Interval], bits: u8) -> Vec<Prefix> {
+    intervals
+        .iter()
+        .flat_map(|&interval| decompose(interval, bits))
+        .collect()
+}
+
+/// Addresses in either `a` or `b`.
+pub fn union(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    merge(a.iter().chain(b).copied().collect())
+}
+
+/// Addresses in both `a` and `b`.
+pub fn intersection(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let first = a[i].0.max(b[j].0);
+        let last = a[i].1.min(b[j].1);
+        if first <= last {
+            out.push((first, last));
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Addresses in `a` but not in `b`.
+pub fn difference(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    let mut out = Vec::new();
+    let mut j = 0;
+    for &(first, last) in a {
+        let mut start = Some(first);
+        while j < b.len() && b[j].1 < first {
+            j += 1;
+        }
+        let mut k = j;
+        while let Some(from) = start {
+            match b.get(k) {
+                Some(&(cut_first, cut_last)) if cut_first <= last => {
+                    if cut_first > from {
+                        out.push((from, cut_first - 1));
+                    }
+                    start = cut_last.checked_add(1).filter(|&next| next <= last);
+                    k += 1;
+                }
+                _ => {
+                    out.push((from, last));
+                    start = None;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Addresses in exactly one of `a` and `b`.
+pub fn symmetric_difference(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    union(&difference(a, b), &difference(b, a))
+}
+
+/// Addresses of a `bits` wide address space not in `a`.
+pub fn complement(a: &[Interval], bits: u8) -> Vec<Interval> {
+    difference(&[(0, host_mask(bits, 0))], a)
+}
@@ -0,0 +1,905 @@
+use super::{
+    Action, Chain, Direction, Family, Hook, Icmp, Ip, NamedSet, PortSet, Rule,
+    cidr::{self, Interval},
+    interface_matches, nft,
+    simulate::{self, Packet},
+};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+};
+
+/// A difference between the rules of two versions of a chain. Positions count from 0, rules
+/// are given as nft statements.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    Added {
+        key: String,
+        at: usize,
+        rule: String,
+    },
+    Removed {
+        key: String,
+        at: usize,
+        rule: String,
+    },
+    /// The rule kept its key but moved relative to the rules around it.
+    Moved { key: String, from: usize, to: usize },
+    Modified {
+        key: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Change::Added { key, at, rule } => write!(f, "+ {} at {}: {}", key, at + 1, rule),
+            Change::Removed { key, at, rule } => {
+                write!(f, "- {} at {}: {}", key, at + 1, rule)
+            }
+            Change::Moved { key, from, to } => {
+                write!(f, "> {} moved from {} to {}", key, from + 1, to + 1)
+            }
+            Change::Modified { key, old, new } => {
+                write!(f, "~ {}: {} => {}", key, old, new)
+            }
+        }
+    }
+}
+
+/// Packets of one family whose verdict changed from `old` to `new`: from any address in `src`
+/// to any in `dst`, on `protocol` (`None` for the protocols no rule names) and destination
+/// ports `ports`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Shift {
+    pub family: Family,
+    pub src: Vec<Interval>,
+    pub dst: Vec<Interval>,
+    pub protocol: Option<u8>,
+    /// Interface patterns the packets arrive on and leave on, `None` for the names no rule
+    /// matches or where the hook knows no interface.
+    pub iif: Option<String>,
+    pub oif: Option<String>,
+    /// ICMP type of ICMP packets, its code `None` for the codes no rule names. `None` for the
+    /// types no rule names.
+    pub icmp: Option<Icmp>,
+    pub ports: (u16, u16),
+    pub old: Action,
+    pub new: Action,
+}
+
+impl Display for Shift {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let bits = bits(self.family);
+        let networks = |intervals: &[Interval]| -> String {
+            let any = network(self.family, (0, 0));
+            if intervals == [(0, cidr::host_mask(bits, 0))] {
+                return any;
+            }
+            let list = |intervals: &[Interval]| -> String {
+                let prefixes = cidr::prefixes(intervals, bits);
+                let nets: Vec<String> = prefixes.iter().map(|&p| network(self.family, p)).collect();
+                nets.join(",")
+            };
+            // Whatever is shorter of the networks and the networks left out.
+            let rest = cidr::complement(intervals, bits);
+            match cidr::prefixes(&rest, bits).len() < cidr::prefixes(intervals, bits).len() {
+                true => format!("{} except {}", any, list(&rest)),
+                false => list(intervals),
+            }
+        };
+        write!(f, "{}", networks(&self.src))?;
+        if self.dst != [(0, cidr::host_mask(bits, 0))] {
+            write!(f, " to {}", networks(&self.dst))?;
+        }
+        match self.protocol {
+            Some(protocol) => write!(f, " {}", protocol_name(protocol))?,
+            None => write!(f, " other protocols")?,
+        }
+        match (&self.icmp, self.protocol) {
+            (Some(icmp), _) => write!(f, " {}", icmp)?,
+            (None, Some(1 | 58)) => write!(f, " other types")?,
+            _ => {}
+        }
+        match self.ports {
+            (0, u16::MAX) => {}
+            (first, last) if first == last => write!(f, "/{}", first)?,
+            (first, last) => write!(f, "/{}-{}", first, last)?,
+        }
+        if let Some(iif) = &self.iif {
+            write!(f, " in {}", iif)?;
+        }
+        if let Some(oif) = &self.oif {
+            write!(f, " out {}", oif)?;
+        }
+        write!(f, " changed from {} to {}", self.old, self.new)
+    }
+}
+
+/// The differences between two versions of a chain.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ChainDiff {
+    pub name: String,
+    /// Old and new policy of a base chain whose policy changed.
+    pub policy: Option<(Action, Action)>,
+    pub changes: Vec<Change>,
+    pub shifts: Vec<Shift>,
+}
+
+impl ChainDiff {
+    pub fn is_empty(&self) -> bool {
+        self.policy.is_none() && self.changes.is_empty() && self.shifts.is_empty()
+    }
+}
+
+impl Display for ChainDiff {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        writeln!(f, "chain {}", self.name)?;
+        if let Some((old, new)) = &self.policy {
+            writeln!(f, "~ policy {} => {}", old, new)?;
+        }
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        for shift in &self.shifts {
+            writeln!(f, "! {}", shift)?;
+        }
+        Ok(())
+    }
+}
+
+/// The differences between two sets of chains, matched by name.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub chains: Vec<ChainDiff>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.chains.is_empty()
+    }
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for name in &self.removed {
+            writeln!(f, "- chain {}", name)?;
+        }
+        for name in &self.added {
+            writeln!(f, "+ chain {}", name)?;
+        }
+        for chain in &self.chains {
+            write!(f, "{}", chain)?;
+        }
+        Ok(())
+    }
+}
+
+/// One side of a comparison: a chain with the chains it jumps to and the sets it looks up.
+#[derive(Clone, Copy)]
+pub struct Side<'a> {
+    pub chain: &'a Chain,
+    pub chains: &'a [Chain],
+    pub sets: &'a [NamedSet],
+}
+
+impl<'a> Side<'a> {
+    pub fn new(chain: &'a Chain) -> Self {
+        Self {
+            chain,
+            chains: &[],
+            sets: &[],
+        }
+    }
+}
+
+fn text(rule: &Rule) -> String {
+    nft::rule(rule, &[]).join("; ")
+}
+
+/// What a rule matches, its text without the verdict.
+fn matches(rule: &Rule) -> String {
+    let mut matches = rule.clone();
+    matches.action = Action::None;
+    match text(&matches) {
+        text if text.is_empty() => "all packets".to_string(),
+        text => text,
+    }
+}
+
+/// Identity of a rule across versions: its name, else its comment, else what it matches, so a
+/// rule whose verdict changed is modified rather than replaced. Repeated keys get `#2`, `#3`
+/// and so on in order.
+fn keys(rules: &[Rule]) -> Vec<String> {
+    let mut seen: Vec<String> = Vec::with_capacity(rules.len());
+    let mut keys = Vec::with_capacity(rules.len());
+    for rule in rules {
+        let key = match (rule.name.as_str(), rule.comment.as_str()) {
+            ("", "") => matches(rule),
+            ("", comment) => comment.to_string(),
+            (name, _) => name.to_string(),
+        };
+        keys.push(match seen.iter().filter(|k| **k == key).count() {
+            0 => key.clone(),
+            n => format!("{} #{}", key, n + 1),
+        });
+        seen.push(key);
+    }
+    keys
+}
+
+/// Rule level changes, keyed by [`keys`]. Rules outside the longest run kept in order are moved.
+pub fn rules(old: &[Rule], new: &[Rule]) -> Vec<Change> {
+    let (old_keys, new_keys) = (keys(old), keys(new));
+    let position = |keys: &[String], key: &String| keys.iter().position(|k| k == key);
+    let mut changes = Vec::new();
+    for (at, key) in old_keys.iter().enumerate() {
+        if position(&new_keys, key).is_none() {
+            changes.push(Change::Removed {
+                key: key.clone(),
+                at,
+                rule: text(&old[at]),
+            });
+        }
+    }
+    // Old positions of the kept rules, in new order.
+    let kept: Vec<(usize, usize)> = new_keys
+        .iter()
+        .enumerate()
+        .filter_map(|(to, key)| position(&old_keys, key).map(|from| (from, to)))
+        .collect();
+    let in_order = longest_increasing(&kept.iter().map(|&(from, _)| from).collect::<Vec<_>>());
+    for (i, &(from, to)) in kept.iter().enumerate() {
+        let key = new_keys[to].clone();
+        if !in_order.contains(&i) {
+            changes.push(Change::Moved {
+                key: key.clone(),
+                from,
+                to,
+            });
+        }
+        if old[from] != new[to] {
+            changes.push(Change::Modified {
+                key,
+                old: text(&old[from]),
+                new: text(&new[to]),
+            });
+        }
+    }
+    for (at, key) in new_keys.iter().enumerate() {
+        if position(&old_keys, key).is_none() {
+            changes.push(Change::Added {
+                key: key.clone(),
+                at,
+                rule: text(&new[at]),
+            });
+        }
+    }
+    changes
+}
+
+/// Indexes into `values` of a longest strictly increasing subsequence.
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+    let mut length = vec![1; values.len()];
+    let mut previous = vec![None; values.len()];
+    for i in 0..values.len() {
+        for j in 0..i {
+            if values[j] < values[i] && length[j] + 1 > length[i] {
+                length[i] = length[j] + 1;
+                previous[i] = Some(j);
+            }
+        }
+    }
+    let mut out = Vec::new();
+    let mut at = (0..values.len()).max_by_key(|&i| (length[i], std::cmp::Reverse(i)));
+    while let Some(i) = at {
+        out.push(i);
+        at = previous[i];
+    }
+    out.reverse();
+    out
+}
+
+fn bits(family: Family) -> u8 {
+    match family {
+        Family::V6 => 128,
+        _ => 32,
+    }
+}
+
+fn network(family: Family, (network, len): cidr::Prefix) -> String {
+    match family {
+        Family::V6 => Ip::from((network, len)).to_string(),
+        _ => Ip::from((network as u32, len)).to_string(),
+    }
+}
+
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => "icmp".to_string(),
+        6 => "tcp".to_string(),
+        17 => "udp".to_string(),
+        58 => "icmpv6".to_string(),
+        132 => "sctp".to_string(),
+        number => number.to_string(),
+    }
+}
+
+/// Splits `0..=max` at every interval edge so each piece is matched by all of a rule or none.
+fn pieces(edges: impl IntoIterator<Item = Interval>, max: u128) -> Vec<Interval> {
+    let mut starts = vec![0u128];
+    for (first, last) in edges {
+        starts.push(first);
+        starts.extend(last.checked_add(1).filter(|&next| next <= max));
+    }
+    starts.sort_unstable();
+    starts.dedup();
+    let ends = starts.iter().skip(1).map(|&next| next - 1).chain([max]);
+    starts.iter().copied().zip(ends).collect()
+}
+
+/// Address pieces of one side of `rules`.
+fn address_pieces(rules: &[&Rule], family: Family, side: fn(&Rule) -> &[Ip]) -> Vec<Interval> {
+    let bits = bits(family);
+    let edges = rules
+        .iter()
+        .flat_map(|rule| side(rule).iter().filter_map(Ip::to_prefix))
+        .filter(|(f, _)| *f == family)
+        .map(|(_, prefix)| cidr::bounds(prefix, bits));
+    pieces(edges, cidr::host_mask(bits, 0))
+}
+
+/// Whether an address match of `ips` holds for `address` of `family`.
+fn holds(ips: &[Ip], family: Family, address: u128) -> bool {
+    ips.is_empty()
+        || ips.iter().filter_map(Ip::to_prefix).any(|(f, prefix)| {
+            let (first, last) = cidr::bounds(prefix, bits(family));
+            f == family && (first..=last).contains(&address)
+        })
+}
+
+/// Interface names to send probes on: one for every pattern of `rules`, standing for the names
+/// it matches, and one matching no pattern. `None` labels the names no rule names.
+fn interface_probes(
+    rules: &[&Rule],
+    side: fn(&Rule) -> &[String],
+) -> Vec<(Option<String>, String)> {
+    let mut patterns: Vec<&String> = rules.iter().flat_map(|r| side(r)).collect();
+    patterns.sort_unstable();
+    patterns.dedup();
+    let matched = |name: &str| {
+        patterns
+            .iter()
+            .filter(|p| interface_matches(p, name))
+            .count()
+    };
+    let mut probes: Vec<(Option<String>, String)> = Vec::new();
+    for pattern in &patterns {
+        // Of the names a wildcard matches, the one matched by the fewest other patterns.
+        let name = match pattern.strip_suffix('*') {
+            Some(prefix) => (0..64)
+                .map(|n| format!("{}{}", prefix, n))
+                .min_by_key(|name| matched(name))
+                .unwrap_or_default(),
+            None => pattern.to_string(),
+        };
+        if !probes.iter().any(|(_, n)| *n == name) {
+            probes.push((Some(pattern.to_string()), name));
+        }
+    }
+    let other = (0..64)
+        .map(|n| format!("if{}", n))
+        .find(|name| matched(name) == 0);
+    probes.extend(other.map(|name| (None, name)));
+    probes
+}
+
+/// ICMP types and codes of `protocol` to send probes with: every code `rules` name, one other
+/// code of every type they name and one type they do not name, labelled `None`.
+fn icmp_probes(rules: &[&Rule], protocol: u8) -> Vec<(Option<Icmp>, u8, u8)> {
+    let named: Vec<&Icmp> = rules
+        .iter()
+        .flat_map(|r| &r.icmp)
+        .filter(|i| i.protocol() == protocol)
+        .collect();
+    let mut kinds: Vec<(Family, u8)> = named.iter().map(|i| (i.family, i.kind)).collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+    let mut probes = Vec::new();
+    for &(family, kind) in &kinds {
+        let mut codes: Vec<u8> = named
+            .iter()
+            .filter(|i| i.kind == kind)
+            .filter_map(|i| i.code)
+            .collect();
+        codes.sort_unstable();
+        codes.dedup();
+        for &code in &codes {
+            probes.push((Some(Icmp::new(family, kind, Some(code))), kind, code));
+        }
+        if let Some(code) = (0..=u8::MAX).find(|c| !codes.contains(c)) {
+            probes.push((Some(Icmp::new(family, kind, None)), kind, code));
+        }
+    }
+    if let Some(kind) = (0..=u8::MAX).find(|k| !kinds.iter().any(|&(_, named)| named == *k)) {
+        probes.push((None, kind, 0));
+    }
+    probes
+}
+
+/// A region of packets, the packet sent for it and the rules that can match packets in it.
+#[derive(Clone)]
+struct Cell<'a> {
+    shift: Shift,
+    packet: Packet,
+    rules: Vec<&'a Rule>,
+}
+
+impl<'a> Cell<'a> {
+    /// Splits the cell in one per probe, `apply` narrows the region and its packet to the probe
+    /// and `keep` tells the rules that can still match.
+    fn split<T>(
+        self,
+        probes: Vec<T>,
+        apply: impl Fn(&mut Cell, T),
+        keep: impl Fn(&Rule, &Packet) -> bool,
+    ) -> Vec<Cell<'a>> {
+        probes
+            .into_iter()
+            .map(|probe| {
+                let mut cell = self.clone();
+                apply(&mut cell, probe);
+                let packet = &cell.packet;
+                cell.rules.retain(|rule| keep(rule, packet));
+                cell
+            })
+            .collect()
+    }
+}
+
+/// Where packets of each family, protocol, interface, ICMP type and destination port get a
+/// different verdict.
+///
+/// The packet space is split one dimension at a time, each piece only by the edges of the rules
+/// that can still match in it, then both chains are run on one packet from every piece and
+/// pieces with the same change are merged. Source ports, connection state, marks and the
+/// contents of sets missing from a side are not told apart.
+pub fn shifts(old: Side, new: Side) -> Vec<Shift> {
+    if old.chain == new.chain && old.chains == new.chains && old.sets == new.sets {
+        return Vec::new();
+    }
+    let direction = match old.chain.base.as_ref().map(|b| b.hook) {
+        Some(Hook::Output) => Direction::Out,
+        Some(Hook::Forward) => Direction::Fwd,
+        _ => Direction::In,
+    };
+    // Rules of the chains jumped to decide verdicts as much as those of the chain itself.
+    let rules: Vec<&Rule> = [old.chain, new.chain]
+        .into_iter()
+        .chain(old.chains)
+        .chain(new.chains)
+        .flat_map(|c| &c.rules)
+        .filter(|r| r.direction == Direction::None || r.direction == direction)
+        .collect();
+    let mut protocols: Vec<u8> = rules
+        .iter()
+        .flat_map(|r| r.protocols.iter().map(|p| p.0))
+        .chain(
+            rules
+                .iter()
+                .flat_map(|r| r.ports.0.iter().chain(&r.ports.1).map(|p| p.proto)),
+        )
+        .chain(rules.iter().flat_map(|r| r.icmp.iter().map(Icmp::protocol)))
+        .filter(|&p| p != 254)
+        .collect();
+    protocols.sort_unstable();
+    protocols.dedup();
+    // One protocol no rule names stands in for all of them.
+    let other = (0..=u8::MAX)
+        .find(|p| !protocols.contains(p))
+        .unwrap_or(255);
+    let mut candidates: Vec<(Option<u8>, u8)> = protocols.iter().map(|&p| (Some(p), p)).collect();
+    candidates.push((None, other));
+
+    let mut packet = Packet::new(Ip::None, Ip::None, 0, 0, 0);
+    packet.direction = direction;
+    let whole = Cell {
+        shift: Shift {
+            family: Family::Any,
+            src: Vec::new(),
+            dst: Vec::new(),
+            protocol: None,
+            iif: None,
+            oif: None,
+            icmp: None,
+            ports: (0, u16::MAX),
+            old: Action::None,
+            new: Action::None,
+        },
+        packet,
+        rules,
+    };
+    let mut cells = whole.split(
+        vec![Family::V4, Family::V6],
+        |cell, family| {
+            cell.shift.family = family;
+            cell.packet.family = family;
+        },
+        |rule, packet| !(rule.family.is_v4() ^ rule.family.is_v6()) || rule.family == packet.family,
+    );
+    cells = cells
+        .into_iter()
+        .flat_map(|cell| {
+            cell.split(
+                candidates.clone(),
+                |cell, (protocol, number)| {
+                    cell.shift.protocol = protocol;
+                    cell.packet.protocol = number;
+                },
+                |rule, packet| {
+                    let carried = |ports: &PortSet| {
+                        ports.is_empty()
+                            || ports
+                                .iter()
+                                .any(|p| p.proto == packet.protocol || p.proto == 254)
+                    };
+                    (rule.protocols.is_empty()
+                        || rule.protocols.iter().any(|p| p.0 == packet.protocol))
+                        && (rule.icmp.is_empty()
+                            || rule.icmp.iter().any(|i| i.protocol() == packet.protocol))
+                        && carried(&rule.ports.0)
+                        && carried(&rule.ports.1)
+                },
+            )
+        })
+        .collect();
+    // The input hook knows no outgoing interface and the output hook no incoming one.
+    if direction != Direction::Out {
+        cells = cells
+            .into_iter()
+            .flat_map(|cell| {
+                let probes = interface_probes(&cell.rules, |r| &r.interfaces.0);
+                cell.split(
+                    probes,
+                    |cell, (label, name)| {
+                        cell.shift.iif = label;
+                        cell.packet.iif = name;
+                    },
+                    |rule, packet| {
+                        rule.interfaces.0.is_empty()
+                            || rule
+                                .interfaces
+                                .0
+                                .iter()
+                                .any(|p| interface_matches(p, &packet.iif))
+                    },
+                )
+            })
+            .collect();
+    }
+    if direction != Direction::In {
+        cells = cells
+            .into_iter()
+            .flat_map(|cell| {
+                let probes = interface_probes(&cell.rules, |r| &r.interfaces.1);
+                cell.split(
+                    probes,
+                    |cell, (label, name)| {
+                        cell.shift.oif = label;
+                        cell.packet.oif = name;
+                    },
+                    |rule, packet| {
+                        rule.interfaces.1.is_empty()
+                            || rule
+                                .interfaces
+                                .1
+                                .iter()
+                                .any(|p| interface_matches(p, &packet.oif))
+                    },
+                )
+            })
+            .collect();
+    }
+    cells = cells
+        .into_iter()
+        .flat_map(|cell| match cell.packet.protocol {
+            1 | 58 => {
+                let probes = icmp_probes(&cell.rules, cell.packet.protocol);
+                cell.split(
+                    probes,
+                    |cell, (label, kind, code)| {
+                        cell.shift.icmp = label;
+                        cell.packet.icmp_type = kind;
+                        cell.packet.icmp_code = code;
+                    },
+                    |rule, packet| {
+                        rule.icmp.is_empty()
+                            || rule.icmp.iter().any(|i| {
+                                i.protocol() == packet.protocol
+                                    && i.kind == packet.icmp_type
+                                    && i.code.is_none_or(|code| code == packet.icmp_code)
+                            })
+                    },
+                )
+            }
+            _ => vec![cell],
+        })
+        .collect();
+    cells = cells
+        .into_iter()
+        .flat_map(|cell| {
+            let edges = cell
+                .rules
+                .iter()
+                .flat_map(|r| &r.ports.1)
+                .filter(|p| {
+                    cell.shift.protocol.is_some()
+                        && (p.proto == cell.packet.protocol || p.proto == 254)
+                })
+                .map(|p| (p.first as u128, p.last as u128));
+            let probes = pieces(edges, u16::MAX as u128);
+            cell.split(
+                probes,
+                |cell, (first, last)| {
+                    cell.shift.ports = (first as u16, last as u16);
+                    cell.packet.dport = first as u16;
+                },
+                |rule, packet| {
+                    rule.ports.1.is_empty() || rule.ports.1.contains(packet.dport, packet.protocol)
+                },
+            )
+        })
+        .collect();
+    let address = |family: Family, first: u128| match family {
+        Family::V6 => Ip::from((first, 128)),
+        _ => Ip::from((first as u32, 32)),
+    };
+    cells = cells
+        .into_iter()
+        .flat_map(|cell| {
+            let family = cell.shift.family;
+            let probes = address_pieces(&cell.rules, family, |r| &r.ips.0);
+            cell.split(
+                probes,
+                |cell, piece| {
+                    cell.shift.src = vec![piece];
+                    cell.packet.src = address(family, piece.0);
+                },
+                |rule, packet| holds(&rule.ips.0, family, first(&packet.src)),
+            )
+        })
+        .collect();
+    cells = cells
+        .into_iter()
+        .flat_map(|cell| {
+            let family = cell.shift.family;
+            let probes = address_pieces(&cell.rules, family, |r| &r.ips.1);
+            cell.split(
+                probes,
+                |cell, piece| {
+                    cell.shift.dst = vec![piece];
+                    cell.packet.dst = address(family, piece.0);
+                },
+                |_, _| true,
+            )
+        })
+        .collect();
+
+    let verdict = |side: Side, packet: &Packet| {
+        simulate::chain(side.chain, side.chains, side.sets, packet).verdict
+    };
+    let shifts = cells.into_iter().filter_map(|cell| {
+        let (before, after) = (verdict(old, &cell.packet), verdict(new, &cell.packet));
+        (before != after).then_some(Shift {
+            old: before,
+            new: after,
+            ..cell.shift
+        })
+    });
+    merge(shifts.collect())
+}
+
+/// The first address of `ip`.
+fn first(ip: &Ip) -> u128 {
+    ip.to_prefix().map_or(0, |(_, (network, _))| network)
+}
+
+/// Joins shifts that differ in one dimension only: destinations, then sources, then adjacent
+/// ports.
+fn merge(shifts: Vec<Shift>) -> Vec<Shift> {
+    let shifts = join(
+        shifts,
+        |s| Shift {
+            dst: Vec::new(),
+            ..s.clone()
+        },
+        |m, s| {
+            m.dst = cidr::union(&m.dst, &s.dst);
+            true
+        },
+    );
+    let shifts = join(
+        shifts,
+        |s| Shift {
+            src: Vec::new(),
+            ..s.clone()
+        },
+        |m, s| {
+            m.src = cidr::union(&m.src, &s.src);
+            true
+        },
+    );
+    join(
+        shifts,
+        |s| Shift {
+            ports: (0, 0),
+            ..s.clone()
+        },
+        |m, s| {
+            let adjacent = m.ports.1.checked_add(1) == Some(s.ports.0);
+            if adjacent {
+                m.ports.1 = s.ports.1;
+            }
+            adjacent
+        },
+    )
+}
+
+/// Joins every shift into the last one with the same `key`, where `into` accepts it.
+fn join(
+    shifts: Vec<Shift>,
+    key: impl Fn(&Shift) -> Shift,
+    into: impl Fn(&mut Shift, &Shift) -> bool,
+) -> Vec<Shift> {
+    let mut joined: Vec<Shift> = Vec::new();
+    let mut last: HashMap<Shift, usize> = HashMap::new();
+    for shift in shifts {
+        let key = key(&shift);
+        match last.get(&key) {
+            Some(&at) if into(&mut joined[at], &shift) => {}
+            _ => {
+                last.insert(key, joined.len());
+                joined.push(shift);
+            }
+        }
+    }
+    joined
+}
+
+/// Rule changes and verdict shifts between two versions of a chain.
+pub fn chain(old: Side, new: Side) -> ChainDiff {
+    let policy = |side: Side| side.chain.base.as_ref().map(|b| b.policy.clone());
+    ChainDiff {
+        name: new.chain.name.clone(),
+        policy: match (policy(old), policy(new)) {
+            (Some(before), Some(after)) if before != after => Some((before, after)),
+            _ => None,
+        },
+        changes: rules(&old.chain.rules, &new.chain.rules),
+        shifts: shifts(old, new),
+    }
+}
+
+/// Compares chains of the same name, jumps are followed within each side and sets looked up
+/// in `sets` of that side. Chains without differences are left out.
+pub fn chains(old: &[Chain], new: &[Chain], sets: (&[NamedSet], &[NamedSet])) -> Diff {
+    let find = |chains: &[Chain], name: &str| chains.iter().position(|c| c.name == name);
+    let mut diff = Diff::default();
+    for chain in old.iter().filter(|c| find(new, &c.name).is_none()) {
+        diff.removed.push(chain.name.clone());
+    }
+    for chain in new {
+        let Some(before) = find(old, &chain.name) else {
+            diff.added.push(chain.name.clone());
+            continue;
+        };
+        let side = |chain, chains, sets| Side {
+            chain,
+            chains,
+            sets,
+        };
+        let result = self::chain(side(&old[before], old, sets.0), side(chain, new, sets.1));
+        if !result.is_empty() {
+            diff.chains.push(result);
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(rules: &str) -> Chain {
+        let text = format!(
+            "chain input {{ type filter hook input priority filter; policy accept; {} }}",
+            rules
+        );
+        nft::parse_chain(&text, &[]).unwrap()
+    }
+
+    fn shifts(old: &str, new: &str) -> Vec<String> {
+        let (old, new) = (chain(old), chain(new));
+        super::shifts(Side::new(&old), Side::new(&new))
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn verdict_change_modifies_unnamed_rule() {
+        let (old, new) = (chain("tcp dport 22 accept"), chain("tcp dport 22 drop"));
+        let changes = rules(&old.rules, &new.rules);
+        assert_eq!(
+            changes,
+            [Change::Modified {
+                key: "tcp dport 22".to_string(),
+                old: "tcp dport 22 accept".to_string(),
+                new: "tcp dport 22 drop".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rules_are_keyed_by_name_then_comment() {
+        let old = chain("tcp dport 22 accept comment \"[ssh] admins\"; udp dport 53 accept");
+        let new = chain("udp dport 53 accept; tcp dport 2222 accept comment \"[ssh] admins\"");
+        let changes: Vec<String> = rules(&old.rules, &new.rules)
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                "> ssh moved from 1 to 2",
+                "~ ssh: tcp dport 22 accept comment \"[ssh] admins\" => tcp dport 2222 accept comment \"[ssh] admins\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn address_and_port_shifts() {
+        assert_eq!(
+            shifts(
+                "ip saddr 203.0.113.0/24 tcp dport 22 accept; tcp dport 22 drop",
+                "tcp dport 22 drop"
+            ),
+            ["203.0.113.0/24 tcp/22 changed from accept to drop"]
+        );
+    }
+
+    #[test]
+    fn interface_shifts() {
+        assert_eq!(
+            shifts("iifname \"eth0\" drop", "iifname \"eth0\" accept"),
+            [
+                "0.0.0.0/0 other protocols in eth0 changed from drop to accept",
+                "::/0 other protocols in eth0 changed from drop to accept",
+            ]
+        );
+    }
+
+    #[test]
+    fn icmp_type_shifts() {
+        assert_eq!(
+            shifts(
+                "meta nfproto ipv4 icmp type echo-request drop",
+                "meta nfproto ipv4 icmp type echo-reply drop"
+            ),
+            [
+                "0.0.0.0/0 icmp echo-reply changed from accept to drop",
+                "0.0.0.0/0 icmp echo-request changed from drop to accept",
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_chains_have_no_shifts() {
+        let rules = "ip saddr 10.0.0.0/8 tcp dport 22 accept; udp dport 53 drop";
+        assert!(shifts(rules, rules).is_empty());
+    }
+}
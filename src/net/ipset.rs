@@ -0,0 +1,101 @@
+use super::Family;
+use std::fmt::Write;
+
+/// Default `maxelem` of an ipset hash, also the largest chunk written per set.
+pub const MAXELEM: usize = 65536;
+
+/// Suffix of the temporary set used for atomic replacement.
+pub const SWAP_SUFFIX: &str = "-tmp";
+
+/// Maps an address family to the ipset `family` keyword.
+pub fn family(family: Family) -> &'static str {
+    match family {
+        Family::V6 => "inet6",
+        _ => "inet",
+    }
+}
+
+/// Family of a generated set from its `-v4`/`-v6` name suffix, IPv4 when it has neither.
+pub fn family_of(name: &str) -> Family {
+    if name.ends_with("-v6") {
+        Family::V6
+    } else {
+        Family::V4
+    }
+}
+
+/// Renders a complete `ipset restore` script for a `hash:net` set.
+///
+/// With `swap` the entries are loaded into `<name>-tmp` which is then swapped with the live set
+/// and destroyed, so the live set is replaced atomically.
+pub fn restore<I, T>(name: &str, fam: Family, maxelem: usize, elements: I, swap: bool) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: std::fmt::Display,
+{
+    let maxelem = maxelem.max(1);
+    let target = if swap {
+        format!("{}{}", name, SWAP_SUFFIX)
+    } else {
+        name.to_string()
+    };
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "create {} hash:net family {} maxelem {} -exist",
+        name,
+        family(fam),
+        maxelem
+    );
+    if swap {
+        let _ = writeln!(
+            out,
+            "create {} hash:net family {} maxelem {} -exist",
+            target,
+            family(fam),
+            maxelem
+        );
+    }
+    let _ = writeln!(out, "flush {}", target);
+    for element in elements {
+        let _ = writeln!(out, "add {} {}", target, element);
+    }
+    if swap {
+        let _ = writeln!(out, "swap {} {}", target, name);
+        let _ = writeln!(out, "destroy {}", target);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::v4::NetSet;
+
+    #[test]
+    fn restore_script() {
+        let nets = NetSet::from_str("10.0.0.0/8 192.168.1.1").unwrap();
+        assert_eq!(
+            nets.to_ipset("block-v4", 1024, false),
+            "create block-v4 hash:net family inet maxelem 1024 -exist\n\
+             flush block-v4\n\
+             add block-v4 10.0.0.0/8\n\
+             add block-v4 192.168.1.1/32\n"
+        );
+    }
+
+    #[test]
+    fn swap_replaces_the_live_set_atomically() {
+        assert_eq!(
+            restore("s-v6", Family::V6, 0, ["2001:db8::/32"], true),
+            "create s-v6 hash:net family inet6 maxelem 1 -exist\n\
+             create s-v6-tmp hash:net family inet6 maxelem 1 -exist\n\
+             flush s-v6-tmp\n\
+             add s-v6-tmp 2001:db8::/32\n\
+             swap s-v6-tmp s-v6\n\
+             destroy s-v6-tmp\n"
+        );
+        assert_eq!(family_of("s-v6"), Family::V6);
+        assert_eq!(family_of("s"), Family::V4);
+    }
+}
@@ -0,0 +1,1264 @@
+use super::{
+    Action, Base, Chain, ChainType, CtState, CtStatus, Family, Hook, Icmp, Ip, PortRange, PortSet,
+    Priority, Proto, Rule, Table, is_interface, parse_mark,
+};
+use crate::meta::Error;
+use std::fmt::Write;
+
+/// Protocols whose ports iptables matches with `--sport`/`--dport`, by protocol number.
+const PORT_PROTOCOLS: [(u8, &str); 4] = [(6, "tcp"), (17, "udp"), (33, "dccp"), (132, "sctp")];
+
+/// Largest number of ports `-m multiport` accepts in one match.
+pub const MULTIPORT_MAX: usize = 15;
+
+/// Name of the save/restore command pair for an address family, `iptables` or `ip6tables`.
+pub fn command(family: Family) -> &'static str {
+    match family {
+        Family::V6 => "ip6tables",
+        _ => "iptables",
+    }
+}
+
+/// Table a base chain of `kind` belongs to, `None` for hooks iptables has no chains on.
+pub fn table(kind: ChainType, hook: Hook) -> Option<&'static str> {
+    match (kind, hook) {
+        (_, Hook::Ingress) => None,
+        (ChainType::Filter, Hook::Prerouting | Hook::Postrouting) => Some("mangle"),
+        (ChainType::Filter, _) => Some("filter"),
+        (ChainType::Nat, Hook::Forward) => None,
+        (ChainType::Nat, _) => Some("nat"),
+        (ChainType::Route, _) => Some("mangle"),
+    }
+}
+
+/// Name of the builtin chain on a hook.
+pub fn builtin(hook: Hook) -> String {
+    hook.as_str().to_ascii_uppercase()
+}
+
+/// Other base chains of `chains` that share the builtin chain of `chain`, in priority order.
+///
+/// Empty unless `chain` is a base chain and at least one other base chain of the same table
+/// sits on its hook.
+fn stack<'a>(chain: &Chain, chains: &'a [Chain]) -> Vec<&'a Chain> {
+    let Some(base) = &chain.base else {
+        return Vec::new();
+    };
+    let mut stack: Vec<&Chain> = chains
+        .iter()
+        .filter(|other| {
+            other.base.as_ref().is_some_and(|other| {
+                other.hook == base.hook
+                    && table(other.kind, other.hook) == table(base.kind, base.hook)
+            })
+        })
+        .collect();
+    if stack.len() < 2 {
+        return Vec::new();
+    }
+    stack.sort_by_key(|chain| chain.base.as_ref().map(|base| base.priority.value()));
+    stack
+}
+
+/// Name a chain is saved under: the builtin chain of its hook for base chains, unless other
+/// base chains share the hook, see [`save`].
+fn chain_name(chain: &Chain, chains: &[Chain]) -> String {
+    match (&chain.base, chain.name.as_str()) {
+        (Some(base), _) if stack(chain, chains).is_empty() => builtin(base.hook),
+        (_, "") => format!("chain_{}", chain.number),
+        (_, name) => name.to_string(),
+    }
+}
+
+/// Name of the chain `number` jumps to, `chain_<number>` when it is not in `chains`.
+fn jump_name(number: u32, chains: &[Chain]) -> String {
+    chains
+        .iter()
+        .find(|chain| chain.number == number)
+        .map(|chain| chain_name(chain, chains))
+        .unwrap_or_else(|| format!("chain_{}", number))
+}
+
+/// Regular chains `chain` reaches through jumps, directly or through other regular chains.
+fn reached<'a>(chain: &Chain, chains: &'a [Chain]) -> Vec<&'a Chain> {
+    let mut reached: Vec<&Chain> = Vec::new();
+    let jumps = |chain: &Chain| -> Vec<u32> {
+        chain
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.action {
+                Action::Jump(number) => Some(number),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut pending = jumps(chain);
+    while let Some(number) = pending.pop() {
+        let Some(next) = chains
+            .iter()
+            .find(|c| c.number == number && c.base.is_none())
+        else {
+            continue;
+        };
+        if reached.iter().any(|c| c.number == number) {
+            continue;
+        }
+        pending.extend(jumps(next));
+        reached.push(next);
+    }
+    reached
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'"))
+}
+
+/// Renders the target of an action, `None` when iptables has no equivalent.
+///
+/// Actions that only match, such as quotas, render as a match without a target. `bucket` names
+/// the hashlimit table rate limits are counted in.
+pub fn target(action: &Action, bucket: &str, chains: &[Chain]) -> Option<String> {
+    match action {
+        Action::None | Action::Skip | Action::Counter => Some(String::new()),
+        Action::Accept => Some("-j ACCEPT".to_string()),
+        Action::Drop => Some("-j DROP".to_string()),
+        Action::Reject => Some("-j REJECT".to_string()),
+        Action::Return => Some("-j RETURN".to_string()),
+        Action::Jump(number) => Some(format!("-j {}", jump_name(*number, chains))),
+        Action::Limit(rate) => Some(format!(
+            "-m hashlimit --hashlimit-above {}/second --hashlimit-name {} -j DROP",
+            rate, bucket
+        )),
+        Action::Hashlimit(rate, burst) => {
+            let burst = burst
+                .map(|b| format!(" --hashlimit-burst {}", b))
+                .unwrap_or_default();
+            Some(format!(
+                "-m hashlimit --hashlimit-upto {}{} --hashlimit-name {}",
+                rate, burst, bucket
+            ))
+        }
+        Action::Log(prefix) if prefix.is_empty() => Some("-j LOG".to_string()),
+        Action::Log(prefix) => Some(format!("-j LOG --log-prefix {}", quote(prefix))),
+        Action::Mark(mark) => Some(format!("-j MARK --set-mark {}", mark)),
+        Action::Masquerade => Some("-j MASQUERADE".to_string()),
+        Action::Snat(addr) => Some(format!("-j SNAT --to-source {}", addr)),
+        Action::Dnat(addr) => Some(format!("-j DNAT --to-destination {}", addr)),
+        Action::Redirect(None) => Some("-j REDIRECT".to_string()),
+        Action::Redirect(Some(port)) => Some(format!("-j REDIRECT --to-ports {}", port)),
+        Action::TProxy(port, 0) => Some(format!("-j TPROXY --on-port {}", port)),
+        Action::TProxy(port, mark) => Some(format!(
+            "-j TPROXY --on-port {} --tproxy-mark {}",
+            port, mark
+        )),
+        Action::Queue => Some("-j NFQUEUE".to_string()),
+        Action::Quota(bytes) => Some(format!("-m quota --quota {}", bytes)),
+        Action::Meta(_)
+        | Action::Set(_, _)
+        | Action::Ct
+        | Action::FlowOffload
+        | Action::Reclassify => None,
+    }
+}
+
+/// Name of the port-carrying protocol a port range belongs to, if iptables matches its ports.
+fn port_proto(port: &PortRange) -> Option<&'static str> {
+    PORT_PROTOCOLS
+        .iter()
+        .find(|(number, _)| *number == port.proto)
+        .map(|(_, name)| *name)
+}
+
+/// Name of a protocol as understood by `-p`.
+fn proto_name(proto: &Proto) -> String {
+    match proto.get_name().as_str() {
+        "unknown" => proto.get_number().to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Protocol and port matches of a rule, one entry per protocol since `-p` takes only one.
+fn proto_matches(rule: &Rule) -> Vec<String> {
+    let ports = || rule.ports.0.iter().chain(rule.ports.1.iter());
+    let mut protos: Vec<String> = Vec::new();
+    for port in ports() {
+        let names = match port_proto(port) {
+            Some(name) => vec![name.to_string()],
+            None => {
+                let names: Vec<String> = rule
+                    .protocols
+                    .iter()
+                    .map(proto_name)
+                    .filter(|name| PORT_PROTOCOLS.iter().any(|(_, p)| p == name))
+                    .collect();
+                match names.is_empty() {
+                    true => vec!["tcp".to_string(), "udp".to_string()],
+                    false => names,
+                }
+            }
+        };
+        for name in names {
+            if !protos.contains(&name) {
+                protos.push(name);
+            }
+        }
+    }
+    if protos.is_empty() {
+        return rule
+            .protocols
+            .iter()
+            .map(|proto| format!("-p {}", proto_name(proto)))
+            .collect();
+    }
+    let mut matches = Vec::new();
+    for name in &protos {
+        // Several multiport matches in one rule must all hold, so larger lists get a rule each.
+        let batches = |ports: &PortSet, flag: &str| -> Vec<String> {
+            let ranges: Vec<&PortRange> = ports
+                .iter()
+                .filter(|port| port_proto(port).is_none_or(|p| p == name))
+                .collect();
+            let text = |range: &PortRange| match range.is_single() {
+                true => range.first.to_string(),
+                false => format!("{}:{}", range.first, range.last),
+            };
+            if let [range] = ranges.as_slice() {
+                return vec![format!(" --{} {}", flag, text(range))];
+            }
+            // A range takes two of the ports multiport allows.
+            let mut batches: Vec<Vec<String>> = Vec::new();
+            let mut used = MULTIPORT_MAX;
+            for range in ranges {
+                let weight = if range.is_single() { 1 } else { 2 };
+                if used + weight > MULTIPORT_MAX {
+                    batches.push(Vec::new());
+                    used = 0;
+                }
+                used += weight;
+                batches.last_mut().unwrap().push(text(range));
+            }
+            match batches.is_empty() {
+                true => vec![String::new()],
+                false => batches
+                    .iter()
+                    .map(|batch| format!(" -m multiport --{}s {}", flag, batch.join(",")))
+                    .collect(),
+            }
+        };
+        for sport in batches(&rule.ports.0, "sport") {
+            for dport in batches(&rule.ports.1, "dport") {
+                matches.push(format!("-p {}{}{}", name, sport, dport));
+            }
+        }
+    }
+    matches
+}
+
+/// ICMP type matches of the rule for the `family` save file, one entry per type since
+/// `--icmp-type` takes only one. Types are written as numbers, as `iptables-save` lists them.
+fn icmp_matches(rule: &Rule, family: Family) -> Vec<String> {
+    let (proto, option) = match family {
+        Family::V6 => ("ipv6-icmp", "--icmpv6-type"),
+        _ => ("icmp", "--icmp-type"),
+    };
+    rule.icmp
+        .iter()
+        .filter(|icmp| icmp.family == family)
+        .map(|icmp| match icmp.code {
+            Some(code) => format!("-p {} {} {}/{}", proto, option, icmp.kind, code),
+            None => format!("-p {} {} {}", proto, option, icmp.kind),
+        })
+        .collect()
+}
+
+/// Interface matches, one entry per pair of input and output interface since `-i` and `-o`
+/// take only one each. A trailing `*` wildcard becomes iptables' `+`.
+fn interface_matches(rule: &Rule) -> Vec<String> {
+    let flags = |flag: &str, names: &[String]| -> Vec<String> {
+        match names.is_empty() {
+            true => vec![String::new()],
+            false => names
+                .iter()
+                .map(|name| match name.strip_suffix('*') {
+                    Some(prefix) => format!(" {} {}+", flag, prefix),
+                    None => format!(" {} {}", flag, name),
+                })
+                .collect(),
+        }
+    };
+    let mut matches = Vec::new();
+    for input in flags("-i", &rule.interfaces.0) {
+        for output in flags("-o", &rule.interfaces.1) {
+            let pair = format!("{}{}", input, output);
+            if !pair.is_empty() {
+                matches.push(pair.trim_start().to_string());
+            }
+        }
+    }
+    matches
+}
+
+/// Connection tracking matches: `--ctstate` for states and for the snat/dnat status bits,
+/// which iptables counts as states, `--ctstatus` for the other bits, `connmark` and `helper`.
+fn conntrack(rule: &Rule) -> Vec<String> {
+    let ct = &rule.conntrack;
+    let upper = |name: &str| name.to_ascii_uppercase().replace('-', "_");
+    let mut matches = Vec::new();
+    if !ct.state.is_empty() {
+        let states: Vec<String> = ct.state.iter().map(|s| upper(s.as_str())).collect();
+        matches.push(format!("-m conntrack --ctstate {}", states.join(",")));
+    }
+    let (nat, status): (Vec<&CtStatus>, Vec<&CtStatus>) = ct
+        .status
+        .iter()
+        .filter(|s| **s != CtStatus::Dying)
+        .partition(|s| matches!(s, CtStatus::Snat | CtStatus::Dnat));
+    for (option, bits) in [("--ctstate", nat), ("--ctstatus", status)] {
+        if !bits.is_empty() {
+            let bits: Vec<String> = bits.iter().map(|s| upper(s.as_str())).collect();
+            matches.push(format!("-m conntrack {} {}", option, bits.join(",")));
+        }
+    }
+    if let Some(mark) = ct.mark {
+        matches.push(format!("-m connmark --mark {:#x}", mark));
+    }
+    if let Some(helper) = &ct.helper {
+        matches.push(format!("-m helper --helper {}", quote(helper)));
+    }
+    matches
+}
+
+/// The first match of `rule` iptables cannot express, if any.
+fn unmatched(rule: &Rule) -> Option<String> {
+    let ct = &rule.conntrack;
+    let nat = ct
+        .status
+        .iter()
+        .any(|s| matches!(s, CtStatus::Snat | CtStatus::Dnat));
+    if let Some(zone) = ct.zone {
+        Some(format!("ct zone {}", zone))
+    } else if ct.status.contains(&CtStatus::Dying) {
+        Some("ct status dying".to_string())
+    } else if nat
+        && ct.status.len() > 1
+        && ct
+            .status
+            .iter()
+            .any(|s| !matches!(s, CtStatus::Snat | CtStatus::Dnat))
+    {
+        // Split over --ctstate and --ctstatus they would all have to hold instead of any.
+        Some("ct status mixing snat or dnat with other bits".to_string())
+    } else {
+        None
+    }
+}
+
+/// Renders a rule as `-A <chain> ...` lines for the `family` save file.
+///
+/// Rules matching addresses only of the other family render to nothing. Rules matching ports
+/// of several protocols, or several interfaces, become one line per protocol and interface
+/// pair, the rule name is kept in the comment as
+/// `[name]`, and rules whose action iptables cannot express are returned commented out.
+pub fn rule(
+    rule: &Rule,
+    family: Family,
+    chain: &str,
+    index: usize,
+    chains: &[Chain],
+) -> Vec<String> {
+    let restricted = rule.family.is_v4() != rule.family.is_v6();
+    if restricted && rule.family != family {
+        return Vec::new();
+    }
+    let mut matches = Vec::new();
+    for (flag, ips, sets, dir) in [
+        ("-s", &rule.ips.0, &rule.sets.0, "src"),
+        ("-d", &rule.ips.1, &rule.sets.1, "dst"),
+    ] {
+        let concrete: Vec<&Ip> = ips
+            .iter()
+            .filter(|ip| matches!(ip, Ip::V4(_) | Ip::V6(_)))
+            .collect();
+        let constrained = !concrete.is_empty() || !sets.is_empty();
+        let addrs: Vec<String> = concrete
+            .iter()
+            .filter(|ip| ip.family() == family)
+            .map(|ip| ip.to_string())
+            .collect();
+        let sets: Vec<&String> = sets
+            .iter()
+            .filter(|set| rule.set_family(set) == family)
+            .collect();
+        // Only the other family can match, the rule belongs to the other save file.
+        if constrained && addrs.is_empty() && sets.is_empty() {
+            return Vec::new();
+        }
+        if !addrs.is_empty() {
+            matches.push(format!("{} {}", flag, addrs.join(",")));
+        }
+        for set in sets {
+            matches.push(format!("-m set --match-set {} {}", set, dir));
+        }
+    }
+    matches.extend(conntrack(rule));
+    let mut comment = String::new();
+    if !rule.name.is_empty() {
+        comment = format!("[{}]", rule.name);
+    }
+    if !rule.comment.is_empty() {
+        if !comment.is_empty() {
+            comment.push(' ');
+        }
+        comment.push_str(&rule.comment);
+    }
+    let bucket = format!("{}-{}", chain, index);
+    let target = self::target(&rule.action, &bucket, chains);
+    // An ICMP type match implies its protocol, types of the other family only never match.
+    let protos = match rule.icmp.is_empty() {
+        true => proto_matches(rule),
+        false => icmp_matches(rule, family),
+    };
+    if protos.is_empty() && !rule.icmp.is_empty() {
+        return Vec::new();
+    }
+    let groups: Vec<Option<&String>> = match protos.is_empty() {
+        true => vec![None],
+        false => protos.iter().map(Some).collect(),
+    };
+    let links = interface_matches(rule);
+    let links: Vec<Option<&String>> = match links.is_empty() {
+        true => vec![None],
+        false => links.iter().map(Some).collect(),
+    };
+    let mut lines = Vec::new();
+    for (link, group) in links
+        .iter()
+        .flat_map(|link| groups.iter().map(move |group| (link, group)))
+    {
+        let mut line = vec![format!("-A {}", chain)];
+        line.extend(matches.iter().cloned());
+        line.extend(link.cloned());
+        line.extend(group.cloned());
+        if !comment.is_empty() {
+            line.push(format!("-m comment --comment {}", quote(&comment)));
+        }
+        match &target {
+            Some(target) if !target.is_empty() => line.push(target.clone()),
+            _ => {}
+        }
+        let line = line.join(" ");
+        match (&target, unmatched(rule)) {
+            (None, _) => lines.push(format!("# unsupported action {:?}: {}", rule.action, line)),
+            (Some(_), Some(part)) => lines.push(format!("# unsupported match {}: {}", part, line)),
+            (Some(_), None) => lines.push(line),
+        }
+    }
+    lines
+}
+
+/// Tables the chains are saved in: base chains go to the table of their type and hook, regular
+/// chains to every table whose chains jump to them, and to `filter` when none does.
+fn tables(chains: &[Chain]) -> Vec<Vec<&'static str>> {
+    let mut tables: Vec<Vec<&'static str>> = chains
+        .iter()
+        .map(|chain| match &chain.base {
+            Some(base) => table(base.kind, base.hook).into_iter().collect(),
+            None => Vec::new(),
+        })
+        .collect();
+    // Jumps may chain through several regular chains, repeat until nothing changes.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, chain) in chains.iter().enumerate() {
+            for rule in &chain.rules {
+                let Action::Jump(number) = rule.action else {
+                    continue;
+                };
+                let Some(j) = chains.iter().position(|c| c.number == number) else {
+                    continue;
+                };
+                if chains[j].base.is_some() {
+                    continue;
+                }
+                for name in tables[i].clone() {
+                    if !tables[j].contains(&name) {
+                        tables[j].push(name);
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    for (i, chain) in chains.iter().enumerate() {
+        if chain.base.is_none() && tables[i].is_empty() {
+            tables[i].push("filter");
+        }
+    }
+    tables
+}
+
+/// Renders chains as an `iptables-restore` (or `ip6tables-restore` for [`Family::V6`]) script.
+///
+/// Base chains become the builtin chain of their hook with their policy. Several base chains
+/// on the same hook each become a regular chain the builtin chain jumps to in priority order:
+/// their `accept` becomes `RETURN`, so the packet goes on to the next one as in nft, and a drop
+/// policy becomes a final `DROP` rule. An `accept` in a chain they jump to still ends the
+/// builtin chain, [`unsupported`] reports those.
+pub fn save(family: Family, chains: &[Chain]) -> String {
+    let tables = tables(chains);
+    let mut out = String::new();
+    for name in ["raw", "mangle", "nat", "filter"] {
+        let mut members: Vec<&Chain> = chains
+            .iter()
+            .zip(tables.iter())
+            .filter(|(_, tables)| tables.contains(&name))
+            .map(|(chain, _)| chain)
+            .collect();
+        if members.is_empty() {
+            continue;
+        }
+        members.sort_by_key(|chain| match &chain.base {
+            Some(base) => (false, base.priority.value()),
+            None => (true, 0),
+        });
+        let _ = writeln!(out, "*{}", name);
+        let mut declared: Vec<String> = Vec::new();
+        let mut jumps: Vec<String> = Vec::new();
+        for chain in &members {
+            let chain_name = chain_name(chain, chains);
+            let policy = match &chain.base {
+                Some(base) if !stack(chain, chains).is_empty() => {
+                    let builtin = builtin(base.hook);
+                    let line = format!(":{} ACCEPT [0:0]", builtin);
+                    if !declared.contains(&line) {
+                        declared.push(line);
+                    }
+                    jumps.push(format!("-A {} -j {}", builtin, chain_name));
+                    "-"
+                }
+                Some(base) if base.policy == Action::Drop => "DROP",
+                Some(_) => "ACCEPT",
+                None => "-",
+            };
+            declared.push(format!(":{} {} [0:0]", chain_name, policy));
+        }
+        for line in declared.iter().chain(jumps.iter()) {
+            let _ = writeln!(out, "{}", line);
+        }
+        for chain in &members {
+            let chain_name = chain_name(chain, chains);
+            let stacked = !stack(chain, chains).is_empty();
+            for (i, r) in chain.rules.iter().enumerate() {
+                let mut r = r.clone();
+                if stacked && r.action == Action::Accept {
+                    r.action = Action::Return;
+                }
+                for line in rule(&r, family, &chain_name, i + 1, chains) {
+                    let _ = writeln!(out, "{}", line);
+                }
+            }
+            if let Some(base) = &chain.base
+                && stacked
+                && base.policy == Action::Drop
+            {
+                let _ = writeln!(out, "-A {} -j DROP", chain_name);
+            }
+        }
+        let _ = writeln!(out, "COMMIT");
+    }
+    out
+}
+
+/// Lists everything in the chains [`save`] cannot express: actions without an iptables target,
+/// base chains on hooks iptables has no builtin chain for, and `accept` in chains jumped to
+/// from a base chain that shares its hook with later base chains.
+pub fn unsupported(chains: &[Chain]) -> Vec<Error> {
+    let mut problems = Vec::new();
+    for chain in chains {
+        let name = chain_name(chain, chains);
+        if let Some(base) = &chain.base
+            && table(base.kind, base.hook).is_none()
+        {
+            problems.push(Error::new(
+                format!(
+                    "chain {}: {} chains on the {} hook have no iptables equivalent",
+                    chain.name, base.kind, base.hook
+                ),
+                2,
+            ));
+        }
+        let later: Vec<&str> = stack(chain, chains)
+            .iter()
+            .skip_while(|c| !std::ptr::eq(**c, chain))
+            .skip(1)
+            .map(|c| c.name.as_str())
+            .collect();
+        for reached in reached(chain, chains) {
+            if let Some(base) = &chain.base
+                && !later.is_empty()
+                && reached.rules.iter().any(|r| r.action == Action::Accept)
+            {
+                problems.push(Error::new(
+                    format!(
+                        "chain {}: accept ends the {} chain in iptables and skips base chain {}",
+                        reached.name,
+                        builtin(base.hook),
+                        later.join(", ")
+                    ),
+                    2,
+                ));
+            }
+        }
+        for (i, rule) in chain.rules.iter().enumerate() {
+            if let Some(part) = unmatched(rule) {
+                problems.push(Error::new(
+                    format!(
+                        "chain {} rule {}: match '{}' has no iptables equivalent",
+                        chain.name,
+                        i + 1,
+                        part
+                    ),
+                    2,
+                ));
+            }
+            if target(&rule.action, &name, chains).is_none() {
+                problems.push(Error::new(
+                    format!(
+                        "chain {} rule {}: action '{}' has no iptables equivalent",
+                        chain.name,
+                        i + 1,
+                        rule.action
+                    ),
+                    2,
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Something in an `iptables-save` file the importer could not represent.
+///
+/// Rules with such a match are not imported, since leaving the match out would widen them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Line of the save file, counting from 1.
+    pub line: usize,
+    /// The option or line that could not be represented.
+    pub token: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, token: &str, message: &str) -> Self {
+        Self {
+            line,
+            token: token.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}: '{}'", self.line, self.message, self.token)
+    }
+}
+
+/// Splits a save file line into words, keeping double-quoted text together.
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => word.extend(chars.next()),
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Hook and priority of a builtin chain in an iptables table.
+fn base(table: &str, chain: &str, policy: &str) -> Option<Base> {
+    let hook = Hook::from_name(chain)?;
+    let (kind, priority) = match (table, hook) {
+        ("nat", Hook::Prerouting | Hook::Output) => (ChainType::Nat, "dstnat"),
+        ("nat", _) => (ChainType::Nat, "srcnat"),
+        ("mangle", Hook::Output) => (ChainType::Route, "mangle"),
+        ("mangle", _) => (ChainType::Filter, "mangle"),
+        ("raw", _) => (ChainType::Filter, "raw"),
+        ("security", _) => (ChainType::Filter, "security"),
+        _ => (ChainType::Filter, "filter"),
+    };
+    let policy = match policy {
+        "DROP" => Action::Drop,
+        _ => Action::Accept,
+    };
+    Some(Base::new(
+        kind,
+        hook,
+        Priority::Named(priority.to_string(), 0),
+        policy,
+    ))
+}
+
+/// Parses the target of a `-j` option and its arguments.
+fn parse_target(target: &str, args: &[(String, String)], chains: &[Chain]) -> Option<Action> {
+    let arg = |name: &str| {
+        args.iter()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let number = |value: Option<&str>| -> Option<u32> { parse_mark(value?.split('/').next()?) };
+    let port =
+        |value: Option<&str>| -> Option<u16> { value?.split(['-', ':']).next()?.parse().ok() };
+    let action = match target {
+        "ACCEPT" => Action::Accept,
+        "DROP" => Action::Drop,
+        "REJECT" => Action::Reject,
+        "RETURN" => Action::Return,
+        "MASQUERADE" => Action::Masquerade,
+        "NFQUEUE" | "QUEUE" => Action::Queue,
+        "LOG" => Action::Log(arg("--log-prefix").unwrap_or_default().to_string()),
+        "MARK" => Action::Mark(number(arg("--set-mark").or(arg("--set-xmark")))?),
+        "SNAT" => Action::Snat(arg("--to-source")?.to_string()),
+        "DNAT" => Action::Dnat(arg("--to-destination")?.to_string()),
+        "REDIRECT" => match arg("--to-ports") {
+            Some(ports) => Action::Redirect(Some(port(Some(ports))?)),
+            None => Action::Redirect(None),
+        },
+        "TPROXY" => Action::TProxy(
+            port(arg("--on-port"))?,
+            number(arg("--tproxy-mark")).unwrap_or(0),
+        ),
+        name => Action::Jump(chains.iter().find(|chain| chain.name == name)?.number),
+    };
+    // Options of the target the action has no room for, such as `--reject-with`.
+    let known = [
+        "--log-prefix",
+        "--set-mark",
+        "--set-xmark",
+        "--to-source",
+        "--to-destination",
+        "--to-ports",
+        "--on-port",
+        "--tproxy-mark",
+    ];
+    // Rejecting with port unreachable is what a plain reject does.
+    let default = |option: &str, value: &str| {
+        option == "--reject-with"
+            && matches!(value, "icmp-port-unreachable" | "icmp6-port-unreachable")
+    };
+    match args
+        .iter()
+        .all(|(option, value)| known.contains(&option.as_str()) || default(option, value))
+    {
+        true => Some(action),
+        false => None,
+    }
+}
+
+/// Parses the options of an `-A <chain>` line into a rule.
+fn parse_rule(
+    words: &[String],
+    chains: &[Chain],
+) -> std::result::Result<Rule, (String, &'static str)> {
+    let mut rule = Rule {
+        family: Family::Any,
+        ..Rule::default()
+    };
+    let mut proto: Option<String> = None;
+    let mut target: Option<(String, Vec<(String, String)>)> = None;
+    let mut i = 0;
+    // An option and the values up to the next option.
+    let take = |i: &mut usize| -> (String, Vec<String>) {
+        let option = words[*i].clone();
+        *i += 1;
+        let mut values = Vec::new();
+        while *i < words.len() && !words[*i].starts_with('-') && words[*i] != "!" {
+            values.push(words[*i].clone());
+            *i += 1;
+        }
+        (option, values)
+    };
+    let mut module = String::new();
+    let mut pending_ports: Vec<(bool, String)> = Vec::new();
+    while i < words.len() {
+        if words[i] == "!" {
+            let rest = words[i..]
+                .iter()
+                .take(3)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" ");
+            return Err((rest, "negated matches are not supported"));
+        }
+        let (option, values) = take(&mut i);
+        let value = values.join(" ");
+        let unsupported =
+            |message| Err((format!("{} {}", option, value).trim().to_string(), message));
+        if let Some((_, args)) = &mut target {
+            args.push((option, value));
+            continue;
+        }
+        match option.as_str() {
+            "-s" | "--source" | "-d" | "--destination" => {
+                let ips = match option.starts_with("-s") || option == "--source" {
+                    true => &mut rule.ips.0,
+                    false => &mut rule.ips.1,
+                };
+                for addr in value.split(',').filter(|a| !a.is_empty()) {
+                    match Ip::from_str(addr) {
+                        Ok(ip) => ips.push(ip),
+                        Err(_) => return unsupported("invalid address"),
+                    }
+                }
+            }
+            "-p" | "--protocol" if value.eq_ignore_ascii_case("all") => {}
+            "-p" | "--protocol" => match Proto::from_name(&value) {
+                Some(p) => {
+                    proto = Some(value.to_ascii_lowercase());
+                    rule.protocols.push(p);
+                }
+                None => return unsupported("unknown protocol"),
+            },
+            "-m" | "--match" => module = value,
+            "--sport" | "--source-port" | "--dport" | "--destination-port"
+                if matches!(module.as_str(), "" | "tcp" | "udp" | "sctp" | "dccp") =>
+            {
+                let is_src = option == "--sport" || option == "--source-port";
+                pending_ports.push((is_src, value));
+            }
+            "--sports" | "--source-ports" | "--dports" | "--destination-ports"
+                if module == "multiport" =>
+            {
+                let is_src = option == "--sports" || option == "--source-ports";
+                for port in value.split(',') {
+                    pending_ports.push((is_src, port.to_string()));
+                }
+            }
+            "--icmp-type" | "--icmpv6-type" if matches!(module.as_str(), "" | "icmp" | "icmp6") => {
+                let family = match option.as_str() {
+                    "--icmpv6-type" => Family::V6,
+                    _ => Family::V4,
+                };
+                match Icmp::from_name(family, &value) {
+                    Some(icmp) => rule.icmp.push(icmp),
+                    None if value == "any" => {}
+                    None => return unsupported("unknown icmp type"),
+                }
+            }
+            "--ctstate" | "--state" if module == "conntrack" || module == "state" => {
+                let mut states = Vec::new();
+                let mut nat = Vec::new();
+                for state in value.split(',') {
+                    match (CtState::from_name(state), CtStatus::from_name(state)) {
+                        (Some(state), _) => states.push(state),
+                        (None, Some(status @ (CtStatus::Snat | CtStatus::Dnat))) => {
+                            nat.push(status)
+                        }
+                        _ => return unsupported("unknown connection state"),
+                    }
+                }
+                // iptables matches any of them, the rule would need all to hold.
+                if !states.is_empty() && !nat.is_empty() {
+                    return unsupported("SNAT and DNAT mixed with other states");
+                }
+                rule.conntrack.state.extend(states);
+                rule.conntrack.status.extend(nat);
+            }
+            "--ctstatus" if module == "conntrack" => {
+                for status in value.split(',') {
+                    match CtStatus::from_name(status) {
+                        Some(status) => rule.conntrack.status.push(status),
+                        None => return unsupported("unknown connection status"),
+                    }
+                }
+            }
+            "--mark" if module == "connmark" => {
+                let (mark, mask) = value.split_once('/').unwrap_or((&value, "0xffffffff"));
+                match (parse_mark(mark), parse_mark(mask)) {
+                    (Some(mark), Some(u32::MAX)) => rule.conntrack.mark = Some(mark),
+                    (Some(_), Some(_)) => return unsupported("connmark masks are not supported"),
+                    _ => return unsupported("invalid mark"),
+                }
+            }
+            "--helper" if module == "helper" => rule.conntrack.helper = Some(value),
+            "--comment" if module == "comment" => {
+                match value.strip_prefix('[').and_then(|v| v.split_once(']')) {
+                    Some((name, rest)) => {
+                        rule.name = name.to_string();
+                        rule.comment = rest.trim().to_string();
+                    }
+                    None => rule.comment = value,
+                }
+            }
+            "--match-set" if module == "set" => match values.as_slice() {
+                [name, dir] if dir == "src" => rule.sets.0.push(name.clone()),
+                [name, dir] if dir == "dst" => rule.sets.1.push(name.clone()),
+                _ => return unsupported("only single src or dst set lookups are supported"),
+            },
+            "-j" | "--jump" => target = Some((value, Vec::new())),
+            "-g" | "--goto" => return unsupported("goto is not supported"),
+            "-i" | "--in-interface" | "-o" | "--out-interface" => {
+                // `eth+` matches every interface starting with `eth`.
+                let name = match value.strip_suffix('+') {
+                    Some(prefix) => format!("{}*", prefix),
+                    None => value.clone(),
+                };
+                if !is_interface(&name) {
+                    return unsupported("invalid interface name");
+                }
+                match option.as_str() {
+                    "-i" | "--in-interface" => rule.interfaces.0.push(name),
+                    _ => rule.interfaces.1.push(name),
+                }
+            }
+            _ if !module.is_empty() => {
+                return Err((
+                    format!("-m {} {} {}", module, option, value)
+                        .trim()
+                        .to_string(),
+                    "match has no equivalent",
+                ));
+            }
+            _ => return unsupported("unknown option"),
+        }
+    }
+    let number = PORT_PROTOCOLS
+        .iter()
+        .find(|(_, name)| proto.as_deref() == Some(*name))
+        .map_or(254, |(number, _)| *number);
+    for (is_src, value) in pending_ports {
+        // iptables-save prints ports as numbers, names only come from hand written files.
+        if !value.split(':').all(|n| n.parse::<u16>().is_ok()) {
+            return Err((value, "invalid port"));
+        }
+        let mut ports = match PortSet::parse(&value, number) {
+            Ok(ports) => ports,
+            Err(_) => return Err((value, "invalid port")),
+        };
+        match is_src {
+            true => rule.ports.0.append(&mut ports),
+            false => rule.ports.1.append(&mut ports),
+        }
+    }
+    if let Some((name, args)) = target {
+        rule.action = match parse_target(&name, &args, chains) {
+            Some(action) => action,
+            None => {
+                let args: Vec<String> = args.iter().map(|(o, v)| format!("{} {}", o, v)).collect();
+                return Err((
+                    format!("-j {} {}", name, args.join(" ")).trim().to_string(),
+                    "target has no equivalent",
+                ));
+            }
+        };
+    }
+    Ok(rule)
+}
+
+/// Parses an `iptables-save` (or `ip6tables-save` with [`Family::V6`]) file into tables.
+///
+/// Every iptables table becomes a table of the same name whose builtin chains are base chains
+/// with their policy. Lines and rules that cannot be represented are skipped and reported.
+pub fn parse(s: &str, family: Family) -> (Vec<Table>, Vec<Diagnostic>) {
+    let mut tables: Vec<Table> = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut current: Option<Table> = None;
+    for (i, raw) in s.lines().enumerate() {
+        let line = raw.trim();
+        let number = i + 1;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('*') {
+            if let Some(table) = current.take() {
+                diagnostics.push(Diagnostic::new(
+                    number,
+                    line,
+                    "table not committed before this one",
+                ));
+                tables.push(table);
+            }
+            current = Some(Table::new(family, name));
+            continue;
+        }
+        let Some(table) = current.as_mut() else {
+            diagnostics.push(Diagnostic::new(number, line, "line outside of a table"));
+            continue;
+        };
+        if line == "COMMIT" {
+            tables.extend(current.take());
+        } else if let Some(declaration) = line.strip_prefix(':') {
+            let mut parts = declaration.split_whitespace();
+            let name = parts.next().unwrap_or_default();
+            let policy = parts.next().unwrap_or("-");
+            let mut chain = Chain::new();
+            chain.set_name(name);
+            chain.set_base(base(&table.name, name, policy));
+            table.push_chain(chain);
+        } else if line.starts_with("-A ") || line.starts_with("--append ") {
+            let words = words(line);
+            let Some(index) = words
+                .get(1)
+                .and_then(|name| table.chains.iter().position(|c| &c.name == name))
+            else {
+                diagnostics.push(Diagnostic::new(
+                    number,
+                    line,
+                    "rule appended to an undeclared chain",
+                ));
+                continue;
+            };
+            match parse_rule(&words[2..], &table.chains) {
+                Ok(rule) => table.chains[index].push(rule),
+                Err((token, message)) => diagnostics.push(Diagnostic::new(number, &token, message)),
+            }
+        } else {
+            diagnostics.push(Diagnostic::new(number, line, "unexpected line"));
+        }
+    }
+    if let Some(table) = current {
+        diagnostics.push(Diagnostic::new(
+            s.lines().count(),
+            &format!("*{}", table.name),
+            "table is never committed",
+        ));
+        tables.push(table);
+    }
+    (tables, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{nft, simulate};
+
+    fn chains(s: &str) -> Vec<Chain> {
+        let ruleset = nft::parse(s).unwrap();
+        ruleset.tables()[0].chains().to_vec()
+    }
+
+    #[test]
+    fn stacked_base_chains_become_jumped_chains() {
+        let chains = chains(
+            "table inet f {
+              chain early { type filter hook input priority -10; policy drop;
+                tcp dport 22 accept }
+              chain late { type filter hook input priority 0; policy accept;
+                ip saddr 10.0.0.1 drop }
+            }",
+        );
+        let out = save(Family::V4, &chains);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "*filter",
+                ":INPUT ACCEPT [0:0]",
+                ":early - [0:0]",
+                ":late - [0:0]",
+                "-A INPUT -j early",
+                "-A INPUT -j late",
+                "-A early -p tcp --dport 22 -j RETURN",
+                "-A early -j DROP",
+                "-A late -s 10.0.0.1 -j DROP",
+                "COMMIT",
+            ]
+        );
+        assert!(unsupported(&chains).is_empty());
+    }
+
+    #[test]
+    fn single_base_chain_is_the_builtin_chain() {
+        let chains = chains(
+            "table ip f { chain input { type filter hook input priority 0; policy drop;
+              tcp dport 22 accept } }",
+        );
+        let out = save(Family::V4, &chains);
+        assert!(out.contains(":INPUT DROP [0:0]\n-A INPUT -p tcp --dport 22 -j ACCEPT\n"));
+    }
+
+    #[test]
+    fn accept_below_a_stacked_base_chain_is_reported() {
+        let chains = chains(
+            "table inet f {
+              chain early { type filter hook input priority -10; policy accept; jump ssh }
+              chain ssh { tcp dport 22 accept }
+              chain late { type filter hook input priority 0; policy drop; }
+            }",
+        );
+        let problems = unsupported(&chains);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].message,
+            "chain ssh: accept ends the INPUT chain in iptables and skips base chain late"
+        );
+    }
+
+    #[test]
+    fn rules_go_to_the_save_file_of_their_family() {
+        let chains = chains(
+            "table inet f {
+              chain input { type filter hook input priority 0; policy accept;
+                ip6 saddr 2001:db8::/32 drop
+                ip saddr 192.0.2.0/24 jump audit }
+              chain audit { log prefix \"audit \" }
+            }",
+        );
+        let v4 = save(Family::V4, &chains);
+        assert!(v4.contains(":audit - [0:0]\n"));
+        assert!(v4.contains("-A INPUT -s 192.0.2.0/24 -j audit\n"));
+        assert!(v4.contains("-A audit -j LOG --log-prefix \"audit \"\n"));
+        assert!(!v4.contains("2001:db8"));
+        let v6 = save(Family::V6, &chains);
+        assert!(v6.contains("-A INPUT -s 2001:db8::/32 -j DROP\n"));
+        assert!(!v6.contains("192.0.2.0"));
+    }
+
+    fn import(s: &str) -> Table {
+        let (tables, diagnostics) = parse(s, Family::V4);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        tables.into_iter().next().unwrap()
+    }
+
+    fn packet(protocol: u8, dport: u16) -> simulate::Packet {
+        let ip = |s| Ip::from_str(s).unwrap();
+        simulate::Packet::new(ip("192.0.2.1"), ip("192.0.2.2"), protocol, 40000, dport)
+    }
+
+    #[test]
+    fn imported_protocols_resolve_to_their_numbers() {
+        let table = import(
+            "*filter
+:INPUT DROP [0:0]
+-A INPUT -p tcp -m tcp --dport 22 -j ACCEPT
+-A INPUT -p udp -j ACCEPT
+-A INPUT -p 47 -j ACCEPT
+COMMIT
+",
+        );
+        let rules = table.chains()[0].get_rules();
+        let numbers: Vec<u8> = rules.iter().map(|r| r.protocols[0].get_number()).collect();
+        assert_eq!(numbers, [6, 17, 47]);
+        assert_eq!(table.simulate(&packet(6, 22)).verdict, Action::Accept);
+        assert_eq!(table.simulate(&packet(17, 53)).verdict, Action::Accept);
+        assert_eq!(table.simulate(&packet(47, 0)).verdict, Action::Accept);
+        assert_eq!(table.simulate(&packet(6, 80)).verdict, Action::Drop);
+    }
+
+    #[test]
+    fn protocol_all_matches_any_protocol() {
+        let table = import("*filter\n:INPUT DROP [0:0]\n-A INPUT -p all -j ACCEPT\nCOMMIT\n");
+        assert!(table.chains()[0].get_rules()[0].protocols.is_empty());
+        assert_eq!(table.simulate(&packet(132, 9)).verdict, Action::Accept);
+    }
+
+    #[test]
+    fn unknown_protocol_is_diagnosed() {
+        let (tables, diagnostics) = parse(
+            "*filter\n:INPUT ACCEPT [0:0]\n-A INPUT -p nosuch -j DROP\nCOMMIT\n",
+            Family::V4,
+        );
+        assert!(tables[0].chains()[0].get_rules().is_empty());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            (diagnostics[0].line, diagnostics[0].token.as_str()),
+            (3, "-p nosuch")
+        );
+        assert_eq!(diagnostics[0].message, "unknown protocol");
+    }
+
+    #[test]
+    fn save_and_import_round_trip() {
+        let text = "*filter
+:INPUT DROP [0:0]
+:ssh - [0:0]
+-A INPUT -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT
+-A INPUT -s 10.0.0.0/8 -p tcp -m tcp --dport 22 -j ssh
+-A INPUT -p udp -m multiport --dports 53,123 -j ACCEPT
+-A ssh -j LOG --log-prefix \"ssh \"
+-A ssh -j ACCEPT
+COMMIT
+";
+        let table = import(text);
+        let again = import(&save(Family::V4, table.chains()));
+        assert_eq!(again.chains(), table.chains());
+    }
+
+    #[test]
+    fn conntrack_matches_render_and_import() {
+        let chains = chains(
+            "table ip f { chain input { type filter hook input priority 0; policy drop;
+              ct state established,related accept
+              ct status assured ct mark 0x2a ct helper \"ftp\" accept
+              ct status dnat accept } }",
+        );
+        let out = save(Family::V4, &chains);
+        let lines: Vec<&str> = out.lines().filter(|l| l.starts_with("-A")).collect();
+        assert_eq!(
+            lines,
+            [
+                "-A INPUT -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "-A INPUT -m conntrack --ctstatus ASSURED -m connmark --mark 0x2a \
+                 -m helper --helper \"ftp\" -j ACCEPT",
+                "-A INPUT -m conntrack --ctstate DNAT -j ACCEPT",
+            ]
+        );
+        assert_eq!(import(&out).chains()[0].rules, chains[0].rules);
+    }
+
+    #[test]
+    fn conntrack_matches_iptables_cannot_express_are_reported() {
+        let chains = chains(
+            "table ip f { chain input { type filter hook input priority 0; policy drop;
+              ct zone 2 accept } }",
+        );
+        let problems = unsupported(&chains);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("ct zone 2"));
+        let (_, diagnostics) = parse(
+            "*filter\n:INPUT ACCEPT [0:0]\n-A INPUT -m state --state NEW,DNAT -j DROP\nCOMMIT\n",
+            Family::V4,
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "SNAT and DNAT mixed with other states"
+        );
+    }
+
+    #[test]
+    fn interfaces_render_one_line_per_pair() {
+        let chains = chains(
+            "table ip f { chain forward { type filter hook forward priority 0; policy drop;
+              iifname { \"wg0\", \"docker*\" } oifname \"eth0\" accept } }",
+        );
+        let out = save(Family::V4, &chains);
+        let lines: Vec<&str> = out.lines().filter(|l| l.starts_with("-A")).collect();
+        assert_eq!(
+            lines,
+            [
+                "-A FORWARD -i wg0 -o eth0 -j ACCEPT",
+                "-A FORWARD -i docker+ -o eth0 -j ACCEPT",
+            ]
+        );
+        let rules = import(&out).chains()[0].rules.clone();
+        assert_eq!(
+            rules[1].interfaces,
+            (vec!["docker*".to_string()], vec!["eth0".to_string()])
+        );
+    }
+}
@@ -0,0 +1,1785 @@
+use super::{
+    Action, Base, Chain, Conntrack, CtState, CtStatus, Family, Icmp, Ip, NamedMap, NamedSet,
+    PortRange, PortSet, Proto, Rule, Ruleset, Table, is_interface, parse_mark,
+};
+use crate::meta::Error;
+use std::{
+    fmt::{Display, Write},
+    str::FromStr,
+};
+
+/// Codes nft lists `icmp code` and `icmpv6 code` matches with by name.
+const ICMP_CODES: [(u8, &str); 8] = [
+    (0, "net-unreachable"),
+    (1, "host-unreachable"),
+    (2, "prot-unreachable"),
+    (3, "port-unreachable"),
+    (4, "frag-needed"),
+    (9, "net-prohibited"),
+    (10, "host-prohibited"),
+    (13, "admin-prohibited"),
+];
+const ICMPV6_CODES: [(u8, &str); 6] = [
+    (0, "no-route"),
+    (1, "admin-prohibited"),
+    (3, "addr-unreachable"),
+    (4, "port-unreachable"),
+    (5, "policy-fail"),
+    (6, "reject-route"),
+];
+
+/// Largest number of elements put in a single statement, keeps `nft -f` below its buffer limits.
+pub const ELEMENTS_PER_STATEMENT: usize = 1024;
+
+/// Default name of the table generated sets are declared in.
+pub const TABLE: &str = "torcher";
+
+/// Maps an address family to the nft table family keyword.
+pub fn family(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "ip",
+        Family::V6 => "ip6",
+        _ => "inet",
+    }
+}
+
+/// Maps an address family to the nft set element type.
+pub fn set_type(family: Family) -> &'static str {
+    match family {
+        Family::V6 => "ipv6_addr",
+        _ => "ipv4_addr",
+    }
+}
+
+/// Maps an address family to the keyword of its ICMP version.
+pub fn icmp_keyword(family: Family) -> &'static str {
+    match family {
+        Family::V6 => "icmpv6",
+        _ => "icmp",
+    }
+}
+
+/// Renders an nft script declaring `table inet <table>` with the interval set `<name>`.
+///
+/// The first `per_statement` elements go into the set declaration, the rest follow as
+/// `add element` statements of at most `per_statement` elements each.
+pub fn set<I, T>(table: &str, name: &str, fam: Family, elements: I, per_statement: usize) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: Display,
+{
+    let per_statement = per_statement.max(1);
+    let elements: Vec<String> = elements.into_iter().map(|e| e.to_string()).collect();
+    let mut batches = elements.chunks(per_statement);
+    let mut out = String::new();
+    let _ = writeln!(out, "table inet {} {{", table);
+    let _ = writeln!(out, "\tset {} {{", name);
+    let _ = writeln!(out, "\t\ttype {}", set_type(fam));
+    let _ = writeln!(out, "\t\tflags interval");
+    let _ = writeln!(out, "\t\tauto-merge");
+    if let Some(first) = batches.next() {
+        let _ = writeln!(out, "\t\telements = {{ {} }}", first.join(", "));
+    }
+    let _ = writeln!(out, "\t}}");
+    let _ = writeln!(out, "}}");
+    for batch in batches {
+        let _ = writeln!(
+            out,
+            "add element inet {} {} {{ {} }}",
+            table,
+            name,
+            batch.join(", ")
+        );
+    }
+    out
+}
+
+/// Protocols whose headers carry ports, by protocol number.
+pub const PORT_PROTOCOLS: [(u8, &str); 5] = [
+    (6, "tcp"),
+    (17, "udp"),
+    (33, "dccp"),
+    (132, "sctp"),
+    (136, "udplite"),
+];
+
+/// Formats one value as is and several as an anonymous set.
+fn list(values: &[String]) -> String {
+    match values {
+        [value] => value.clone(),
+        _ => format!("{{ {} }}", values.join(", ")),
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'"))
+}
+
+/// Name of a protocol as understood by `meta l4proto`.
+fn proto_name(proto: &Proto) -> String {
+    match proto.get_name().as_str() {
+        "unknown" => proto.get_number().to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Whether the protocol is ICMP or ICMPv6, by number or by name.
+fn is_icmp(proto: &Proto) -> bool {
+    matches!(proto.0, 1 | 58)
+        || matches!(proto.get_name().as_str(), "icmp" | "icmpv6" | "ipv6-icmp")
+}
+
+/// Name of the port-carrying protocol a port range belongs to, if it is one.
+fn port_proto(port: &PortRange) -> Option<&'static str> {
+    PORT_PROTOCOLS
+        .iter()
+        .find(|(number, _)| *number == port.proto)
+        .map(|(_, name)| *name)
+}
+
+/// Name of the chain `number` jumps to, `chain_<number>` when it is not in `chains`.
+fn chain_name(number: u32, chains: &[Chain]) -> String {
+    chains
+        .iter()
+        .find(|chain| chain.number == number && !chain.name.is_empty())
+        .map(|chain| chain.name.clone())
+        .unwrap_or_else(|| format!("chain_{}", number))
+}
+
+/// Renders the verdict or statement of an action, `None` when nft has no equivalent.
+///
+/// `family` is the `ip`/`ip6` keyword NAT and tproxy statements need inside inet tables.
+pub fn action(action: &Action, family: Option<&str>, chains: &[Chain]) -> Option<String> {
+    let family = family.map(|f| format!("{} ", f)).unwrap_or_default();
+    match action {
+        Action::None => Some(String::new()),
+        Action::Accept | Action::Drop | Action::Reject | Action::Return => Some(action.to_string()),
+        Action::Skip => Some("continue".to_string()),
+        Action::Limit(_) | Action::Log(_) | Action::Mark(_) | Action::Meta(_) => {
+            Some(action.to_string())
+        }
+        Action::Jump(number) => Some(format!("jump {}", chain_name(*number, chains))),
+        Action::Masquerade | Action::Queue | Action::Counter => Some(action.to_string()),
+        Action::Snat(addr) => Some(format!("snat {}to {}", family, addr)),
+        Action::Dnat(addr) => Some(format!("dnat {}to {}", family, addr)),
+        Action::Redirect(None) => Some("redirect".to_string()),
+        Action::Redirect(Some(port)) => Some(format!("redirect to :{}", port)),
+        Action::Quota(bytes) => Some(format!("quota {} bytes", bytes)),
+        Action::TProxy(port, 0) => Some(format!("tproxy {}to :{}", family, port)),
+        Action::TProxy(port, mark) => Some(format!(
+            "meta mark set {} tproxy {}to :{}",
+            mark, family, port
+        )),
+        Action::Set(_, _)
+        | Action::Ct
+        | Action::Hashlimit(_, _)
+        | Action::FlowOffload
+        | Action::Reclassify => None,
+    }
+}
+
+/// Port matches of a rule, one entry per protocol the ports belong to, each with the reason it
+/// never matches when the rule's protocols leave that protocol out.
+///
+/// Ports without a protocol of their own match the transport header of the rule's protocols,
+/// or of every protocol in [`PORT_PROTOCOLS`] when the rule names none.
+fn port_matches(rule: &Rule) -> Vec<(String, Option<String>)> {
+    let mut keys: Vec<(Option<&str>, &PortRange)> = Vec::new();
+    for port in rule.ports.0.iter().chain(rule.ports.1.iter()) {
+        let key = port_proto(port);
+        if !keys.iter().any(|(k, _)| *k == key) {
+            keys.push((key, port));
+        }
+    }
+    keys.iter()
+        .map(|&(key, first)| {
+            let (l4, header) = match key {
+                Some(name) => (None, name.to_string()),
+                None => {
+                    let protos: Vec<String> = rule.protocols.iter().map(proto_name).collect();
+                    match protos.as_slice() {
+                        [name] if PORT_PROTOCOLS.iter().any(|(_, p)| p == name) => {
+                            (None, name.clone())
+                        }
+                        [] => {
+                            let all: Vec<String> =
+                                PORT_PROTOCOLS.iter().map(|(_, n)| n.to_string()).collect();
+                            (Some(list(&all)), "th".into())
+                        }
+                        _ => (Some(list(&protos)), "th".to_string()),
+                    }
+                }
+            };
+            let mut parts: Vec<String> = l4
+                .map(|l4| vec![format!("meta l4proto {}", l4)])
+                .unwrap_or_default();
+            for (field, ports) in [("sport", &rule.ports.0), ("dport", &rule.ports.1)] {
+                let numbers: Vec<String> = ports
+                    .iter()
+                    .filter(|port| port_proto(port) == key)
+                    .map(|port| port.to_string())
+                    .collect();
+                if !numbers.is_empty() {
+                    parts.push(format!("{} {} {}", header, field, list(&numbers)));
+                }
+            }
+            (parts.join(" "), uncarried_ports(rule, first))
+        })
+        .collect()
+}
+
+/// Interface matches, e.g. `iifname { "eth0", "wg*" }`.
+fn interfaces(rule: &Rule) -> Vec<String> {
+    let mut parts = Vec::new();
+    for (field, names) in [
+        ("iifname", &rule.interfaces.0),
+        ("oifname", &rule.interfaces.1),
+    ] {
+        if !names.is_empty() {
+            let names: Vec<String> = names.iter().map(|n| quote(n)).collect();
+            parts.push(format!("{} {}", field, list(&names)));
+        }
+    }
+    parts
+}
+
+/// ICMP type matches of the rule a packet of `fam` can match, one entry per group that must
+/// be a statement of its own: `icmp type { echo-request, echo-reply }` for types without a
+/// code, `icmp type . icmp code { destination-unreachable . 3 }` for those with one.
+fn icmp_matches(rule: &Rule, fam: Family) -> Vec<String> {
+    let mut matches = Vec::new();
+    for family in [Family::V4, Family::V6] {
+        if fam != Family::Any && fam != family {
+            continue;
+        }
+        let keyword = icmp_keyword(family);
+        let name = |icmp: &Icmp| match icmp.name() {
+            Some(name) => name.to_string(),
+            None => icmp.kind.to_string(),
+        };
+        let (coded, plain): (Vec<&Icmp>, Vec<&Icmp>) = rule
+            .icmp
+            .iter()
+            .filter(|i| i.family == family)
+            .partition(|i| i.code.is_some());
+        if !plain.is_empty() {
+            let types: Vec<String> = plain.iter().map(|i| name(i)).collect();
+            matches.push(format!("{} type {}", keyword, list(&types)));
+        }
+        match coded.as_slice() {
+            [] => {}
+            [icmp] => matches.push(format!(
+                "{} type {} {} code {}",
+                keyword,
+                name(icmp),
+                keyword,
+                icmp.code.unwrap_or_default()
+            )),
+            _ => {
+                let pairs: Vec<String> = coded
+                    .iter()
+                    .map(|i| format!("{} . {}", name(i), i.code.unwrap_or_default()))
+                    .collect();
+                matches.push(format!(
+                    "{} type . {} code {{ {} }}",
+                    keyword,
+                    keyword,
+                    pairs.join(", ")
+                ));
+            }
+        }
+    }
+    matches
+}
+
+/// Connection tracking matches, e.g. `ct state { established, related }`.
+fn conntrack(ct: &Conntrack) -> Vec<String> {
+    let mut parts = Vec::new();
+    if !ct.state.is_empty() {
+        let states: Vec<String> = ct.state.iter().map(|s| s.to_string()).collect();
+        parts.push(format!("ct state {}", list(&states)));
+    }
+    if !ct.status.is_empty() {
+        let status: Vec<String> = ct.status.iter().map(|s| s.to_string()).collect();
+        parts.push(format!("ct status {}", list(&status)));
+    }
+    if let Some(zone) = ct.zone {
+        parts.push(format!("ct zone {}", zone));
+    }
+    if let Some(mark) = ct.mark {
+        parts.push(format!("ct mark {:#010x}", mark));
+    }
+    if let Some(helper) = &ct.helper {
+        parts.push(format!("ct helper {}", quote(helper)));
+    }
+    parts
+}
+
+/// Renders a rule as nft rule statements.
+///
+/// Rules matching addresses of both families, or ports of several protocols, become one
+/// statement per family and protocol. The rule name is kept in the comment as `[name]`.
+/// Rules whose action nft cannot express, whose ICMP types no packet of their family carries,
+/// or whose ports belong to a protocol the rule leaves out, are returned commented out.
+pub fn rule(rule: &Rule, chains: &[Chain]) -> Vec<String> {
+    // `any` and empty addresses do not restrict the match.
+    let concrete = |ips: &[Ip]| -> Vec<Ip> {
+        ips.iter()
+            .filter(|ip| matches!(ip, Ip::V4(_) | Ip::V6(_)))
+            .cloned()
+            .collect()
+    };
+    let (src, dst) = (concrete(&rule.ips.0), concrete(&rule.ips.1));
+    let has_addresses =
+        !src.is_empty() || !dst.is_empty() || !rule.sets.0.is_empty() || !rule.sets.1.is_empty();
+    let restricted = rule.family.is_v4() != rule.family.is_v6();
+    let mut families: Vec<(Option<&str>, Family)> = Vec::new();
+    for fam in [Family::V4, Family::V6] {
+        let usable = |ips: &[Ip], sets: &[String]| {
+            (ips.is_empty() && sets.is_empty())
+                || ips.iter().any(|ip| ip.family() == fam)
+                || sets.iter().any(|set| rule.set_family(set) == fam)
+        };
+        if (restricted && rule.family != fam)
+            || (has_addresses && (!usable(&src, &rule.sets.0) || !usable(&dst, &rule.sets.1)))
+        {
+            continue;
+        } else if !has_addresses && !restricted {
+            families.push((None, Family::Any));
+            break;
+        }
+        families.push((Some(family(fam)), fam));
+    }
+    let ports = port_matches(rule);
+    let mut comment = String::new();
+    if !rule.name.is_empty() {
+        comment = format!("[{}]", rule.name);
+    }
+    if !rule.comment.is_empty() {
+        if !comment.is_empty() {
+            comment.push(' ');
+        }
+        comment.push_str(&rule.comment);
+    }
+    let mut statements = Vec::new();
+    // Matches of the families no type of the rule is carried in.
+    let mut skipped = Vec::new();
+    for (keyword, fam) in families {
+        let mut parts = interfaces(rule);
+        match keyword {
+            Some(keyword) if has_addresses => {
+                for (field, ips, sets) in
+                    [("saddr", &src, &rule.sets.0), ("daddr", &dst, &rule.sets.1)]
+                {
+                    let addrs: Vec<String> = ips
+                        .iter()
+                        .filter(|ip| ip.family() == fam)
+                        .map(|ip| ip.to_string())
+                        .collect();
+                    if !addrs.is_empty() {
+                        parts.push(format!("{} {} {}", keyword, field, list(&addrs)));
+                    }
+                    for set in sets.iter().filter(|set| rule.set_family(set) == fam) {
+                        parts.push(format!("{} {} @{}", keyword, field, set));
+                    }
+                }
+            }
+            Some(_) if fam == Family::V4 => parts.push("meta nfproto ipv4".to_string()),
+            Some(_) => parts.push("meta nfproto ipv6".to_string()),
+            None => {}
+        }
+        // An ICMP type match implies its protocol.
+        let protos: Vec<String> = rule
+            .protocols
+            .iter()
+            .filter(|p| rule.icmp.is_empty() || !is_icmp(p))
+            .map(proto_name)
+            .collect();
+        if ports.is_empty() && !protos.is_empty() {
+            parts.push(format!("meta l4proto {}", list(&protos)));
+        }
+        parts.extend(conntrack(&rule.conntrack));
+        let verdict = action(&rule.action, keyword, chains);
+        let groups: Vec<Option<&(String, Option<String>)>> = if ports.is_empty() {
+            vec![None]
+        } else {
+            ports.iter().map(Some).collect()
+        };
+        let icmp = icmp_matches(rule, fam);
+        let kinds: Vec<Option<&String>> = match icmp.is_empty() {
+            true if !rule.icmp.is_empty() => {
+                skipped.push(parts.join(" "));
+                continue;
+            }
+            true => vec![None],
+            false => icmp.iter().map(Some).collect(),
+        };
+        for (kind, group) in kinds
+            .iter()
+            .flat_map(|kind| groups.iter().map(move |group| (kind, group)))
+        {
+            let mut statement = parts.clone();
+            statement.extend(kind.cloned());
+            statement.extend(group.map(|(ports, _)| ports.clone()));
+            match &verdict {
+                Some(verdict) if !verdict.is_empty() => statement.push(verdict.clone()),
+                _ => {}
+            }
+            if !comment.is_empty() {
+                statement.push(format!("comment {}", quote(&comment)));
+            }
+            let statement = statement.join(" ");
+            match (&verdict, group.and_then(|(_, stray)| stray.as_ref())) {
+                (Some(_), _) if statement.is_empty() => {}
+                (_, Some(stray)) => {
+                    statements.push(format!("# unsupported {}: {}", stray, statement))
+                }
+                (Some(_), None) => statements.push(statement),
+                (None, None) => statements.push(format!(
+                    "# unsupported action {:?}: {}",
+                    rule.action, statement
+                )),
+            }
+        }
+    }
+    if statements.is_empty() && !skipped.is_empty() {
+        let wrong = rule
+            .wrong_icmp(&rule.icmp)
+            .unwrap_or_else(|| "icmp types of another family".to_string());
+        statements.push(format!("# unsupported {}: {}", wrong, skipped.join("; ")));
+    }
+    statements
+}
+
+/// Renders a chain inside a table, led by its hook, priority and policy when it is a base chain.
+pub fn chain(chain: &Chain, chains: &[Chain]) -> String {
+    let name = match chain.name.as_str() {
+        "" => format!("chain_{}", chain.number),
+        name => name.to_string(),
+    };
+    let mut out = String::new();
+    let _ = writeln!(out, "\tchain {} {{", name);
+    if let Some(base) = &chain.base {
+        let _ = writeln!(out, "\t\t{}", base);
+    }
+    for rule in &chain.rules {
+        for statement in self::rule(rule, chains) {
+            let _ = writeln!(out, "\t\t{}", statement);
+        }
+    }
+    let _ = writeln!(out, "\t}}");
+    out
+}
+
+/// Renders chains as a complete `nft -f` script declaring `table inet <table>`.
+pub fn ruleset(table: &str, chains: &[Chain]) -> String {
+    let mut t = Table::new(Family::Any, table);
+    t.chains = chains.to_vec();
+    self::table(&t)
+}
+
+/// Renders a table with its sets, maps and chains as an `nft -f` script.
+pub fn table(table: &Table) -> String {
+    let mut blocks = Vec::new();
+    for set in &table.sets {
+        let mut out = String::new();
+        let _ = writeln!(out, "\tset {} {{", set.name);
+        let _ = writeln!(out, "\t\ttype {}", set.kind);
+        if !set.flags.is_empty() {
+            let _ = writeln!(out, "\t\tflags {}", set.flags.join(", "));
+        }
+        if !set.elements.is_empty() {
+            let _ = writeln!(out, "\t\telements = {{ {} }}", set.elements.join(", "));
+        }
+        let _ = writeln!(out, "\t}}");
+        blocks.push(out);
+    }
+    for map in &table.maps {
+        let mut out = String::new();
+        let _ = writeln!(out, "\tmap {} {{", map.name);
+        let _ = writeln!(out, "\t\ttype {} : {}", map.key, map.value);
+        if !map.flags.is_empty() {
+            let _ = writeln!(out, "\t\tflags {}", map.flags.join(", "));
+        }
+        if !map.elements.is_empty() {
+            let elements: Vec<String> = map
+                .elements
+                .iter()
+                .map(|(key, value)| format!("{} : {}", key, value))
+                .collect();
+            let _ = writeln!(out, "\t\telements = {{ {} }}", elements.join(", "));
+        }
+        let _ = writeln!(out, "\t}}");
+        blocks.push(out);
+    }
+    for c in &table.chains {
+        blocks.push(chain(c, &table.chains));
+    }
+    format!(
+        "table {} {} {{\n{}}}\n",
+        family(table.family),
+        table.name,
+        blocks.join("\n")
+    )
+}
+
+/// Maps an nft table family keyword back to an address family.
+pub fn parse_family(keyword: &str) -> Option<Family> {
+    match keyword {
+        "ip" => Some(Family::V4),
+        "ip6" => Some(Family::V6),
+        "inet" => Some(Family::Any),
+        _ => None,
+    }
+}
+
+/// A word, quoted string or punctuation of an nft script and where it starts.
+///
+/// Punctuation tokens are `{`, `}`, `;`, `,` and `\n`, which ends a statement like `;` does.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub quoted: bool,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    fn is(&self, text: &str) -> bool {
+        !self.quoted && self.text == text
+    }
+}
+
+/// Splits an nft script into tokens, dropping `#` comments such as `# handle 4`.
+pub fn tokenize(s: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let mut chars = line.char_indices().peekable();
+        while let Some((column, c)) = chars.next() {
+            let mut token = |text: String, quoted: bool| {
+                tokens.push(Token {
+                    text,
+                    quoted,
+                    line: i + 1,
+                    column: column + 1,
+                })
+            };
+            match c {
+                '#' => break,
+                c if c.is_whitespace() => {}
+                '{' | '}' | ';' | ',' => token(c.to_string(), false),
+                '"' => {
+                    let mut text = String::new();
+                    let mut closed = false;
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => text.extend(chars.next().map(|(_, c)| c)),
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            c => text.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err(Error {
+                            line: i + 1,
+                            column: column + 1,
+                            token: "\"".to_string(),
+                            snippet: line.to_string(),
+                            ..Error::new("unterminated string".to_string(), 2)
+                        });
+                    }
+                    token(text, true);
+                }
+                c => {
+                    let mut text = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if c.is_whitespace() || "{};,\"#".contains(c) {
+                            break;
+                        }
+                        text.push(c);
+                        chars.next();
+                    }
+                    token(text, false);
+                }
+            }
+        }
+        tokens.push(Token {
+            text: "\n".to_string(),
+            quoted: false,
+            line: i + 1,
+            column: line.len() + 1,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens of an nft script.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn at(&self, text: &str) -> bool {
+        self.peek().is_some_and(|t| t.is(text))
+    }
+    fn eat(&mut self, text: &str) -> bool {
+        let found = self.at(text);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+    fn skip_separators(&mut self) {
+        while self.at("\n") || self.at(";") {
+            self.pos += 1;
+        }
+    }
+    /// Error at the current token, naming the token found.
+    fn error(&self, message: &str) -> Error {
+        match self.peek() {
+            Some(token) => {
+                let found = match token.text.as_str() {
+                    "\n" => "end of line".to_string(),
+                    text => format!("'{}'", text),
+                };
+                error_at(token, &format!("{}, found {}", message, found))
+            }
+            None => Error::new(format!("unexpected end of input: {}", message), 2),
+        }
+    }
+    fn expect(&mut self, text: &str) -> Result<Token, Error> {
+        match self.at(text) {
+            true => Ok(self.next().unwrap()),
+            false => Err(self.error(&format!("expected '{}'", text))),
+        }
+    }
+    /// A word or quoted string, `what` names it in the error.
+    fn word(&mut self, what: &str) -> Result<String, Error> {
+        match self.peek() {
+            Some(t) if t.quoted || !matches!(t.text.as_str(), "{" | "}" | ";" | "," | "\n") => {
+                Ok(self.next().unwrap().text)
+            }
+            _ => Err(self.error(&format!("expected {}", what))),
+        }
+    }
+    /// Tokens up to the end of the statement, keeping anonymous sets `{ ... }` whole.
+    fn statement(&mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            match token.text.as_str() {
+                _ if token.quoted => {}
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                "\n" if depth > 0 => {
+                    self.pos += 1;
+                    continue;
+                }
+                "\n" | ";" if depth == 0 => break,
+                _ => {}
+            }
+            tokens.push(self.next().unwrap());
+        }
+        tokens
+    }
+    /// A single value, `a, b` or an anonymous set `{ a, b }`.
+    fn values(&mut self, what: &str) -> Result<Vec<String>, Error> {
+        let braced = self.eat("{");
+        let mut values = vec![self.word(what)?];
+        while self.eat(",") {
+            if braced && self.at("}") {
+                break;
+            }
+            values.push(self.word(what)?);
+        }
+        if braced {
+            self.expect("}")?;
+        }
+        Ok(values)
+    }
+    /// Elements of `elements = { ... }`, each the words up to the next comma.
+    fn elements(&mut self) -> Result<Vec<Vec<String>>, Error> {
+        self.expect("{")?;
+        let mut elements = Vec::new();
+        let mut element = Vec::new();
+        loop {
+            while self.eat("\n") {}
+            match self.peek() {
+                Some(t) if t.is("}") || t.is(",") => {
+                    if !element.is_empty() {
+                        elements.push(std::mem::take(&mut element));
+                    }
+                    if self.next().unwrap().is("}") {
+                        return Ok(elements);
+                    }
+                }
+                _ => element.push(self.word("a set element")?),
+            }
+        }
+    }
+    fn table(&mut self) -> Result<Table, Error> {
+        self.expect("table")?;
+        let keyword = self.peek().cloned();
+        let family = parse_family(&self.word("a table family")?).ok_or_else(|| {
+            error_at(
+                keyword.as_ref().unwrap(),
+                "expected ip, ip6 or inet table family",
+            )
+        })?;
+        let mut table = Table::new(family, &self.word("a table name")?);
+        self.expect("{")?;
+        let mut bodies: Vec<Vec<Vec<Token>>> = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => return Err(self.error("expected '}'")),
+                Some(t) if t.is("}") => break,
+                Some(t) if t.is("chain") => {
+                    let (mut chain, body) = self.chain()?;
+                    chain.number = table.chains.len() as u32 + 1;
+                    table.chains.push(chain);
+                    bodies.push(body);
+                }
+                Some(t) if t.is("set") => {
+                    let set = self.set()?;
+                    table.sets.push(set);
+                }
+                Some(t) if t.is("map") => {
+                    let map = self.map()?;
+                    table.maps.push(map);
+                }
+                Some(t) if t.is("flags") || t.is("comment") => {
+                    self.statement();
+                }
+                _ => return Err(self.error("expected chain, set, map or '}'")),
+            }
+        }
+        self.expect("}")?;
+        let names: Vec<(String, u32)> = table
+            .chains
+            .iter()
+            .map(|c| (c.name.clone(), c.number))
+            .collect();
+        for (chain, body) in table.chains.iter_mut().zip(bodies) {
+            for statement in body {
+                chain.rules.push(Parser::new(statement).rule(&names)?);
+            }
+        }
+        Ok(table)
+    }
+    /// A chain with its base chain header and the unparsed statements of its rules, which
+    /// may jump to chains declared later in the table.
+    fn chain(&mut self) -> Result<(Chain, Vec<Vec<Token>>), Error> {
+        self.expect("chain")?;
+        let mut chain = Chain::new();
+        chain.name = self.word("a chain name")?;
+        self.expect("{")?;
+        let mut body = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => return Err(self.error("expected '}'")),
+                Some(t) if t.is("}") => break,
+                Some(t) if t.is("type") => {
+                    let mut header = self.statement();
+                    // `type ...; policy ...;` is one header split by a separator.
+                    if self.eat(";") && self.at("policy") {
+                        header.push(self.tokens[self.pos - 1].clone());
+                        header.extend(self.statement());
+                    }
+                    let text: Vec<&str> = header.iter().map(|t| t.text.as_str()).collect();
+                    chain.base = Some(Base::from_str(&text.join(" ")).map_err(|e| {
+                        // Points at the header token the error names, or at `type`.
+                        let token = header.iter().find(|t| t.text == e.token);
+                        error_at(token.unwrap_or(&header[0]), &e.message)
+                    })?);
+                }
+                Some(t) if t.is("policy") => {
+                    let start = self.next().unwrap();
+                    let policy = match self.word("accept or drop")?.as_str() {
+                        "accept" => Action::Accept,
+                        "drop" => Action::Drop,
+                        _ => return Err(error_at(&start, "policy must be accept or drop")),
+                    };
+                    match chain.base.as_mut() {
+                        Some(base) => base.set_policy(policy),
+                        None => return Err(error_at(&start, "policy on a regular chain")),
+                    }
+                }
+                Some(t) if t.is("comment") => {
+                    self.statement();
+                }
+                _ => body.push(self.statement()),
+            }
+        }
+        self.expect("}")?;
+        Ok((chain, body))
+    }
+    fn set(&mut self) -> Result<NamedSet, Error> {
+        self.expect("set")?;
+        let mut set = NamedSet::new(&self.word("a set name")?, "");
+        self.expect("{")?;
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => return Err(self.error("expected '}'")),
+                Some(t) if t.is("}") => break,
+                Some(t) if t.is("type") => {
+                    self.next();
+                    let kind: Vec<String> = self.statement().into_iter().map(|t| t.text).collect();
+                    set.kind = kind.join(" ");
+                }
+                Some(t) if t.is("flags") => {
+                    self.next();
+                    set.flags = self.values("a set flag")?;
+                }
+                Some(t) if t.is("elements") => {
+                    self.next();
+                    self.expect("=")?;
+                    set.elements = self.elements()?.iter().map(|e| e.join(" ")).collect();
+                }
+                // Properties such as auto-merge, size or timeout do not change what matches.
+                _ => {
+                    self.statement();
+                }
+            }
+        }
+        self.expect("}")?;
+        Ok(set)
+    }
+    fn map(&mut self) -> Result<NamedMap, Error> {
+        self.expect("map")?;
+        let mut map = NamedMap::new(&self.word("a map name")?, "", "");
+        self.expect("{")?;
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => return Err(self.error("expected '}'")),
+                Some(t) if t.is("}") => break,
+                Some(t) if t.is("type") => {
+                    let start = self.next().unwrap();
+                    let kind: Vec<String> = self.statement().into_iter().map(|t| t.text).collect();
+                    let kind = kind.join(" ");
+                    let (key, value) = kind
+                        .split_once(" : ")
+                        .ok_or_else(|| error_at(&start, "expected 'type <key> : <value>'"))?;
+                    map.key = key.to_string();
+                    map.value = value.to_string();
+                }
+                Some(t) if t.is("flags") => {
+                    self.next();
+                    map.flags = self.values("a map flag")?;
+                }
+                Some(t) if t.is("elements") => {
+                    let start = self.next().unwrap();
+                    self.expect("=")?;
+                    for element in self.elements()? {
+                        let element = element.join(" ");
+                        let (key, value) = element
+                            .split_once(" : ")
+                            .ok_or_else(|| error_at(&start, "expected '<key> : <value>'"))?;
+                        map.insert(key, value);
+                    }
+                }
+                _ => {
+                    self.statement();
+                }
+            }
+        }
+        self.expect("}")?;
+        Ok(map)
+    }
+    /// `add element <family> <table> <set> { ... }`, as [`set`] emits for large sets.
+    fn add_element(&mut self, ruleset: &mut Ruleset) -> Result<(), Error> {
+        self.expect("add")?;
+        self.expect("element")?;
+        let start = self.peek().cloned();
+        let family = parse_family(&self.word("a table family")?);
+        let table = self.word("a table name")?;
+        let name = self.word("a set name")?;
+        let elements = self.elements()?;
+        let set = ruleset
+            .tables
+            .iter_mut()
+            .filter(|t| Some(t.family) == family && t.name == table)
+            .flat_map(|t| t.sets.iter_mut())
+            .find(|s| s.name == name)
+            .ok_or_else(|| error_at(start.as_ref().unwrap(), "element added to an unknown set"))?;
+        set.elements.extend(elements.iter().map(|e| e.join(" ")));
+        Ok(())
+    }
+    /// Parses the statement of one rule. `names` resolves jump targets to chain numbers.
+    fn rule(&mut self, names: &[(String, u32)]) -> Result<Rule, Error> {
+        // Unrestricted until `meta nfproto` says otherwise.
+        let mut rule = Rule {
+            family: Family::Any,
+            ..Rule::default()
+        };
+        let mut counter = false;
+        while let Some(token) = self.next() {
+            if token.quoted {
+                return Err(error_at(&token, "unexpected string"));
+            }
+            let mut action = None;
+            match token.text.as_str() {
+                "ip" | "ip6" => {
+                    let field = self.word("saddr, daddr or protocol")?;
+                    match field.as_str() {
+                        "saddr" | "daddr" => {
+                            let start = self.peek().cloned().unwrap_or(token.clone());
+                            for value in self.values("an address")? {
+                                let (ips, sets) = match field.as_str() {
+                                    "saddr" => (&mut rule.ips.0, &mut rule.sets.0),
+                                    _ => (&mut rule.ips.1, &mut rule.sets.1),
+                                };
+                                match value.strip_prefix('@') {
+                                    Some(set) => {
+                                        sets.push(set.to_string());
+                                        // Keeps the lookup on the family its keyword names.
+                                        let family = match token.text.as_str() {
+                                            "ip" => Family::V4,
+                                            _ => Family::V6,
+                                        };
+                                        if rule.set_family(set) != family {
+                                            rule.family = family;
+                                        }
+                                    }
+                                    None => ips.push(Ip::from_str(&value).map_err(|_| {
+                                        error_at(&start, &format!("invalid address '{}'", value))
+                                    })?),
+                                }
+                            }
+                        }
+                        "protocol" | "nexthdr" => self.protocols(&token, &mut rule)?,
+                        _ => {
+                            return Err(error_at(
+                                &token,
+                                &format!("unsupported match '{} {}'", token.text, field),
+                            ));
+                        }
+                    }
+                }
+                "meta" => match self.word("nfproto, l4proto or mark")?.as_str() {
+                    "nfproto" => {
+                        rule.family = match self.word("ipv4 or ipv6")?.as_str() {
+                            "ipv4" => Family::V4,
+                            "ipv6" => Family::V6,
+                            _ => {
+                                return Err(error_at(&token, "expected meta nfproto ipv4 or ipv6"));
+                            }
+                        }
+                    }
+                    "l4proto" => self.protocols(&token, &mut rule)?,
+                    "mark" => {
+                        self.expect("set")?;
+                        action = Some(Action::Mark(self.mark(&token)?));
+                    }
+                    other => {
+                        return Err(error_at(
+                            &token,
+                            &format!("unsupported match 'meta {}'", other),
+                        ));
+                    }
+                },
+                "tcp" | "udp" | "sctp" | "dccp" | "udplite" | "th" => {
+                    let field = self.word("sport or dport")?;
+                    if field != "sport" && field != "dport" {
+                        return Err(error_at(
+                            &token,
+                            &format!("unsupported match '{} {}'", token.text, field),
+                        ));
+                    }
+                    let proto = PORT_PROTOCOLS
+                        .iter()
+                        .find(|(_, name)| *name == token.text)
+                        .map(|(number, _)| *number)
+                        .unwrap_or(254);
+                    // nft reads the headers as a conjunction, the rule's ports as a disjunction.
+                    let other = rule
+                        .ports
+                        .0
+                        .iter()
+                        .chain(&rule.ports.1)
+                        .find(|range| range.proto != 254 && range.proto != proto);
+                    if let Some(other) = other
+                        && proto != 254
+                    {
+                        return Err(error_at(
+                            &token,
+                            &format!(
+                                "rule matches both {} and {} ports, no packet carries both",
+                                port_proto(other).unwrap_or("th"),
+                                token.text
+                            ),
+                        ));
+                    }
+                    for value in self.values("a port")? {
+                        let mut ports = PortSet::parse(&value, proto)
+                            .map_err(|_| error_at(&token, &format!("invalid port '{}'", value)))?;
+                        match field.as_str() {
+                            "sport" => rule.ports.0.append(&mut ports),
+                            _ => rule.ports.1.append(&mut ports),
+                        }
+                    }
+                    if let Some(problem) = uncarried(&rule) {
+                        return Err(error_at(&token, &problem));
+                    }
+                }
+                "icmp" | "icmpv6" => {
+                    let family = match token.text.as_str() {
+                        "icmpv6" => Family::V6,
+                        _ => Family::V4,
+                    };
+                    match self.word("type or code")?.as_str() {
+                        // `icmp type . icmp code { destination-unreachable . port-unreachable }`
+                        "type" if self.eat(".") => {
+                            self.expect(&token.text)?;
+                            self.expect("code")?;
+                            for element in self.elements()? {
+                                let [kind, dot, code] = element.as_slice() else {
+                                    return Err(error_at(&token, "expected '<type> . <code>'"));
+                                };
+                                if dot != "." {
+                                    return Err(error_at(&token, "expected '<type> . <code>'"));
+                                }
+                                let mut icmp = self.icmp(&token, family, kind)?;
+                                icmp.code = Some(self.icmp_code(&token, family, code)?);
+                                rule.icmp.push(icmp);
+                            }
+                        }
+                        "type" => {
+                            for value in self.values("an icmp type")? {
+                                rule.icmp.push(self.icmp(&token, family, &value)?);
+                            }
+                        }
+                        // Narrows the types matched before it, `icmp type x icmp code 3`.
+                        "code" => {
+                            let word = self.word("an icmp code")?;
+                            let code = self.icmp_code(&token, family, &word)?;
+                            let types: Vec<&mut Icmp> = rule
+                                .icmp
+                                .iter_mut()
+                                .filter(|i| i.family == family && i.code.is_none())
+                                .collect();
+                            if types.is_empty() {
+                                return Err(error_at(
+                                    &token,
+                                    &format!(
+                                        "unsupported match '{} code' without a type",
+                                        token.text
+                                    ),
+                                ));
+                            }
+                            for icmp in types {
+                                icmp.code = Some(code);
+                            }
+                        }
+                        other => {
+                            return Err(error_at(
+                                &token,
+                                &format!("unsupported match '{} {}'", token.text, other),
+                            ));
+                        }
+                    }
+                }
+                "iifname" | "oifname" => {
+                    for value in self.values("an interface name")? {
+                        if value.starts_with('@') {
+                            return Err(error_at(
+                                &token,
+                                &format!("unsupported match '{} {}'", token.text, value),
+                            ));
+                        } else if !is_interface(&value) {
+                            return Err(error_at(
+                                &token,
+                                &format!("invalid interface name '{}'", value),
+                            ));
+                        }
+                        match token.text.as_str() {
+                            "iifname" => rule.interfaces.0.push(value),
+                            _ => rule.interfaces.1.push(value),
+                        }
+                    }
+                }
+                "ct" => match self.word("state, status, zone, mark or helper")?.as_str() {
+                    "state" => {
+                        for value in self.values("a connection state")? {
+                            let state = CtState::from_name(&value).ok_or_else(|| {
+                                error_at(&token, &format!("unknown ct state '{}'", value))
+                            })?;
+                            rule.conntrack.state.push(state);
+                        }
+                    }
+                    "status" => {
+                        for value in self.values("a connection status")? {
+                            let status = CtStatus::from_name(&value).ok_or_else(|| {
+                                error_at(&token, &format!("unknown ct status '{}'", value))
+                            })?;
+                            rule.conntrack.status.push(status);
+                        }
+                    }
+                    "zone" => rule.conntrack.zone = Some(self.number(&token)?),
+                    "mark" if self.at("set") => {
+                        return Err(error_at(&token, "unsupported statement 'ct mark set'"));
+                    }
+                    "mark" => rule.conntrack.mark = Some(self.mark(&token)?),
+                    "helper" => rule.conntrack.helper = Some(self.word("a helper name")?),
+                    other => {
+                        return Err(error_at(
+                            &token,
+                            &format!("unsupported match 'ct {}'", other),
+                        ));
+                    }
+                },
+                "counter" => {
+                    counter = true;
+                    while self.eat("packets") || self.eat("bytes") {
+                        self.number::<u64>(&token)?;
+                    }
+                }
+                "accept" => action = Some(Action::Accept),
+                "drop" => action = Some(Action::Drop),
+                "reject" => action = Some(Action::Reject),
+                "return" => action = Some(Action::Return),
+                "continue" => action = Some(Action::Skip),
+                "queue" => action = Some(Action::Queue),
+                "masquerade" => action = Some(Action::Masquerade),
+                "jump" => {
+                    let name = self.word("a chain name")?;
+                    let number = names
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .map(|(_, number)| *number)
+                        .or_else(|| name.strip_prefix("chain_")?.parse().ok())
+                        .ok_or_else(|| {
+                            error_at(&token, &format!("jump to unknown chain '{}'", name))
+                        })?;
+                    action = Some(Action::Jump(number));
+                }
+                "snat" | "dnat" => {
+                    let _ = self.eat("ip") || self.eat("ip6");
+                    self.expect("to")?;
+                    let addr = self.word("an address")?;
+                    action = Some(match token.text.as_str() {
+                        "snat" => Action::Snat(addr),
+                        _ => Action::Dnat(addr),
+                    });
+                }
+                "redirect" => {
+                    action = Some(match self.eat("to") {
+                        true => Action::Redirect(Some(self.port(&token)?)),
+                        false => Action::Redirect(None),
+                    });
+                }
+                "tproxy" => {
+                    let _ = self.eat("ip") || self.eat("ip6");
+                    self.expect("to")?;
+                    let port = self.port(&token)?;
+                    // The mark is set by a `meta mark set` just before.
+                    let mark = match rule.action {
+                        Action::Mark(mark) => {
+                            rule.action = Action::None;
+                            mark
+                        }
+                        _ => 0,
+                    };
+                    action = Some(Action::TProxy(port, mark));
+                }
+                "log" => {
+                    let prefix = match self.eat("prefix") {
+                        true => self.word("a log prefix")?,
+                        false => String::new(),
+                    };
+                    action = Some(Action::Log(prefix));
+                }
+                "limit" => {
+                    self.expect("rate")?;
+                    self.expect("over")?;
+                    let rate = self.word("a rate")?;
+                    let rate = rate
+                        .strip_suffix("/second")
+                        .and_then(|r| r.parse().ok())
+                        .ok_or_else(|| error_at(&token, &format!("unsupported rate '{}'", rate)))?;
+                    self.eat("drop");
+                    action = Some(Action::Limit(rate));
+                }
+                "quota" => {
+                    let bytes = self.word("a byte count")?;
+                    let bytes = bytes
+                        .parse()
+                        .map_err(|_| error_at(&token, &format!("invalid quota '{}'", bytes)))?;
+                    self.eat("bytes");
+                    action = Some(Action::Quota(bytes));
+                }
+                "comment" => {
+                    let comment = self.word("a comment")?;
+                    match comment.strip_prefix('[').and_then(|c| c.split_once(']')) {
+                        Some((name, rest)) => {
+                            rule.name = name.to_string();
+                            rule.comment = rest.trim().to_string();
+                        }
+                        None => rule.comment = comment,
+                    }
+                }
+                other => {
+                    return Err(error_at(
+                        &token,
+                        &format!("unsupported expression '{}'", other),
+                    ));
+                }
+            }
+            // Addresses after the types restrict the family as much as `meta nfproto` before.
+            if let Some(wrong) = rule.wrong_icmp(&rule.icmp) {
+                return Err(error_at(&token, &wrong));
+            }
+            if let Some(action) = action {
+                if rule.action != Action::None {
+                    return Err(error_at(
+                        &token,
+                        "rule has more than one verdict or statement",
+                    ));
+                }
+                rule.action = action;
+            }
+        }
+        if counter && rule.action == Action::None {
+            rule.action = Action::Counter;
+        }
+        Ok(rule)
+    }
+    fn proto(&self, token: &Token, name: &str) -> Result<Proto, Error> {
+        Proto::from_name(name)
+            .ok_or_else(|| error_at(token, &format!("unknown protocol '{}'", name)))
+    }
+    /// The protocols of `meta l4proto` or `ip protocol`. A rule lists the protocols of one
+    /// such match, a second one would turn the conjunction nft reads into a disjunction.
+    fn protocols(&mut self, token: &Token, rule: &mut Rule) -> Result<(), Error> {
+        if !rule.protocols.is_empty() {
+            return Err(error_at(token, "rule matches the protocol twice"));
+        }
+        for value in self.values("a protocol")? {
+            rule.protocols.push(self.proto(token, &value)?);
+        }
+        match uncarried(rule) {
+            Some(problem) => Err(error_at(token, &problem)),
+            None => Ok(()),
+        }
+    }
+    fn icmp(&self, token: &Token, family: Family, name: &str) -> Result<Icmp, Error> {
+        Icmp::from_name(family, name).ok_or_else(|| {
+            let keyword = icmp_keyword(family);
+            error_at(token, &format!("unknown {} type '{}'", keyword, name))
+        })
+    }
+    /// An ICMP code, by number or by the name nft lists it with.
+    fn icmp_code(&self, token: &Token, family: Family, name: &str) -> Result<u8, Error> {
+        let codes: &[(u8, &str)] = match family {
+            Family::V6 => &ICMPV6_CODES,
+            _ => &ICMP_CODES,
+        };
+        match codes.iter().find(|(_, n)| *n == name) {
+            Some((code, _)) => Ok(*code),
+            None => name
+                .parse()
+                .map_err(|_| error_at(token, &format!("unknown icmp code '{}'", name))),
+        }
+    }
+    fn number<T: FromStr>(&mut self, token: &Token) -> Result<T, Error> {
+        let word = self.word("a number")?;
+        word.parse()
+            .map_err(|_| error_at(token, &format!("invalid number '{}'", word)))
+    }
+    /// A mark, nft lists them in hex.
+    fn mark(&mut self, token: &Token) -> Result<u32, Error> {
+        let word = self.word("a mark")?;
+        parse_mark(&word).ok_or_else(|| error_at(token, &format!("invalid mark '{}'", word)))
+    }
+    /// A port written as `:80`.
+    fn port(&mut self, token: &Token) -> Result<u16, Error> {
+        let word = self.word("a port")?;
+        word.trim_start_matches(':')
+            .parse()
+            .map_err(|_| error_at(token, &format!("invalid port '{}'", word)))
+    }
+}
+
+/// Describes ports of a protocol header the rule's protocols rule out, e.g. `udp dport` in a
+/// `meta l4proto tcp` rule, which never match.
+fn uncarried(rule: &Rule) -> Option<String> {
+    rule.ports
+        .0
+        .iter()
+        .chain(&rule.ports.1)
+        .find_map(|range| uncarried_ports(rule, range))
+}
+
+/// Like [`uncarried`], for the ports of the protocol `range` belongs to.
+fn uncarried_ports(rule: &Rule, range: &PortRange) -> Option<String> {
+    if rule.protocols.is_empty()
+        || range.proto == 254
+        || rule.protocols.iter().any(|p| p.0 == range.proto)
+    {
+        return None;
+    }
+    let protos: Vec<String> = rule.protocols.iter().map(proto_name).collect();
+    Some(format!(
+        "{} ports in a rule matching only protocol {}",
+        port_proto(range).unwrap_or("th"),
+        protos.join(", ")
+    ))
+}
+
+/// An error placed at `token`; the line's text is added by [`in_source`].
+fn error_at(token: &Token, message: &str) -> Error {
+    Error {
+        line: token.line,
+        column: token.column,
+        token: match token.text.as_str() {
+            "\n" => String::new(),
+            text => text.to_string(),
+        },
+        ..Error::new(message.to_string(), 2)
+    }
+}
+
+fn in_source(e: Error, s: &str) -> Error {
+    match e.line.checked_sub(1).and_then(|i| s.lines().nth(i)) {
+        Some(line) if e.snippet.is_empty() => Error {
+            snippet: line.to_string(),
+            ..e
+        },
+        _ => e,
+    }
+}
+
+/// Parses a single rule statement, e.g. `ip saddr 10.0.0.0/8 tcp dport 22 accept`. Jumps
+/// refer to `chains` by name.
+pub fn parse_rule(s: &str, chains: &[Chain]) -> Result<Rule, Error> {
+    let tokens = tokenize(s)?.into_iter().filter(|t| !t.is("\n")).collect();
+    let names: Vec<(String, u32)> = chains.iter().map(|c| (c.name.clone(), c.number)).collect();
+    Parser::new(tokens)
+        .rule(&names)
+        .map_err(|e| in_source(e, s))
+}
+
+/// Parses a single `chain <name> { ... }` block as [`chain`] renders it. Jumps refer to
+/// `chains` by name.
+pub fn parse_chain(s: &str, chains: &[Chain]) -> Result<Chain, Error> {
+    block(s, chains).map_err(|e| in_source(e, s))
+}
+
+fn block(s: &str, chains: &[Chain]) -> Result<Chain, Error> {
+    let mut parser = Parser::new(tokenize(s)?);
+    parser.skip_separators();
+    let (mut chain, body) = parser.chain()?;
+    parser.skip_separators();
+    if parser.peek().is_some() {
+        return Err(parser.error("expected end of input"));
+    }
+    let mut names: Vec<(String, u32)> = chains.iter().map(|c| (c.name.clone(), c.number)).collect();
+    names.push((chain.name.clone(), chain.number));
+    for statement in body {
+        chain.rules.push(Parser::new(statement).rule(&names)?);
+    }
+    Ok(chain)
+}
+
+/// Parses an nft script such as the output of `nft list ruleset` or of [`table`].
+///
+/// Handles, counter values and set properties that do not change what matches are dropped.
+/// Rules are kept in the order they are listed; jumps are resolved to chain numbers.
+pub fn parse(s: &str) -> Result<Ruleset, Error> {
+    script(s).map_err(|e| in_source(e, s))
+}
+
+fn script(s: &str) -> Result<Ruleset, Error> {
+    let mut parser = Parser::new(tokenize(s)?);
+    let mut ruleset = Ruleset::new();
+    loop {
+        parser.skip_separators();
+        match parser.peek() {
+            None => return Ok(ruleset),
+            Some(t) if t.is("table") => {
+                let table = parser.table()?;
+                ruleset.push(table);
+            }
+            Some(t) if t.is("add") => parser.add_element(&mut ruleset)?,
+            Some(t) if t.is("flush") => {
+                parser.statement();
+            }
+            _ => return Err(parser.error("expected table")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{Direction, Port, simulate::Packet, v4};
+
+    const LIVE: &str = "table inet filter { # handle 1
+	set blocklist { # handle 3
+		type ipv4_addr
+		flags interval
+		auto-merge
+		elements = { 10.0.0.0/8, 192.168.0.0/16 }
+	}
+
+	chain input { # handle 1
+		type filter hook input priority filter; policy drop;
+		ct state established,related counter packets 1204 bytes 98123 accept # handle 5
+		ip saddr @blocklist counter packets 0 bytes 0 drop # handle 8
+		ip6 saddr { 2001:db8::/32, fe80::/10 } tcp dport { 22, 80, 443 } accept comment \"[web] public\" # handle 9
+		meta l4proto { tcp, udp } th dport 53 jump dns # handle 10
+	}
+
+	chain dns { udp dport 53 accept; tcp dport 53 return }
+}
+add element inet filter blocklist { 172.16.0.0/12 }
+";
+
+    fn rule(s: &str) -> Rule {
+        parse_rule(s, &[]).unwrap()
+    }
+
+    fn packet(protocol: u8, dport: u16) -> Packet {
+        let ip = |s| Ip::from_str(s).unwrap();
+        Packet::new(ip("192.0.2.1"), ip("192.0.2.2"), protocol, 40000, dport)
+    }
+
+    #[test]
+    fn parses_live_ruleset_output() {
+        let ruleset = parse(LIVE).unwrap();
+        let table = &ruleset.tables()[0];
+        assert_eq!((table.family, table.name.as_str()), (Family::Any, "filter"));
+        assert_eq!(table.sets()[0].elements.len(), 3);
+        let input = &table.chains()[0];
+        assert_eq!(input.get_base().unwrap().policy, Action::Drop);
+        let rules = input.get_rules();
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[1].src_sets(), ["blocklist"]);
+        assert_eq!((rules[2].name(), rules[2].comment()), ("web", "public"));
+        assert_eq!(rules[2].dst_ports().len(), 3);
+        assert_eq!(rules[3].action, Action::Jump(table.chains()[1].number));
+    }
+
+    #[test]
+    fn parse_of_render_round_trips() {
+        let ruleset = parse(LIVE).unwrap();
+        let table = &ruleset.tables()[0];
+        let again = parse(&self::table(table)).unwrap();
+        assert_eq!(again.tables()[0].chains(), table.chains());
+        assert_eq!(again.tables()[0].sets(), table.sets());
+    }
+
+    #[test]
+    fn protocol_names_resolve_to_numbers() {
+        let numbers = |s| -> Vec<u8> { rule(s).protocols.iter().map(|p| p.0).collect() };
+        assert_eq!(numbers("meta l4proto udp accept"), [17]);
+        assert_eq!(
+            numbers("meta l4proto { tcp, icmpv6, 47 } accept"),
+            [6, 58, 47]
+        );
+        assert_eq!(numbers("ip protocol icmp accept"), [1]);
+        let e = parse_rule("meta l4proto nosuch accept", &[]).unwrap_err();
+        assert_eq!(e.message, "unknown protocol 'nosuch'");
+    }
+
+    #[test]
+    fn protocols_are_simulated_and_analyzed_by_number() {
+        let table = parse(
+            "table inet f { chain input { type filter hook input priority 0; policy drop;
+              meta l4proto tcp drop
+              meta l4proto udp accept } }",
+        )
+        .unwrap();
+        let chain = &table.tables()[0].chains()[0];
+        assert_eq!(chain.simulate(&packet(17, 53)).verdict, Action::Accept);
+        assert_eq!(chain.simulate(&packet(6, 53)).verdict, Action::Drop);
+        assert!(chain.analyze().is_empty());
+    }
+
+    #[test]
+    fn conjunction_of_transport_protocols_is_refused() {
+        let s = "tcp sport 1-10 udp dport 5 accept";
+        let e = parse_rule(s, &[]).unwrap_err();
+        assert_eq!(
+            e.message,
+            "rule matches both tcp and udp ports, no packet carries both"
+        );
+        assert_eq!((e.line, e.column, e.token.as_str()), (1, 16, "udp"));
+        let e = parse_rule("meta l4proto tcp udp dport 53 accept", &[]).unwrap_err();
+        assert_eq!(e.message, "udp ports in a rule matching only protocol tcp");
+        let e = parse_rule("udp dport 53 meta l4proto tcp accept", &[]).unwrap_err();
+        assert_eq!(e.column, 14);
+        let e = parse_rule("meta l4proto tcp ip protocol udp accept", &[]).unwrap_err();
+        assert_eq!(e.message, "rule matches the protocol twice");
+        // Any transport header may go with a specific one.
+        assert_eq!(
+            rule("meta l4proto { tcp, udp } tcp dport 22 th sport 1024-65535 accept")
+                .dst_ports()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn rule_and_chain_from_str_use_the_nft_parser() {
+        let text = "tcp dport 22 accept";
+        assert_eq!(Rule::from_str(text).unwrap(), rule(text));
+        let block = "chain input {
+            type filter hook input priority 0; policy drop;
+            ct state established,related accept
+            ip saddr 10.0.0.0/8 tcp dport { 22, 443 } accept
+        }";
+        let chain = Chain::from_str(block).unwrap();
+        let table = parse(&format!("table inet f {{ {} }}", block)).unwrap();
+        assert_eq!(chain.get_rules(), table.tables()[0].chains()[0].get_rules());
+        assert_eq!(chain.get_base(), table.tables()[0].chains()[0].get_base());
+    }
+
+    #[test]
+    fn rule_and_chain_text_round_trips() {
+        for text in [
+            "ip saddr 10.0.0.0/8 tcp dport { 22, 80-89 } accept",
+            "meta l4proto { tcp, udp } th dport 53 accept comment \"[dns] resolver\"",
+            "iifname \"wg*\" ct state new jump chain_4",
+            "icmpv6 type { nd-neighbor-solicit, echo-request } accept",
+            "meta nfproto ipv6 meta l4proto udp drop",
+        ] {
+            let rule = rule(text);
+            assert_eq!(rule.to_string(), text);
+            assert_eq!(Rule::from_str(&rule.to_string()).unwrap(), rule);
+        }
+        let chain = Chain::from_str(
+            "chain input { type filter hook input priority 0; policy drop; tcp dport 22 accept }",
+        )
+        .unwrap();
+        assert_eq!(Chain::from_str(&chain.to_string()).unwrap(), chain);
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let e = parse("table inet f {\n  chain c {\n    tcp dport 22 acept\n  }\n}\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.token.as_str()), (3, 18, "acept"));
+        assert_eq!(e.snippet, "    tcp dport 22 acept");
+    }
+
+    #[test]
+    fn icmp_types_of_the_other_family_are_refused() {
+        let e = parse_rule("icmp type echo-request ip6 saddr ::1 accept", &[]).unwrap_err();
+        assert_eq!(e.message, "icmp type echo-request in a v6 rule");
+        assert_eq!((e.column, e.token.as_str()), (24, "ip6"));
+        let ok = rule("icmpv6 type echo-request ip6 saddr ::1 accept");
+        assert_eq!(
+            super::rule(&ok, &[]),
+            ["ip6 saddr ::1 icmpv6 type echo-request accept"]
+        );
+    }
+
+    #[test]
+    fn rule_no_packet_matches_is_commented_out() {
+        let mut r = rule("ip6 saddr ::1 accept");
+        r.icmp
+            .push(Icmp::from_name(Family::V4, "echo-request").unwrap());
+        assert_eq!(
+            super::rule(&r, &[]),
+            ["# unsupported icmp type echo-request in a v6 rule: ip6 saddr ::1"]
+        );
+    }
+
+    #[test]
+    fn sets_render_as_interval_sets() {
+        let nets = v4::NetSet::from_str("10.0.0.0/8 192.168.0.0/16").unwrap();
+        let out = set("t", "s", Family::V4, nets.iter(), ELEMENTS_PER_STATEMENT);
+        let ruleset = parse(&out).unwrap();
+        assert_eq!(
+            ruleset.tables()[0].sets()[0].elements,
+            ["10.0.0.0/8", "192.168.0.0/16"]
+        );
+    }
+
+    #[test]
+    fn large_sets_continue_in_add_element_statements() {
+        let nets = ["2001:db8::/32", "2001:db9::/32", "fe80::/10"];
+        assert_eq!(
+            set("fw", "block-v6", Family::V6, nets, 2),
+            "table inet fw {\n\
+             \tset block-v6 {\n\
+             \t\ttype ipv6_addr\n\
+             \t\tflags interval\n\
+             \t\tauto-merge\n\
+             \t\telements = { 2001:db8::/32, 2001:db9::/32 }\n\
+             \t}\n\
+             }\n\
+             add element inet fw block-v6 { fe80::/10 }\n"
+        );
+        let ruleset = parse(&set("fw", "block-v6", Family::V6, nets, 2)).unwrap();
+        assert_eq!(ruleset.tables()[0].sets()[0].elements, nets);
+    }
+
+    #[test]
+    fn rules_split_per_family_and_port_protocol() {
+        let ip = |s| Ip::from_str(s).unwrap();
+        let r = Rule::new(
+            (vec![ip("10.0.0.1"), ip("2001:db8::1")], Vec::new()),
+            (
+                Vec::new(),
+                vec![Port(22, 6, "tcp".into()), Port(53, 17, "udp".into())],
+            ),
+            Vec::new(),
+            Family::Any,
+            Direction::In,
+            Action::Accept,
+            "ssh".to_string(),
+            String::new(),
+        );
+        assert_eq!(
+            super::rule(&r, &[]),
+            [
+                "ip saddr 10.0.0.1 tcp dport 22 accept comment \"[ssh]\"",
+                "ip saddr 10.0.0.1 udp dport 53 accept comment \"[ssh]\"",
+                "ip6 saddr 2001:db8::1 tcp dport 22 accept comment \"[ssh]\"",
+                "ip6 saddr 2001:db8::1 udp dport 53 accept comment \"[ssh]\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn actions_nft_cannot_express_are_commented_out() {
+        let mut r = rule("tcp dport 22 accept");
+        r.action = Action::Skip;
+        assert_eq!(super::rule(&r, &[]), ["tcp dport 22 continue"]);
+        r.action = Action::FlowOffload;
+        assert_eq!(
+            super::rule(&r, &[]),
+            ["# unsupported action FlowOffload: tcp dport 22"]
+        );
+    }
+
+    #[test]
+    fn ruleset_declares_chains_and_jump_targets() {
+        let mut input =
+            Chain::from_str("chain input { type filter hook input priority 0; policy drop; }")
+                .unwrap();
+        let mut ssh = Chain::from_str("chain ssh { tcp dport 22 accept }").unwrap();
+        (input.number, ssh.number) = (1, 2);
+        let mut jump = rule("ip saddr 10.0.0.0/8 accept");
+        jump.action = Action::Jump(2);
+        input.rules.push(jump);
+        let parsed = parse(&ruleset("fw", &[input, ssh])).unwrap();
+        let table = &parsed.tables()[0];
+        let chains = table.chains();
+        assert_eq!(table.name, "fw");
+        assert_eq!(chains[0].base.as_ref().unwrap().policy, Action::Drop);
+        assert_eq!(chains[0].rules[0].action, Action::Jump(chains[1].number));
+        assert_eq!(
+            super::rule(&chains[0].rules[0], chains),
+            ["ip saddr 10.0.0.0/8 jump ssh"]
+        );
+    }
+
+    #[test]
+    fn conntrack_matches_round_trip() {
+        for text in [
+            "ct state { established, related } accept",
+            "ct state invalid drop",
+            "ct status { assured, dnat } ct zone 3 ct mark 0x0000002a ct helper \"ftp\" accept",
+        ] {
+            let rule = parse_rule(text, &[]).unwrap();
+            let rendered = super::rule(&rule, &[]);
+            assert_eq!(rendered, [text]);
+            assert_eq!(parse_rule(&rendered[0], &[]).unwrap(), rule);
+        }
+        let rule =
+            parse_rule("ct state established,related ct helper \"ftp\" accept", &[]).unwrap();
+        assert_eq!(
+            rule.conntrack.state(),
+            [CtState::Established, CtState::Related]
+        );
+        assert_eq!(rule.conntrack.helper.as_deref(), Some("ftp"));
+        let e = parse_rule("ct status confused accept", &[]).unwrap_err();
+        assert!(e.message().contains("unknown ct status 'confused'"));
+    }
+
+    #[test]
+    fn interface_matches_round_trip() {
+        for text in [
+            "iifname \"wg*\" accept",
+            "iifname { \"eth0\", \"wg*\" } oifname \"docker0\" accept",
+        ] {
+            let rule = parse_rule(text, &[]).unwrap();
+            let rendered = super::rule(&rule, &[]);
+            assert_eq!(rendered, [text]);
+            assert_eq!(parse_rule(&rendered[0], &[]).unwrap(), rule);
+        }
+    }
+
+    #[test]
+    fn ports_without_a_protocol_match_the_transport_header() {
+        let ports = |proto| {
+            (
+                Vec::new(),
+                vec![
+                    Port(53, proto, String::new()),
+                    Port(853, proto, String::new()),
+                ],
+            )
+        };
+        let mut r = Rule::new(
+            (Vec::new(), Vec::new()),
+            ports(254),
+            Vec::new(),
+            Family::Any,
+            Direction::In,
+            Action::Accept,
+            String::new(),
+            String::new(),
+        );
+        assert_eq!(
+            super::rule(&r, &[]),
+            ["meta l4proto { tcp, udp, dccp, sctp, udplite } th dport { 53, 853 } accept"]
+        );
+        r.protocols = vec![Proto::from_str("sctp 132").unwrap()];
+        assert_eq!(super::rule(&r, &[]), ["sctp dport { 53, 853 } accept"]);
+        r.protocols.push(Proto::from_str("dccp 33").unwrap());
+        assert_eq!(
+            super::rule(&r, &[]),
+            ["meta l4proto { sctp, dccp } th dport { 53, 853 } accept"]
+        );
+        r.ports.1 = PortSet::new();
+        r.ports.1.push(Port(53, 17, "udp".into()));
+        r.ports.1.push(Port(853, 17, "udp".into()));
+        assert_eq!(
+            super::rule(&r, &[]),
+            [
+                "# unsupported udp ports in a rule matching only protocol sctp, dccp: \
+                 udp dport { 53, 853 } accept"
+            ]
+        );
+        r.ports.1.push(Port(22, 132, "sctp".into()));
+        assert_eq!(
+            super::rule(&r, &[]),
+            [
+                "# unsupported udp ports in a rule matching only protocol sctp, dccp: \
+                 udp dport { 53, 853 } accept",
+                "sctp dport 22 accept",
+            ]
+        );
+    }
+}
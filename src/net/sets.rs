@@ -0,0 +1,132 @@
+use super::{
+    cidr::Report,
+    v4::NetSet,
+    v6::{self, Set},
+};
+use crate::meta;
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// Networks of both address families read from the same source.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Sets {
+    pub v4: NetSet,
+    pub v6: Set,
+}
+
+impl Sets {
+    pub fn new() -> Self {
+        Self {
+            v4: NetSet::new(),
+            v6: Set::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+    pub fn append(&mut self, other: &Sets) {
+        self.v4.append(&mut other.v4.clone());
+        self.v6.append(&other.v6);
+    }
+    /// Aggregates each family independently, see [`NetSet::optimize`] and [`Set::optimize`].
+    pub fn optimize(&mut self) -> (Report, Report) {
+        (self.v4.optimize(), self.v6.optimize())
+    }
+    pub fn intersection(&self, other: &Sets) -> Sets {
+        Sets {
+            v4: self.v4.intersection(&other.v4),
+            v6: self.v6.intersection(&other.v6),
+        }
+    }
+    pub fn difference(&self, other: &Sets) -> Sets {
+        Sets {
+            v4: self.v4.difference(&other.v4),
+            v6: self.v6.difference(&other.v6),
+        }
+    }
+}
+
+impl Default for Sets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Routes every token to its family: tokens that parse as an IPv6 network go to `v6`, the
+/// remaining text is left to the IPv4 tokenizer of [`NetSet::from_str`]. IPv6 tokens are
+/// blanked rather than removed so errors keep the line and column of the original text.
+impl FromStr for Sets {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut v6 = Set::new();
+        let mut rest = String::with_capacity(s.len());
+        for line in s.lines() {
+            for token in line.split(|c: char| c.is_whitespace() || c == ',' || c == ';') {
+                let trimmed = token.trim_matches(|c| matches!(c, '[' | ']' | '"' | '\''));
+                match trimmed.contains(':').then(|| v6::Net::from_str(trimmed)) {
+                    Some(Ok(net)) => {
+                        v6.push(&net);
+                        rest.extend(token.chars().map(|_| ' '));
+                    }
+                    _ => rest.push_str(token),
+                }
+                rest.push(' ');
+            }
+            rest.push('\n');
+        }
+        let v4 = NetSet::from_str(&rest).map_err(|e| {
+            // The blanked text would hide IPv6 tokens on the line shown with the error.
+            let snippet = s.lines().nth(e.line.saturating_sub(1)).unwrap_or_default();
+            meta::Error {
+                snippet: snippet.to_string(),
+                ..e
+            }
+        })?;
+        Ok(Self { v4, v6 })
+    }
+}
+
+impl Display for Sets {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.v4)?;
+        if !self.v6.is_empty() {
+            writeln!(f, "{}", self.v6)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_routed_to_their_family() {
+        let sets =
+            Sets::from_str("10.0.0.0/8, [2001:db8::/32]\n\"fe80::1\" 192.168.0.1;::1\n").unwrap();
+        assert_eq!(sets.v4.to_string(), "10.0.0.0/8\n192.168.0.1/32\n");
+        assert_eq!(sets.v6.to_string(), "2001:db8::/32\nfe80::1\n::1");
+        assert_eq!(sets.len(), 5);
+    }
+
+    #[test]
+    fn families_are_optimized_independently() {
+        let mut sets =
+            Sets::from_str("10.0.0.0/9 10.128.0.0/9 2001:db8::/33 2001:db8:8000::/33").unwrap();
+        let (v4, v6) = sets.optimize();
+        assert_eq!((v4.entries_out, v6.entries_out), (1, 1));
+        assert_eq!(sets.to_string(), "10.0.0.0/8\n2001:db8::/32\n");
+    }
+
+    #[test]
+    fn errors_show_the_original_line() {
+        let e = Sets::from_str("2001:db8::/32 10.0.0.300\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.token.as_str()), (1, 22, "300"));
+        assert_eq!(e.snippet, "2001:db8::/32 10.0.0.300");
+    }
+}
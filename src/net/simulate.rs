@@ -0,0 +1,444 @@
+use super::{
+    Action, Chain, Conntrack, CtState, CtStatus, Direction, Family, Hook, Icmp, Ip, NamedSet, Rule,
+    Table, interface_matches,
+};
+use std::fmt::{Display, Formatter};
+
+/// Deepest chain nesting followed, as the kernel's jump stack.
+pub const JUMP_DEPTH: usize = 16;
+
+/// A synthetic packet to run through a ruleset.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Packet {
+    pub family: Family,
+    pub src: Ip,
+    pub dst: Ip,
+    /// Protocol number, 6 for tcp, 17 for udp.
+    pub protocol: u8,
+    pub sport: u16,
+    pub dport: u16,
+    /// Type and code of an ICMP or ICMPv6 packet.
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub direction: Direction,
+    /// Interfaces the packet arrives on and leaves on, empty where the hook knows none.
+    pub iif: String,
+    pub oif: String,
+    pub state: CtState,
+    /// Status bits, zone, mark and helper of the connection the packet belongs to.
+    pub status: Vec<CtStatus>,
+    pub zone: u16,
+    pub mark: u32,
+    pub helper: Option<String>,
+}
+
+impl Packet {
+    /// A new connection from `src` to `dst`, of the family of `src`.
+    pub fn new(src: Ip, dst: Ip, protocol: u8, sport: u16, dport: u16) -> Self {
+        Self {
+            family: src.family(),
+            src,
+            dst,
+            protocol,
+            sport,
+            dport,
+            icmp_type: 0,
+            icmp_code: 0,
+            direction: Direction::In,
+            iif: String::new(),
+            oif: String::new(),
+            state: CtState::New,
+            status: Vec::new(),
+            zone: 0,
+            mark: 0,
+            helper: None,
+        }
+    }
+    /// The hook the packet passes, input unless it is outgoing or forwarded.
+    pub fn hook(&self) -> Hook {
+        match self.direction {
+            Direction::Out => Hook::Output,
+            Direction::Fwd => Hook::Forward,
+            _ => Hook::Input,
+        }
+    }
+    /// Whether every match of `rule` holds for the packet. `sets` holds the named sets `@name`
+    /// references are looked up in; a set that is not there never matches.
+    pub fn matches(&self, rule: &Rule, sets: &[NamedSet]) -> bool {
+        let family = match rule.family {
+            Family::V4 | Family::V6 => rule.family == self.family,
+            _ => true,
+        };
+        let in_set = |addr: &Ip, name: &String| {
+            sets.iter().find(|s| &s.name == name).is_some_and(|s| {
+                s.elements
+                    .iter()
+                    .filter_map(|e| Ip::from_str(e).ok())
+                    .any(|net| contains(&net, addr))
+            })
+        };
+        family
+            && (rule.direction == Direction::None || rule.direction == self.direction)
+            && (rule.interfaces.0.is_empty()
+                || rule
+                    .interfaces
+                    .0
+                    .iter()
+                    .any(|i| interface_matches(i, &self.iif)))
+            && (rule.interfaces.1.is_empty()
+                || rule
+                    .interfaces
+                    .1
+                    .iter()
+                    .any(|i| interface_matches(i, &self.oif)))
+            && (rule.ips.0.is_empty() || rule.ips.0.iter().any(|n| contains(n, &self.src)))
+            && (rule.ips.1.is_empty() || rule.ips.1.iter().any(|n| contains(n, &self.dst)))
+            && rule.sets.0.iter().all(|name| in_set(&self.src, name))
+            && rule.sets.1.iter().all(|name| in_set(&self.dst, name))
+            && self.tracked(&rule.conntrack)
+            && (rule.protocols.is_empty() || rule.protocols.iter().any(|p| p.0 == self.protocol))
+            && (rule.icmp.is_empty() || rule.icmp.iter().any(|i| self.icmp(i)))
+            && (rule.ports.0.is_empty() || rule.ports.0.contains(self.sport, self.protocol))
+            && (rule.ports.1.is_empty() || rule.ports.1.contains(self.dport, self.protocol))
+    }
+    fn tracked(&self, ct: &Conntrack) -> bool {
+        (ct.state.is_empty() || ct.state.contains(&self.state))
+            && (ct.status.is_empty() || ct.status.iter().any(|s| self.status.contains(s)))
+            && ct.zone.is_none_or(|zone| zone == self.zone)
+            && ct.mark.is_none_or(|mark| mark == self.mark)
+            && (ct.helper.is_none() || ct.helper == self.helper)
+    }
+    fn icmp(&self, icmp: &Icmp) -> bool {
+        icmp.protocol() == self.protocol
+            && icmp.kind == self.icmp_type
+            && icmp.code.is_none_or(|code| code == self.icmp_code)
+    }
+}
+
+impl Default for Packet {
+    fn default() -> Self {
+        Self::new(Ip::None, Ip::None, 6, 0, 0)
+    }
+}
+
+impl Display for Packet {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "proto {} {}:{} -> {}:{} {} {}",
+            self.protocol, self.src, self.sport, self.dst, self.dport, self.direction, self.state
+        )?;
+        if matches!(self.protocol, 1 | 58) {
+            write!(f, " icmp {}/{}", self.icmp_type, self.icmp_code)?;
+        }
+        if !self.iif.is_empty() {
+            write!(f, " iif {}", self.iif)?;
+        }
+        if !self.oif.is_empty() {
+            write!(f, " oif {}", self.oif)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the network `net` holds the address of `addr`.
+fn contains(net: &Ip, addr: &Ip) -> bool {
+    match (net.to_prefix(), addr.to_prefix()) {
+        (Some((family, net)), Some((other, (addr, _)))) if family == other => {
+            let bits = if family == Family::V6 { 128 } else { 32 };
+            let (first, last) = super::cidr::bounds(net, bits);
+            (first..=last).contains(&addr)
+        }
+        _ => false,
+    }
+}
+
+/// A rule that matched on the way to the verdict, or the policy of a chain when `rule` is `None`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Step {
+    pub chain: String,
+    /// Index of the rule in the chain.
+    pub rule: Option<usize>,
+    pub action: Action,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self.rule {
+            Some(rule) => write!(f, "{}: rule {} {}", self.chain, rule + 1, self.action),
+            None => write!(f, "{}: policy {}", self.chain, self.action),
+        }
+    }
+}
+
+/// The verdict on a packet and the steps that led to it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Outcome {
+    /// Accept, drop, reject or queue; [`Action::None`] when a regular chain ran to its end.
+    pub verdict: Action,
+    pub trace: Vec<Step>,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for step in &self.trace {
+            writeln!(f, "{}", step)?;
+        }
+        write!(f, "verdict: {}", self.verdict)
+    }
+}
+
+/// Evaluates the chains of one table the way netfilter does.
+struct Simulator<'a> {
+    chains: &'a [Chain],
+    sets: &'a [NamedSet],
+    trace: Vec<Step>,
+}
+
+impl Simulator<'_> {
+    /// Verdict of `chain`, `None` when it returns or runs to its end without one.
+    fn chain(&mut self, chain: &Chain, packet: &Packet, depth: usize) -> Option<Action> {
+        for (index, rule) in chain.rules.iter().enumerate() {
+            if !packet.matches(rule, self.sets) {
+                continue;
+            }
+            self.trace.push(Step {
+                chain: chain.name.clone(),
+                rule: Some(index),
+                action: rule.action.clone(),
+            });
+            match &rule.action {
+                Action::Accept | Action::Drop | Action::Reject | Action::Queue => {
+                    return Some(rule.action.clone());
+                }
+                // Address translation ends the nat chain and lets the packet through.
+                Action::Snat(_) | Action::Dnat(_) | Action::Masquerade | Action::Redirect(_) => {
+                    return Some(Action::Accept);
+                }
+                Action::Return => return None,
+                Action::Jump(number) => {
+                    let target = self.chains.iter().find(|c| c.number == *number);
+                    if let Some(target) = target
+                        && depth < JUMP_DEPTH
+                        && let Some(verdict) = self.chain(target, packet, depth + 1)
+                    {
+                        return Some(verdict);
+                    }
+                }
+                // Logging, counting, marking and rate limits leave the verdict to later rules.
+                _ => {}
+            }
+        }
+        None
+    }
+    /// Verdict of a base chain, its policy when no rule decides.
+    /// Regular chains have no policy and give [`Action::None`].
+    fn base(&mut self, chain: &Chain, packet: &Packet) -> Action {
+        if let Some(verdict) = self.chain(chain, packet, 0) {
+            return verdict;
+        }
+        let Some(base) = &chain.base else {
+            return Action::None;
+        };
+        self.trace.push(Step {
+            chain: chain.name.clone(),
+            rule: None,
+            action: base.policy.clone(),
+        });
+        base.policy.clone()
+    }
+}
+
+/// Runs `packet` through `chain`, following jumps into `chains` and looking up sets in `sets`.
+pub fn chain(chain: &Chain, chains: &[Chain], sets: &[NamedSet], packet: &Packet) -> Outcome {
+    let mut simulator = Simulator {
+        chains,
+        sets,
+        trace: Vec::new(),
+    };
+    let verdict = simulator.base(chain, packet);
+    Outcome {
+        verdict,
+        trace: simulator.trace,
+    }
+}
+
+/// Runs `packet` through every base chain of `tables` on its hook, by priority. The packet goes
+/// on to the next base chain when one accepts it and is accepted when none drops it.
+pub fn tables<'a>(tables: impl IntoIterator<Item = &'a Table>, packet: &Packet) -> Outcome {
+    let mut bases: Vec<(&Table, &Chain)> = tables
+        .into_iter()
+        .filter(|t| t.family == Family::Any || t.family == packet.family)
+        .flat_map(|t| t.chains.iter().map(move |c| (t, c)))
+        .filter(|(_, c)| c.base.as_ref().is_some_and(|b| b.hook == packet.hook()))
+        .collect();
+    bases.sort_by_key(|(_, c)| c.base.as_ref().map(|b| b.priority.value()));
+    let mut outcome = Outcome {
+        verdict: Action::Accept,
+        trace: Vec::new(),
+    };
+    for (table, base) in bases {
+        let result = chain(base, &table.chains, &table.sets, packet);
+        outcome.trace.extend(result.trace);
+        if result.verdict != Action::Accept && result.verdict != Action::None {
+            outcome.verdict = result.verdict;
+            break;
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::nft;
+
+    const FILTER: &str = "table inet filter {
+	set blocklist {
+		type ipv4_addr
+		flags interval
+		elements = { 10.0.0.0/8 }
+	}
+	chain input {
+		type filter hook input priority 0; policy drop;
+		ip saddr @blocklist drop
+		tcp dport 22 jump ssh
+		tcp dport 80 accept
+	}
+	chain ssh {
+		ip saddr 192.168.0.0/16 accept
+		return
+	}
+}
+";
+
+    fn packet(src: &str, dport: u16) -> Packet {
+        let src = Ip::from_str(src).unwrap();
+        Packet::new(src, Ip::from_str("192.0.2.1").unwrap(), 6, 40000, dport)
+    }
+
+    fn verdict(ruleset: &str, packet: &Packet) -> Action {
+        let ruleset = nft::parse(ruleset).unwrap();
+        tables(ruleset.tables(), packet).verdict
+    }
+
+    #[test]
+    fn first_matching_rule_decides_and_policy_is_the_fallback() {
+        assert_eq!(verdict(FILTER, &packet("203.0.113.5", 80)), Action::Accept);
+        assert_eq!(verdict(FILTER, &packet("203.0.113.5", 443)), Action::Drop);
+        let ruleset = nft::parse(FILTER).unwrap();
+        let outcome = tables(ruleset.tables(), &packet("203.0.113.5", 443));
+        assert_eq!(
+            outcome.trace,
+            [Step {
+                chain: "input".to_string(),
+                rule: None,
+                action: Action::Drop,
+            }]
+        );
+    }
+
+    #[test]
+    fn jumps_return_to_the_calling_chain() {
+        let ruleset = nft::parse(FILTER).unwrap();
+        let outcome = tables(ruleset.tables(), &packet("192.168.1.1", 22));
+        assert_eq!(outcome.verdict, Action::Accept);
+        let steps: Vec<String> = outcome.trace.iter().map(|s| s.to_string()).collect();
+        assert_eq!(steps[1], "ssh: rule 1 accept");
+        let outcome = tables(ruleset.tables(), &packet("203.0.113.5", 22));
+        assert_eq!(outcome.verdict, Action::Drop);
+        assert_eq!(outcome.trace.len(), 3);
+        assert_eq!(outcome.trace[1].action, Action::Return);
+        assert_eq!(outcome.trace[2].rule, None);
+    }
+
+    #[test]
+    fn named_sets_are_looked_up() {
+        assert_eq!(verdict(FILTER, &packet("10.1.2.3", 80)), Action::Drop);
+        let ruleset = nft::parse(FILTER).unwrap();
+        let table = &ruleset.tables()[0];
+        let input = table.chain("input").unwrap();
+        let outcome = chain(input, &table.chains, &[], &packet("10.1.2.3", 80));
+        assert_eq!(outcome.verdict, Action::Accept);
+    }
+
+    #[test]
+    fn base_chains_run_by_priority_until_one_drops() {
+        let ruleset = "table inet a {
+	chain late {
+		type filter hook input priority 10; policy accept;
+		drop
+	}
+	chain early {
+		type filter hook input priority -10; policy accept;
+		tcp dport 22 drop
+	}
+	chain out {
+		type filter hook output priority -20; policy drop;
+	}
+}
+";
+        let ruleset = nft::parse(ruleset).unwrap();
+        let outcome = tables(ruleset.tables(), &packet("192.0.2.7", 22));
+        assert_eq!(outcome.verdict, Action::Drop);
+        assert_eq!(outcome.trace[0].chain, "early");
+        assert_eq!(outcome.trace.len(), 1);
+        let outcome = tables(ruleset.tables(), &packet("192.0.2.7", 80));
+        let chains: Vec<&str> = outcome.trace.iter().map(|s| s.chain.as_str()).collect();
+        assert_eq!(chains, ["early", "late"]);
+        let mut outgoing = packet("192.0.2.7", 80);
+        outgoing.direction = Direction::Out;
+        assert_eq!(tables(ruleset.tables(), &outgoing).verdict, Action::Drop);
+    }
+
+    #[test]
+    fn connection_tracking_is_matched() {
+        let ruleset = "table inet f {
+	chain input {
+		type filter hook input priority 0; policy drop;
+		ct state invalid drop
+		ct state established,related accept
+		ct status dnat ct zone 2 accept
+		ct mark 0x2a ct helper \"ftp\" accept
+	}
+}
+";
+        let mut packet = packet("203.0.113.5", 21);
+        assert_eq!(verdict(ruleset, &packet), Action::Drop);
+        packet.state = CtState::Established;
+        assert_eq!(verdict(ruleset, &packet), Action::Accept);
+        packet.state = CtState::New;
+        packet.status = vec![CtStatus::Confirmed, CtStatus::Dnat];
+        assert_eq!(verdict(ruleset, &packet), Action::Drop);
+        packet.zone = 2;
+        assert_eq!(verdict(ruleset, &packet), Action::Accept);
+        packet.status.clear();
+        packet.mark = 42;
+        assert_eq!(verdict(ruleset, &packet), Action::Drop);
+        packet.helper = Some("ftp".to_string());
+        assert_eq!(verdict(ruleset, &packet), Action::Accept);
+    }
+
+    #[test]
+    fn interfaces_are_matched_with_wildcards() {
+        let ruleset = "table inet f {
+	chain forward {
+		type filter hook forward priority 0; policy drop;
+		iifname { \"wg0\", \"docker*\" } oifname \"eth0\" accept
+	}
+}
+";
+        let mut packet = packet("203.0.113.5", 443);
+        packet.direction = Direction::Fwd;
+        packet.iif = "docker1".to_string();
+        packet.oif = "eth0".to_string();
+        assert_eq!(verdict(ruleset, &packet), Action::Accept);
+        packet.oif = "eth1".to_string();
+        assert_eq!(verdict(ruleset, &packet), Action::Drop);
+        packet.iif = "wg0".to_string();
+        packet.oif = "eth0".to_string();
+        assert_eq!(verdict(ruleset, &packet), Action::Accept);
+        packet.iif = "wg01".to_string();
+        assert_eq!(verdict(ruleset, &packet), Action::Drop);
+    }
+}
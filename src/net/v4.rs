@@ -2,7 +2,6 @@ use std::{
     cmp::Ordering,
     fmt::{Display, Error as FmtError, Formatter},
     iter::IntoIterator,
-    num::ParseIntError,
     ops::{
         Add, AddAssign, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Div,
         DivAssign, Index, IndexMut, Mul, MulAssign, Not, Rem, RemAssign, Shl, ShlAssign, Shr,
@@ -13,13 +12,17 @@ use std::{
     vec::IntoIter,
 };
 
+use super::{
+    Family,
+    cidr::{self, Interval, Prefix, Report},
+};
 use crate::{
     impl_abs_diff, impl_add, impl_bitand, impl_bitor, impl_bitxor, impl_default, impl_div,
     impl_from, impl_into_iter, impl_mul, impl_not, impl_octet_quad, impl_op, impl_op_assign,
     impl_ord, impl_range_bounds, impl_rem, impl_shl, impl_shr, impl_size_of, impl_sub, impl_vec,
+    meta,
 };
 
-const LOGGING_ENABLED: bool = true;
 pub type Protocol = u8;
 pub type Action = u8;
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -42,6 +45,39 @@ pub struct Rule {
     action: Action,
 }
 
+/// Parses `token` as a number, errors are placed within `source`.
+fn number<T: FromStr>(token: &str, expected: &str, source: &str) -> Result<T, meta::Error> {
+    token
+        .parse()
+        .map_err(|_| meta::Error::parse(token, expected).locate(source, token))
+}
+/// Parses a prefix length of at most 32 bits.
+fn prefix_len(token: &str, source: &str) -> Result<u8, meta::Error> {
+    match number(token, "a prefix length 0-32", source)? {
+        len if len <= 32 => Ok(len),
+        _ => Err(meta::Error::parse(token, "a prefix length 0-32").locate(source, token)),
+    }
+}
+/// Parses exactly four dotted octets.
+fn address(token: &str, source: &str) -> Result<Addr, meta::Error> {
+    let octets: Vec<&str> = token.split('.').collect();
+    if octets.len() != 4 {
+        return Err(meta::Error::parse(token, "an IPv4 address").locate(source, token));
+    }
+    let mut addr = 0u32;
+    for octet in octets {
+        addr = addr << 8 | number::<u8>(octet, "an octet 0-255", source)? as u32;
+    }
+    Ok(Addr(addr))
+}
+/// Parses `a.b.c.d` with an optional `/len`.
+fn network(token: &str, source: &str) -> Result<Net, meta::Error> {
+    match token.split_once('/') {
+        Some((addr, len)) => Ok(Net(address(addr, source)?.0, prefix_len(len, source)?)),
+        None => Ok(Net(address(token, source)?.0, 32)),
+    }
+}
+
 impl Port {
     pub fn new() -> Self {
         Self(0)
@@ -52,12 +88,9 @@ impl Port {
     pub fn to_string(&self) -> String {
         format!(":{}", self.0)
     }
-    pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
-        if s.starts_with(":") {
-            Ok(Self(u16::from_str(&s[1..])?))
-        } else {
-            Ok(Self(u16::from_str(s)?))
-        }
+    /// Parses `port` or `:port`.
+    pub fn from_str(s: &str) -> Result<Self, meta::Error> {
+        number(s.strip_prefix(':').unwrap_or(s), "a port 0-65535", s).map(Self)
     }
 }
 impl Display for Port {
@@ -66,7 +99,7 @@ impl Display for Port {
     }
 }
 impl FromStr for Port {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -98,12 +131,9 @@ impl Addr {
         let bytes = self.to_be_bytes();
         format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
     }
-    pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
-        let mut addr: u32 = 0;
-        for (i, ss) in s.split('.').enumerate() {
-            addr |= u32::from_str(ss)? << (24 - i * 8);
-        }
-        Ok(Self(addr))
+    /// Parses a dotted quad, `a.b.c.d`.
+    pub fn from_str(s: &str) -> Result<Self, meta::Error> {
+        address(s, s)
     }
     pub fn to_be_bytes(&self) -> [u8; 4] {
         [
@@ -221,12 +251,9 @@ impl Subnet {
     pub fn to_string(&self) -> String {
         format!("/{}", self.0)
     }
-    pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
-        if s.starts_with('/') {
-            Ok(Self(u8::from_str(s.split('/').last().unwrap())?))
-        } else {
-            Ok(Self(u8::from_str(s)?))
-        }
+    /// Parses `len` or `/len`.
+    pub fn from_str(s: &str) -> Result<Self, meta::Error> {
+        prefix_len(s.strip_prefix('/').unwrap_or(s), s).map(Self)
     }
 }
 impl Display for Subnet {
@@ -235,7 +262,7 @@ impl Display for Subnet {
     }
 }
 impl FromStr for Subnet {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -290,10 +317,10 @@ impl Net {
         self.1 = subnet;
     }
     pub fn netmask(&self) -> u32 {
-        !(0xFFFFFFFF << (32 - self.1))
+        cidr::host_mask(32, self.1) as u32
     }
     pub fn wildcard(&self) -> u32 {
-        0xFFFFFFFF << (32 - self.1)
+        !self.netmask()
     }
     pub fn network(&self) -> u32 {
         self.0 & self.wildcard()
@@ -353,30 +380,21 @@ impl Net {
             bytes[4].min(32),
         )
     }
-    pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
-        let mut addr: u32 = 0;
-        let mut subnet: u8 = 32;
-
-        let (ip_part, subnet_part) = if s.contains('/') {
-            let parts: Vec<&str> = s.split('/').collect();
-            (parts[0], Some(parts[1]))
-        } else {
-            (s, None)
-        };
-
-        for (idx, octet) in ip_part.split('.').enumerate() {
-            addr |= u32::from_str(octet)? << (24 - idx * 8);
-        }
-
-        if let Some(subnet_str) = subnet_part {
-            subnet = subnet_str.parse::<u8>()?.min(32);
-        }
-
-        Ok(Self(addr, subnet))
+    /// Parses `a.b.c.d` or `a.b.c.d/len`.
+    pub fn from_str(s: &str) -> Result<Self, meta::Error> {
+        network(s, s)
     }
     pub fn cmp(&self, other: &Self) -> Ordering {
         self.0.cmp(&other.0).then(self.1.cmp(&other.1))
     }
+    /// The network as a normalized [`Prefix`], host bits cleared.
+    pub fn prefix(&self) -> Prefix {
+        cidr::normalize((self.0 as u128, self.1), 32)
+    }
+    pub fn from_prefix(prefix: Prefix) -> Self {
+        let (network, len) = cidr::normalize(prefix, 32);
+        Self(network as u32, len)
+    }
 }
 impl Display for Net {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
@@ -437,7 +455,7 @@ impl From<u32> for Net {
     }
 }
 impl FromStr for Net {
-    type Err = ParseIntError;
+    type Err = meta::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::from_str(s)
     }
@@ -498,57 +516,125 @@ impl SubAssign<Addr> for Net {
         self.0 -= addr.0;
     }
 }
+impl Range<Addr> {
+    /// A range of addresses, the endpoints are put in order.
+    pub fn new(start: Addr, end: Addr) -> Self {
+        Self(start.min(end), start.max(end))
+    }
+    pub fn start(&self) -> Addr {
+        self.0
+    }
+    pub fn end(&self) -> Addr {
+        self.1
+    }
+    /// Iterates over every address of the range.
+    pub fn iter(&self) -> impl Iterator<Item = Addr> + use<> {
+        (self.0.0..=self.1.0).map(Addr)
+    }
+    /// The minimal list of networks covering exactly the range.
+    pub fn to_nets(&self) -> NetSet {
+        NetSet::from_intervals(&[(self.0.0 as u128, self.1.0 as u128)])
+    }
+    /// Parses `a.b.c.d-e.f.g.h`.
+    pub fn from_str(s: &str) -> Result<Self, meta::Error> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        Ok(Self::new(
+            address(start.trim(), s)?,
+            address(end.trim(), s)?,
+        ))
+    }
+}
+impl Display for Range<Addr> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+impl FromStr for Range<Addr> {
+    type Err = meta::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_str(s)
+    }
+}
+impl From<Net> for Range<Addr> {
+    fn from(net: Net) -> Self {
+        let (start, end) = cidr::bounds(net.prefix(), 32);
+        Self(Addr(start as u32), Addr(end as u32))
+    }
+}
+impl IntoIterator for Range<Addr> {
+    type Item = Addr;
+    type IntoIter = std::iter::Map<std::ops::RangeInclusive<u32>, fn(u32) -> Addr>;
+    fn into_iter(self) -> Self::IntoIter {
+        (self.0.0..=self.1.0).map(Addr as fn(u32) -> Addr)
+    }
+}
 impl NetSet {
     pub fn new() -> Self {
         Self(Vec::new())
     }
-    pub fn optimize(&mut self) -> Self {
-        let old_len = self.len();
-        if old_len == 0 {
-            return self.clone();
-        }
-        if LOGGING_ENABLED {
-            println!("Current Total: {} entries", old_len);
-        }
-        let mut i = 0;
-        let mut changed = false;
-        while i < self.len() - 1 {
-            let net1 = Net(self[i].network(), self[i].subnet());
-            let net2 = Net(self[i + 1].network(), self[i + 1].subnet());
-            if net1 == net2 {
-                self.swap_remove(i + 1);
-                changed = true;
-            } else if net1 > net2 {
-                self.swap(i, i + 1);
-                changed = true;
-            } else if net1.contains(&net2) {
-                self.swap_remove(i + 1);
-                changed = true;
-            } else if net1.broadcast().abs_diff(net2.network()) == 1
-                && net1.subnet() == net2.subnet()
-            {
-                self[i] = Net(net1.address(), net1.subnet() - 1);
-                self.swap_remove(i + 1);
-                changed = true;
-            } else {
-                i += 1;
-            }
-            if changed && i > 0 {
-                i -= 1; // Check previous entry again as it might now merge with the next
-            }
-            changed = false;
+    /// Replaces the set with the minimal list of networks covering the same addresses,
+    /// sorted by network. See [`cidr::aggregate`].
+    pub fn optimize(&mut self) -> Report {
+        let entries_in = self.len();
+        let prefixes: Vec<Prefix> = self.iter().map(|net| net.prefix()).collect();
+        let merged = cidr::aggregate(&prefixes, 32);
+        self.0 = merged.iter().map(|&p| Net::from_prefix(p)).collect();
+        Report {
+            entries_in,
+            entries_out: self.len(),
+            addresses: cidr::addresses(&merged, 32),
         }
-        self.shrink_to_fit();
-
-        if LOGGING_ENABLED {
-            println!(
-                "New Total: {} entries\nDifference: {} entries",
-                self.len(),
-                old_len - self.len(),
-            );
-        }
-
-        self.clone()
+    }
+    /// Addresses in `self` or `other`.
+    pub fn union(&self, other: &Self) -> Self {
+        Self::from_intervals(&cidr::union(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self::from_intervals(&cidr::intersection(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in `self` but not in `other`, splitting networks where needed.
+    pub fn difference(&self, other: &Self) -> Self {
+        Self::from_intervals(&cidr::difference(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in exactly one of `self` and `other`.
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        Self::from_intervals(&cidr::symmetric_difference(
+            &self.intervals(),
+            &other.intervals(),
+        ))
+    }
+    /// Every IPv4 address not in `self`.
+    pub fn complement(&self) -> Self {
+        Self::from_intervals(&cidr::complement(&self.intervals(), 32))
+    }
+    /// The covered addresses as sorted, merged ranges, e.g. for `iptables -m iprange`.
+    pub fn to_ranges(&self) -> Vec<Range<Addr>> {
+        self.intervals()
+            .into_iter()
+            .map(|(start, end)| Range(Addr(start as u32), Addr(end as u32)))
+            .collect()
+    }
+    /// Splits the set into consecutive sets of at most `size` networks.
+    pub fn chunks(&self, size: usize) -> Vec<Self> {
+        self.0
+            .chunks(size.max(1))
+            .map(|chunk| Self(chunk.to_vec()))
+            .collect()
+    }
+    /// The covered addresses as sorted, non-overlapping ranges.
+    pub fn intervals(&self) -> Vec<Interval> {
+        let prefixes: Vec<Prefix> = self.iter().map(|net| net.prefix()).collect();
+        cidr::intervals(&prefixes, 32)
+    }
+    /// The minimal set of networks covering `intervals`.
+    pub fn from_intervals(intervals: &[Interval]) -> Self {
+        Self(
+            cidr::prefixes(intervals, 32)
+                .into_iter()
+                .map(Net::from_prefix)
+                .collect(),
+        )
     }
     pub fn to_string(&self) -> String {
         let mut str = String::new();
@@ -559,7 +645,23 @@ impl NetSet {
         }
         str
     }
-    pub fn from_str(str: &str) -> Result<Self, ParseIntError> {
+    /// Renders the set as an `ipset restore` script, see [`super::ipset::restore`].
+    pub fn to_ipset(&self, name: &str, maxelem: usize, swap: bool) -> String {
+        super::ipset::restore(name, Family::V4, maxelem, self.iter(), swap)
+    }
+    /// Renders the set as an nft interval set of `table`, see [`super::nft::set`].
+    pub fn to_nft(&self, table: &str, name: &str) -> String {
+        super::nft::set(
+            table,
+            name,
+            Family::V4,
+            self.iter(),
+            super::nft::ELEMENTS_PER_STATEMENT,
+        )
+    }
+    /// Extracts every network from free-form text. Besides `a.b.c.d` and `a.b.c.d/len`, ranges
+    /// written as `a.b.c.d-e.f.g.h` are split into the networks covering them.
+    pub fn from_str(str: &str) -> Result<Self, meta::Error> {
         let mut netset = Self::new();
         let mut prev_segment = "";
         for line in str.lines() {
@@ -568,12 +670,7 @@ impl NetSet {
                 if !Self::is_valid_char(c) {
                     let segment = &line[start..i];
                     if prev_segment != segment {
-                        if Self::is_valid_str(&segment) {
-                            match Net::from_str(&segment) {
-                                Ok(net) => netset.push(net),
-                                Err(e) => return Err(e),
-                            }
-                        }
+                        netset.push_segment(segment, str)?;
                         prev_segment = segment;
                     }
                     start = i + 1;
@@ -581,19 +678,39 @@ impl NetSet {
             }
 
             // Check the last segment
-            if Self::is_valid_str(&line[start..]) && prev_segment != &line[start..] {
-                match Net::from_str(&line[start..]) {
-                    Ok(net) => netset.push(net),
-                    Err(e) => return Err(e),
-                }
+            if prev_segment != &line[start..] {
+                netset.push_segment(&line[start..], str)?;
             }
         }
 
         Ok(netset)
     }
+    /// Adds the networks of one segment of `source`, errors are placed within `source`.
+    fn push_segment(&mut self, segment: &str, source: &str) -> Result<(), meta::Error> {
+        match segment.split_once('-') {
+            Some((start, end))
+                if Self::is_valid_str(start)
+                    && Self::is_valid_str(end)
+                    && !segment.contains('/') =>
+            {
+                let (start, end) = (address(start, source)?, address(end, source)?);
+                self.append(&mut Range::new(start, end).to_nets());
+            }
+            Some(_) => {
+                for part in segment.split('-') {
+                    if Self::is_valid_str(part) {
+                        self.push(network(part, source)?);
+                    }
+                }
+            }
+            None if Self::is_valid_str(segment) => self.push(network(segment, source)?),
+            None => {}
+        }
+        Ok(())
+    }
     fn is_valid_char(c: char) -> bool {
         match c {
-            '0'..='9' | '.' | '/' => true,
+            '0'..='9' | '.' | '/' | '-' => true,
             _ => false,
         }
     }
@@ -640,7 +757,7 @@ impl_vec!(NetSet, Net);
 impl_into_iter!(NetSet, Net);
 impl_size_of!(NetSet);
 impl FromStr for NetSet {
-    type Err = ParseIntError;
+    type Err = meta::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::from_str(s)
@@ -666,6 +783,56 @@ impl std::io::Write for NetSet {
         Ok(())
     }
 }
+impl BitOr for NetSet {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self::Output {
+        self.union(&other)
+    }
+}
+impl BitOrAssign for NetSet {
+    fn bitor_assign(&mut self, other: Self) {
+        *self = self.union(&other);
+    }
+}
+impl BitAnd for NetSet {
+    type Output = Self;
+    fn bitand(self, other: Self) -> Self::Output {
+        self.intersection(&other)
+    }
+}
+impl BitAndAssign for NetSet {
+    fn bitand_assign(&mut self, other: Self) {
+        *self = self.intersection(&other);
+    }
+}
+impl Sub for NetSet {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self::Output {
+        self.difference(&other)
+    }
+}
+impl SubAssign for NetSet {
+    fn sub_assign(&mut self, other: Self) {
+        *self = self.difference(&other);
+    }
+}
+impl BitXor for NetSet {
+    type Output = Self;
+    fn bitxor(self, other: Self) -> Self::Output {
+        self.symmetric_difference(&other)
+    }
+}
+impl BitXorAssign for NetSet {
+    fn bitxor_assign(&mut self, other: Self) {
+        *self = self.symmetric_difference(&other);
+    }
+}
+impl Not for NetSet {
+    type Output = Self;
+    fn not(self) -> Self::Output {
+        self.complement()
+    }
+}
 impl Index<usize> for NetSet {
     type Output = Net;
     fn index(&self, idx: usize) -> &Self::Output {
@@ -729,3 +896,101 @@ impl ShlAssign<Net> for u32 {
         *self <<= net.address();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(s: &str) -> NetSet {
+        NetSet::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn range_splits_into_covering_networks() {
+        let range = Range::<Addr>::from_str("10.0.0.1-10.0.0.6").unwrap();
+        assert_eq!(
+            range.to_nets().to_string(),
+            "10.0.0.1/32\n10.0.0.2/31\n10.0.0.4/31\n10.0.0.6/32\n"
+        );
+    }
+
+    #[test]
+    fn range_endpoints_are_ordered() {
+        let range = Range::<Addr>::from_str("10.0.0.9-10.0.0.1").unwrap();
+        assert_eq!(range.to_string(), "10.0.0.1-10.0.0.9");
+    }
+
+    #[test]
+    fn ranges_in_free_text() {
+        let nets = set("block 192.168.0.0-192.168.1.255, and 10.0.0.0/8\n");
+        assert_eq!(nets.to_string(), "192.168.0.0/23\n10.0.0.0/8\n");
+    }
+
+    #[test]
+    fn to_ranges_merges_adjacent_networks() {
+        let nets = set("10.0.0.0/24 10.0.1.0/24 10.0.3.0/24");
+        let ranges: Vec<String> = nets.to_ranges().iter().map(|r| r.to_string()).collect();
+        assert_eq!(ranges, ["10.0.0.0-10.0.1.255", "10.0.3.0-10.0.3.255"]);
+    }
+    #[test]
+    fn parse_errors_are_located() {
+        let e = Net::from_str("10.0.300.1/8").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (6, "300"));
+        let e = Net::from_str("10.0.0.0/33").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (10, "33"));
+        let e = Addr::from_str("1.2.3.4.5").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (1, "1.2.3.4.5"));
+        let e = Range::<Addr>::from_str("10.0.0.1-10.0.0.x").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (17, "x"));
+        let e = Port::from_str(":8o").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (2, "8o"));
+    }
+
+    #[test]
+    fn free_text_errors_point_into_the_text() {
+        let e = NetSet::from_str("10.0.0.0/8\nallow 10.0.0.256\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.token.as_str()), (2, 14, "256"));
+        assert_eq!(e.snippet, "allow 10.0.0.256");
+    }
+
+    #[test]
+    fn optimize_yields_the_minimal_covering_networks() {
+        let mut nets =
+            set("10.0.0.0/25 10.0.0.128/25 10.0.1.0/24 10.0.0.7 10.0.2.0/24 10.0.3.0/25");
+        let report = nets.optimize();
+        assert_eq!(nets.to_string(), "10.0.0.0/23\n10.0.2.0/24\n10.0.3.0/25\n");
+        assert_eq!((report.entries_in, report.entries_out), (6, 3));
+        assert_eq!(report.addresses, 512 + 256 + 128);
+    }
+
+    #[test]
+    fn optimize_clears_host_bits_and_drops_covered_networks() {
+        let mut nets = set("192.168.1.77/16 192.168.200.0/24 0.0.0.0/0");
+        nets.optimize();
+        assert_eq!(nets.to_string(), "0.0.0.0/0\n");
+        let mut nets = set("192.168.1.77/16 192.168.200.0/24");
+        nets.optimize();
+        assert_eq!(nets.to_string(), "192.168.0.0/16\n");
+    }
+
+    #[test]
+    fn set_algebra_splits_networks_where_needed() {
+        let (a, b) = (set("10.0.0.0/8"), set("10.128.0.0/9 11.0.0.0/8"));
+        assert_eq!(a.union(&b).to_string(), "10.0.0.0/7\n");
+        assert_eq!(a.intersection(&b).to_string(), "10.128.0.0/9\n");
+        assert_eq!(a.difference(&b).to_string(), "10.0.0.0/9\n");
+        assert_eq!(
+            a.symmetric_difference(&b).to_string(),
+            "10.0.0.0/9\n11.0.0.0/8\n"
+        );
+        assert_eq!(set("10.0.0.1").difference(&set("10.0.0.1")).to_string(), "");
+    }
+
+    #[test]
+    fn complement_covers_everything_else() {
+        let nets = set("128.0.0.0/1");
+        assert_eq!(nets.complement().to_string(), "0.0.0.0/1\n");
+        assert_eq!(set("0.0.0.0/0").complement().to_string(), "");
+        assert_eq!(nets.complement().union(&nets).to_string(), "0.0.0.0/0\n");
+    }
+}
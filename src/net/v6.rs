@@ -1,10 +1,15 @@
+use super::{
+    Family,
+    cidr::{self, Interval, Prefix, Report},
+};
+use crate::meta::Error;
 use std::{
     cmp::Ordering,
     fmt::{Display, Error as FmtError, Formatter},
     fs::File,
     hash::{Hash, Hasher},
     io::{Error as IoError, ErrorKind as IoErrorKind, Read as IoRead, Write as IoWrite},
-    num::ParseIntError,
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Sub, SubAssign},
     str::FromStr,
 };
 const LOGGING_ENABLED: bool = true;
@@ -15,6 +20,7 @@ pub type Mask = u128;
 pub struct Net {
     address: Addr,
     subnet: Subnet,
+    zone: Option<String>,
 }
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Set {
@@ -22,7 +28,11 @@ pub struct Set {
 }
 impl Net {
     pub fn new(address: Addr, subnet: Subnet) -> Self {
-        Net { address, subnet }
+        Net {
+            address,
+            subnet,
+            zone: None,
+        }
     }
     pub fn address(&self) -> Addr {
         self.address
@@ -79,6 +89,7 @@ impl Net {
                 | (bytes[14] as Addr) << 8
                 | (bytes[15] as Addr),
             subnet: bytes[16],
+            zone: None,
         }
     }
     pub fn to_bytes(&self) -> [u8; 17] {
@@ -121,47 +132,189 @@ impl Net {
                 | (bytes[14] as Addr) << 8
                 | (bytes[15] as Addr),
             subnet: bytes[16],
+            zone: None,
         }
     }
-    pub fn to_string(&self) -> String {
-        format!("{:016x}/{}", self.address, self.subnet)
+    /// The network as a normalized [`Prefix`], host bits cleared.
+    pub fn prefix(&self) -> Prefix {
+        cidr::normalize((self.address, self.subnet), 128)
     }
-    pub fn from_str(s: &str) -> Result<Self, ParseIntError> {
-        let mut address = 0;
-        for (i, byte) in s.split(':').enumerate() {
-            let byte = u16::from_str_radix(byte, 16)?;
-            address |= (byte as Addr) << (16 * i);
+    pub fn from_prefix(prefix: Prefix) -> Self {
+        let (address, subnet) = cidr::normalize(prefix, 128);
+        Net {
+            address,
+            subnet,
+            zone: None,
         }
-        if s.contains('/') {
-            let subnet = s.split('/').last().unwrap().parse::<Subnet>().unwrap();
-            Ok(Net { address, subnet })
+    }
+    /// Zone ID of a scoped address, e.g. `eth0` in `fe80::1%eth0`.
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+    pub fn set_zone(&mut self, zone: Option<String>) {
+        self.zone = zone.filter(|z| !z.is_empty());
+    }
+    /// Formats the network in the RFC 5952 canonical text form.
+    pub fn to_string(&self) -> String {
+        let mut s = format_addr(self.address);
+        if let Some(zone) = &self.zone {
+            s.push('%');
+            s.push_str(zone);
+        }
+        if self.subnet < 128 {
+            s.push('/');
+            s.push_str(&self.subnet.to_string());
+        }
+        s
+    }
+    /// Parses any RFC 4291 text form, optionally followed by a `%zone` and a `/prefix` length.
+    pub fn from_str(s: &str) -> Result<Self, Error> {
+        network(s, s)
+    }
+}
+
+/// Parses a network written within `source`, errors are placed within `source`.
+fn network(s: &str, source: &str) -> Result<Net, Error> {
+    let invalid = |token: &str, expected: &str| Error::parse(token, expected).locate(source, token);
+    let (rest, subnet) = match s.split_once('/') {
+        Some((rest, len)) => match len.parse::<Subnet>() {
+            Ok(subnet) if subnet <= 128 => (rest, subnet),
+            _ => return Err(invalid(len, "a prefix length 0-128")),
+        },
+        None => (s, 128),
+    };
+    let (addr, zone) = match rest.split_once('%') {
+        Some((_, "")) => return Err(invalid(rest, "a zone after '%'")),
+        Some((addr, zone)) => (addr, Some(zone.to_string())),
+        None => (rest, None),
+    };
+    let address = parse_addr(addr).ok_or_else(|| invalid(addr, "an IPv6 address"))?;
+    Ok(Net {
+        address,
+        subnet,
+        zone,
+    })
+}
+
+/// Parses an RFC 4291 address: eight hex groups, `::` compression and a trailing dotted quad.
+pub fn parse_addr(s: &str) -> Option<Addr> {
+    let (head, tail) = match s.split_once("::") {
+        Some((head, tail)) if !tail.contains("::") => (head, Some(tail)),
+        Some(_) => return None,
+        None => (s, None),
+    };
+    let mut groups = parse_groups(head, tail.is_none())?;
+    let mut tail_groups = match tail {
+        Some(tail) => parse_groups(tail, true)?,
+        None => Vec::new(),
+    };
+    let total = groups.len() + tail_groups.len();
+    match tail {
+        None if total != 8 => return None,
+        Some(_) if total > 7 => return None,
+        _ => {}
+    }
+    groups.resize(8 - tail_groups.len(), 0);
+    groups.append(&mut tail_groups);
+    Some(groups.iter().fold(0, |addr, &g| addr << 16 | g as Addr))
+}
+
+/// Parses `:` separated hex groups, the last one may be a dotted quad when `v4_tail` is set.
+fn parse_groups(s: &str, v4_tail: bool) -> Option<Vec<u16>> {
+    let mut groups = Vec::new();
+    if s.is_empty() {
+        return Some(groups);
+    }
+    let parts: Vec<&str> = s.split(':').collect();
+    for (i, part) in parts.iter().enumerate() {
+        if v4_tail && i == parts.len() - 1 && part.contains('.') {
+            let octets: Vec<u8> = part
+                .split('.')
+                .map(|o| match o.len() {
+                    1..=3 if o.bytes().all(|b| b.is_ascii_digit()) => o.parse().ok(),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?;
+            if octets.len() != 4 {
+                return None;
+            }
+            groups.push(u16::from_be_bytes([octets[0], octets[1]]));
+            groups.push(u16::from_be_bytes([octets[2], octets[3]]));
+        } else if (1..=4).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_hexdigit()) {
+            groups.push(u16::from_str_radix(part, 16).ok()?);
         } else {
-            Ok(Net {
-                address,
-                subnet: 128,
-            })
+            return None;
         }
     }
+    Some(groups)
+}
+
+/// Formats an address in the RFC 5952 canonical form: lower case, no leading zeros, the
+/// longest run of two or more zero groups compressed to `::`, and IPv4-mapped addresses
+/// written with a dotted quad.
+pub fn format_addr(address: Addr) -> String {
+    if address >> 32 == 0xffff {
+        let v4 = address as u32;
+        return format!(
+            "::ffff:{}.{}.{}.{}",
+            v4 >> 24,
+            (v4 >> 16) as u8,
+            (v4 >> 8) as u8,
+            v4 as u8
+        );
+    }
+    let groups: Vec<u16> = (0..8).map(|i| (address >> (112 - 16 * i)) as u16).collect();
+    // Longest run of zero groups, the first one wins a tie.
+    let (mut best, mut best_len) = (0, 0);
+    let mut i = 0;
+    while i < 8 {
+        let start = i;
+        while i < 8 && groups[i] == 0 {
+            i += 1;
+        }
+        if i - start > best_len {
+            (best, best_len) = (start, i - start);
+        }
+        i += 1;
+    }
+    let hex = |gs: &[u16]| {
+        gs.iter()
+            .map(|g| format!("{:x}", g))
+            .collect::<Vec<String>>()
+            .join(":")
+    };
+    if best_len < 2 {
+        return hex(&groups);
+    }
+    format!(
+        "{}::{}",
+        hex(&groups[..best]),
+        hex(&groups[best + best_len..])
+    )
 }
 impl Default for Net {
     fn default() -> Net {
         Net {
             address: 0,
             subnet: 128,
+            zone: None,
         }
     }
 }
 impl Ord for Net {
     fn cmp(&self, other: &Self) -> Ordering {
         match self.address.cmp(&other.address) {
-            Ordering::Equal => self.subnet.cmp(&other.subnet),
+            Ordering::Equal => self
+                .subnet
+                .cmp(&other.subnet)
+                .then_with(|| self.zone.cmp(&other.zone)),
             other => other,
         }
     }
 }
 impl PartialEq for Net {
     fn eq(&self, other: &Self) -> bool {
-        self.address == other.address && self.subnet == other.subnet
+        self.address == other.address && self.subnet == other.subnet && self.zone == other.zone
     }
 }
 impl PartialOrd for Net {
@@ -174,6 +327,7 @@ impl Hash for Net {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.address.hash(state);
         self.subnet.hash(state);
+        self.zone.hash(state);
     }
 }
 impl Display for Net {
@@ -186,6 +340,7 @@ impl From<Addr> for Net {
         Net {
             address: v,
             subnet: 128,
+            zone: None,
         }
     }
 }
@@ -194,11 +349,12 @@ impl From<(Addr, Subnet)> for Net {
         Net {
             address: v.0,
             subnet: v.1,
+            zone: None,
         }
     }
 }
 impl FromStr for Net {
-    type Err = ParseIntError;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Net::from_str(s)
     }
@@ -208,7 +364,8 @@ impl From<Net> for String {
         v.to_string()
     }
 }
-struct Range {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
     start: Addr,
     end: Addr,
 }
@@ -216,10 +373,35 @@ impl Range {
     pub fn new() -> Self {
         Range { start: 0, end: 0 }
     }
+    pub fn start(&self) -> Addr {
+        self.start
+    }
+    pub fn end(&self) -> Addr {
+        self.end
+    }
+    /// Parses `start-end` in any RFC 4291 text form, or a single address as a range of one.
+    /// The endpoints are put in order.
+    pub fn from_str(s: &str) -> Result<Self, Error> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let addr = |part: &str| {
+            let part = part.trim();
+            parse_addr(part).ok_or_else(|| Error::parse(part, "an IPv6 address").locate(s, part))
+        };
+        let (start, end) = (addr(start)?, addr(end)?);
+        Ok(Range {
+            start: start.min(end),
+            end: start.max(end),
+        })
+    }
+}
+impl Default for Range {
+    fn default() -> Range {
+        Range::new()
+    }
 }
 impl Display for Range {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        write!(f, "{}-{}", self.start, self.end)
+        write!(f, "{}-{}", format_addr(self.start), format_addr(self.end))
     }
 }
 impl From<Range> for String {
@@ -228,22 +410,16 @@ impl From<Range> for String {
     }
 }
 impl FromStr for Range {
-    type Err = ParseIntError;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split('-');
-        let start = parts.next().unwrap();
-        let end = parts.next().unwrap();
-        Ok(Range {
-            start: Addr::from_str(start)?,
-            end: Addr::from_str(end)?,
-        })
+        Range::from_str(s)
     }
 }
 
 impl Set {
     const VALID_CHARS: &'static [char] = &[
         '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F', 'a', 'b',
-        'c', 'd', 'e', 'f', ':', '/',
+        'c', 'd', 'e', 'f', ':', '.', '/',
     ];
     pub fn new() -> Set {
         Set { data: Vec::new() }
@@ -290,6 +466,79 @@ impl Set {
                 .then_with(|| lhs.subnet.cmp(&rhs.subnet))
         });
     }
+    /// Replaces the set with the minimal list of networks covering the same addresses,
+    /// sorted by network. Zone IDs do not survive aggregation.
+    pub fn optimize(&mut self) -> Report {
+        let entries_in = self.len();
+        let merged = cidr::aggregate(&self.prefixes(), 128);
+        self.data = merged.iter().map(|&p| Net::from_prefix(p)).collect();
+        Report {
+            entries_in,
+            entries_out: self.len(),
+            addresses: cidr::addresses(&merged, 128),
+        }
+    }
+    /// Addresses in `self` or `other`.
+    pub fn union(&self, other: &Set) -> Set {
+        Set::from_intervals(&cidr::union(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in both `self` and `other`.
+    pub fn intersection(&self, other: &Set) -> Set {
+        Set::from_intervals(&cidr::intersection(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in `self` but not in `other`, splitting networks where needed.
+    pub fn difference(&self, other: &Set) -> Set {
+        Set::from_intervals(&cidr::difference(&self.intervals(), &other.intervals()))
+    }
+    /// Addresses in exactly one of `self` and `other`.
+    pub fn symmetric_difference(&self, other: &Set) -> Set {
+        Set::from_intervals(&cidr::symmetric_difference(
+            &self.intervals(),
+            &other.intervals(),
+        ))
+    }
+    /// Every IPv6 address not in `self`.
+    pub fn complement(&self) -> Set {
+        Set::from_intervals(&cidr::complement(&self.intervals(), 128))
+    }
+    /// The covered addresses as sorted, non-overlapping ranges.
+    pub fn intervals(&self) -> Vec<Interval> {
+        cidr::intervals(&self.prefixes(), 128)
+    }
+    /// The minimal set of networks covering `intervals`.
+    pub fn from_intervals(intervals: &[Interval]) -> Set {
+        Set {
+            data: cidr::prefixes(intervals, 128)
+                .into_iter()
+                .map(Net::from_prefix)
+                .collect(),
+        }
+    }
+    fn prefixes(&self) -> Vec<Prefix> {
+        self.data.iter().map(|net| net.prefix()).collect()
+    }
+    /// The covered addresses as sorted, merged ranges.
+    pub fn to_ranges(&self) -> Vec<Range> {
+        self.intervals()
+            .into_iter()
+            .map(|(start, end)| Range { start, end })
+            .collect()
+    }
+    /// Splits the set into consecutive sets of at most `size` networks.
+    pub fn chunks(&self, size: usize) -> Vec<Set> {
+        self.data
+            .chunks(size.max(1))
+            .map(|chunk| Set {
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, Net> {
+        self.data.iter()
+    }
     pub fn to_string(&self) -> String {
         self.data
             .iter()
@@ -297,13 +546,26 @@ impl Set {
             .collect::<Vec<String>>()
             .join("\n")
     }
-    pub fn from_str(s: &str) -> Result<Set, ParseIntError> {
+    /// Renders the set as an `ipset restore` script, see [`super::ipset::restore`].
+    pub fn to_ipset(&self, name: &str, maxelem: usize, swap: bool) -> String {
+        super::ipset::restore(name, Family::V6, maxelem, self.data.iter(), swap)
+    }
+    /// Renders the set as an nft interval set of `table`, see [`super::nft::set`].
+    pub fn to_nft(&self, table: &str, name: &str) -> String {
+        super::nft::set(
+            table,
+            name,
+            Family::V6,
+            self.data.iter(),
+            super::nft::ELEMENTS_PER_STATEMENT,
+        )
+    }
+    /// Parses one network per line, blank lines are skipped. Errors are placed within `s`.
+    pub fn from_str(s: &str) -> Result<Set, Error> {
         let mut ipset = Set::new();
-        s.split('\n').filter(|s| !s.is_empty()).for_each(|ip_str| {
-            if let Ok(ip) = Net::from_str(ip_str.trim()) {
-                ipset.push(&ip);
-            }
-        });
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            ipset.push(&network(line, s)?);
+        }
         Ok(ipset)
     }
     pub fn from_file(file: &File) -> Result<Set, IoError> {
@@ -354,7 +616,7 @@ impl Default for Set {
     }
 }
 impl FromStr for Set {
-    type Err = ParseIntError;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Set, Self::Err> {
         Set::from_str(s)
     }
@@ -365,3 +627,219 @@ impl Display for Set {
         write!(f, "{}", s)
     }
 }
+impl BitOr for Set {
+    type Output = Set;
+    fn bitor(self, other: Set) -> Self::Output {
+        self.union(&other)
+    }
+}
+impl BitOrAssign for Set {
+    fn bitor_assign(&mut self, other: Set) {
+        *self = self.union(&other);
+    }
+}
+impl BitAnd for Set {
+    type Output = Set;
+    fn bitand(self, other: Set) -> Self::Output {
+        self.intersection(&other)
+    }
+}
+impl BitAndAssign for Set {
+    fn bitand_assign(&mut self, other: Set) {
+        *self = self.intersection(&other);
+    }
+}
+impl Sub for Set {
+    type Output = Set;
+    fn sub(self, other: Set) -> Self::Output {
+        self.difference(&other)
+    }
+}
+impl SubAssign for Set {
+    fn sub_assign(&mut self, other: Set) {
+        *self = self.difference(&other);
+    }
+}
+impl BitXor for Set {
+    type Output = Set;
+    fn bitxor(self, other: Set) -> Self::Output {
+        self.symmetric_difference(&other)
+    }
+}
+impl BitXorAssign for Set {
+    fn bitxor_assign(&mut self, other: Set) {
+        *self = self.symmetric_difference(&other);
+    }
+}
+impl Not for Set {
+    type Output = Set;
+    fn not(self) -> Self::Output {
+        self.complement()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(nets: &[&str]) -> Set {
+        Set::from_str(&nets.join("\n")).unwrap()
+    }
+
+    fn net(s: &str) -> String {
+        Net::from_str(s).unwrap().to_string()
+    }
+
+    #[test]
+    fn range_parses_rfc_4291_text() {
+        let range = "::1-::ff".parse::<Range>().unwrap();
+        assert_eq!((range.start(), range.end()), (1, 0xff));
+        assert_eq!(range.to_string(), "::1-::ff");
+    }
+
+    #[test]
+    fn range_of_one_address() {
+        let range = Range::from_str("2001:db8::1").unwrap();
+        assert_eq!(range.start(), range.end());
+    }
+
+    #[test]
+    fn range_endpoints_are_ordered() {
+        let range = Range::from_str("2001:db8::9 - 2001:db8::1").unwrap();
+        assert_eq!(range.to_string(), "2001:db8::1-2001:db8::9");
+    }
+
+    #[test]
+    fn range_error_is_located() {
+        let e = Range::from_str("::1-::fffff").unwrap_err();
+        assert_eq!(e.token, "::fffff");
+        assert_eq!((e.line, e.column), (1, 5));
+    }
+
+    #[test]
+    fn to_ranges_merges_adjacent_networks() {
+        let mut set = Set::new();
+        for net in ["2001:db8::/33", "2001:db8:8000::/33", "2001:db9:1::/48"] {
+            set.push(&Net::from_str(net).unwrap());
+        }
+        let ranges: Vec<String> = set.to_ranges().iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            ranges,
+            [
+                "2001:db8::-2001:db8:ffff:ffff:ffff:ffff:ffff:ffff",
+                "2001:db9:1::-2001:db9:1:ffff:ffff:ffff:ffff:ffff"
+            ]
+        );
+    }
+
+    #[test]
+    fn network_errors_are_located() {
+        let e = Net::from_str("2001:db8::/129").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (12, "129"));
+        let e = Net::from_str("2001:db8:::1").unwrap_err();
+        assert_eq!((e.column, e.token.as_str()), (1, "2001:db8:::1"));
+    }
+
+    #[test]
+    fn set_reports_invalid_lines() {
+        let set = Set::from_str("2001:db8::/32\n\n  ::1\n").unwrap();
+        assert_eq!(set.len(), 2);
+        let e = Set::from_str("2001:db8::/32\n  fe80::1%\n").unwrap_err();
+        assert_eq!((e.line, e.column, e.token.as_str()), (2, 3, "fe80::1%"));
+    }
+
+    #[test]
+    fn formats_as_rfc_5952() {
+        // Lowercase, no leading zeros, the longest run of zero groups compressed.
+        assert_eq!(
+            net("2001:0DB8:0000:0000:0000:0000:0000:0001"),
+            "2001:db8::1"
+        );
+        assert_eq!(net("2001:db8:0:0:1:0:0:1"), "2001:db8::1:0:0:1");
+        // The first of equally long runs, and never a single group.
+        assert_eq!(net("2001:0:0:1:0:0:1:1"), "2001::1:0:0:1:1");
+        assert_eq!(net("2001:db8:0:1:1:1:1:1"), "2001:db8:0:1:1:1:1:1");
+        assert_eq!(net("0:0:0:0:0:0:0:0"), "::");
+        assert_eq!(net("::ffff:192.0.2.1"), "::ffff:192.0.2.1");
+        assert_eq!(net("2001:db8::/32"), "2001:db8::/32");
+        assert_eq!(net("fe80::1%eth0/64"), "fe80::1%eth0/64");
+    }
+
+    #[test]
+    fn parse_of_format_round_trips() {
+        for text in [
+            "::",
+            "::1",
+            "2001:db8::8:800:200c:417a",
+            "ff01::101",
+            "::ffff:10.0.0.1/120",
+        ] {
+            assert_eq!(net(text), text);
+            assert_eq!(net(&net(text)), net(text));
+        }
+    }
+
+    #[test]
+    fn invalid_addresses_are_refused() {
+        for text in [
+            "2001:db8::1::1",
+            "1:2:3:4:5:6:7:8:9",
+            "1:2:3:4:5:6:7",
+            "12345::",
+            "::g",
+            ":1::",
+        ] {
+            assert!(parse_addr(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn optimize_yields_the_minimal_covering_networks() {
+        let mut nets = set(&[
+            "2001:db8::/33",
+            "2001:db8:8000::/33",
+            "2001:db8:1::1",
+            "2001:db9::/48",
+        ]);
+        let report = nets.optimize();
+        assert_eq!(nets.to_string(), "2001:db8::/32\n2001:db9::/48");
+        assert_eq!((report.entries_in, report.entries_out), (4, 2));
+        assert_eq!(report.addresses, (1 << 96) + (1 << 80));
+    }
+
+    #[test]
+    fn set_algebra_splits_networks_where_needed() {
+        let (a, b) = (
+            set(&["2001:db8::/32"]),
+            set(&["2001:db8:8000::/33", "2001:db9::/32"]),
+        );
+        assert_eq!(a.union(&b).to_string(), "2001:db8::/31");
+        assert_eq!(a.intersection(&b).to_string(), "2001:db8:8000::/33");
+        assert_eq!(a.difference(&b).to_string(), "2001:db8::/33");
+        assert_eq!(
+            a.symmetric_difference(&b).to_string(),
+            "2001:db8::/33\n2001:db9::/32"
+        );
+        assert_eq!(set(&["8000::/1"]).complement().to_string(), "::/1");
+    }
+
+    #[test]
+    fn assigning_operators_match_the_methods() {
+        let (a, b) = (
+            set(&["2001:db8::/32"]),
+            set(&["2001:db8:8000::/33", "2001:db9::/32"]),
+        );
+        let mut c = a.clone();
+        c |= b.clone();
+        assert_eq!(c, a.clone() | b.clone());
+        let mut c = a.clone();
+        c &= b.clone();
+        assert_eq!(c, a.intersection(&b));
+        let mut c = a.clone();
+        c ^= b.clone();
+        assert_eq!(c, a.symmetric_difference(&b));
+        let mut c = a.clone();
+        c -= b.clone();
+        assert_eq!(c, a - b);
+    }
+}